@@ -129,13 +129,11 @@ async fn main() {
     )));
 
     let (pitaya_server, shutdown_receiver) = pitaya::PitayaBuilder::new()
-        .with_server_info(Arc::new(pitaya::cluster::ServerInfo {
-            id: pitaya::cluster::ServerId::from("random-id"),
-            kind: pitaya::cluster::ServerKind::from("random"),
-            metadata: HashMap::new(),
-            hostname: String::new(),
-            frontend: false,
-        }))
+        .with_server_info(Arc::new(
+            pitaya::cluster::ServerInfo::builder("random")
+                .build()
+                .expect("server info should be valid"),
+        ))
         .with_logger(logger.clone())
         .with_client_handlers(pitaya::handlers![entry, hi, bind])
         .with_metrics_reporter(metrics_reporter.clone())
@@ -14,11 +14,14 @@ use pitaya_core::{
     service::{self, RpcHandler},
     Route,
 };
-pub use pitaya_etcd_nats_cluster::{EtcdLazy, NatsRpcClient, NatsRpcServer};
+pub use pitaya_etcd_nats_cluster::{
+    DefaultTopicResolver, EtcdLazy, NatsRpcClient, NatsRpcServer, TopicResolver,
+};
 pub use pitaya_macros::{handlers, json_handler, protobuf_handler};
 use slog::{debug, error, info, o, trace, warn};
 use std::sync::Arc;
 use tokio::{
+    stream::Stream,
     sync::{broadcast, mpsc, oneshot, Mutex, RwLock},
     task,
 };
@@ -36,6 +39,7 @@ struct ClusterComponents {
     discovery: Arc<Mutex<Box<dyn cluster::Discovery>>>,
     rpc_server: Arc<dyn cluster::RpcServer>,
     rpc_client: Arc<dyn cluster::RpcClient>,
+    router: Arc<dyn cluster::Router>,
 }
 
 /// Pitaya represent a pitaya server.
@@ -46,6 +50,7 @@ pub struct Pitaya {
     discovery: Arc<Mutex<Box<dyn cluster::Discovery>>>,
     rpc_server: Arc<dyn cluster::RpcServer>,
     rpc_client: Arc<dyn cluster::RpcClient>,
+    router: Arc<dyn cluster::Router>,
     shared_state: Arc<SharedState>,
     logger: slog::Logger,
     settings: Arc<settings::Settings>,
@@ -60,6 +65,7 @@ impl Clone for Pitaya {
             discovery: self.discovery.clone(),
             rpc_server: self.rpc_server.clone(),
             rpc_client: self.rpc_client.clone(),
+            router: self.router.clone(),
             shared_state: self.shared_state.clone(),
             logger: self.logger.clone(),
             settings: self.settings.clone(),
@@ -99,6 +105,7 @@ impl Pitaya {
             discovery: cluster_components.discovery,
             rpc_client: cluster_components.rpc_client,
             rpc_server: cluster_components.rpc_server,
+            router: cluster_components.router,
             logger,
             settings: Arc::new(settings),
             metrics_reporter,
@@ -121,6 +128,15 @@ impl Pitaya {
         Ok(server)
     }
 
+    /// Returns a stream of the servers that are added to and removed from the cluster.
+    ///
+    /// It is an alternative to `PitayaBuilder::with_cluster_subscriber` that can be consumed
+    /// from any task. Notifications missed because the stream was not consumed fast enough
+    /// are skipped.
+    pub async fn cluster_notifications(&self) -> impl Stream<Item = cluster::Notification> {
+        cluster::notification_stream(self.discovery.lock().await.subscribe())
+    }
+
     /// Gracefully shuts down the Pitaya server.
     pub async fn shutdown(self) -> Result<(), Error> {
         let tasks = self
@@ -134,7 +150,12 @@ impl Pitaya {
         info!(self.logger, "shutting down pitaya server");
 
         info!(self.logger, "stopping service discovery");
-        self.discovery.lock().await.shutdown().await?;
+        // The other components are still shut down when the discovery fails, and its error
+        // is returned at the end.
+        let discovery_result = self.discovery.lock().await.shutdown().await;
+        if let Err(ref e) = discovery_result {
+            error!(self.logger, "service discovery was not stopped cleanly"; "error" => %e);
+        }
         info!(self.logger, "stopped");
 
         info!(self.logger, "stopping rpc client");
@@ -148,6 +169,7 @@ impl Pitaya {
 
         info!(self.logger, "waiting graceful shutdown task");
         tasks.graceful_shutdown.await?;
+        discovery_result?;
         Ok(())
     }
 
@@ -203,8 +225,8 @@ impl Pitaya {
     }
 
     /// Similar to the method `send_rpc_to_server`. The difference is that the RPC will not be
-    /// sent to a specific Server. It will randomize over all servers of the specified server kind
-    /// on the route.
+    /// sent to a specific Server. The server is picked among all servers of the specified server
+    /// kind on the route by the router (see `PitayaBuilder::with_router`).
     pub async fn send_rpc(
         &self,
         ctx: context::Context,
@@ -227,8 +249,8 @@ impl Pitaya {
             .servers_by_kind(&server_kind)
             .await?;
 
-        debug!(self.logger, "getting random server");
-        if let Some(random_server_info) = utils::random_server(&servers) {
+        debug!(self.logger, "routing rpc");
+        if let Some(server_info) = self.router.route(&ctx, &servers) {
             debug!(self.logger, "sending rpc");
 
             let msg = message::Message {
@@ -242,7 +264,7 @@ impl Pitaya {
 
             let res = self
                 .rpc_client
-                .call(ctx, protos::RpcType::User, msg, random_server_info)
+                .call(ctx, protos::RpcType::User, msg, server_info)
                 .await
                 .map(|res| {
                     trace!(self.logger, "received rpc response"; "res" => ?res);
@@ -293,7 +315,7 @@ impl Pitaya {
 
         self.rpc_client.start().await?;
 
-        let rpc_server_connection = self.rpc_server.start().await?;
+        let rpc_server_connection = self.rpc_server.start(app_die_sender.clone()).await?;
         let listen_for_rpc = tokio::spawn(Self::start_handlers_task(
             self.logger.new(o!("task" => "start_listen_for_rpc")),
             rpc_server_connection,
@@ -461,6 +483,9 @@ pub struct PitayaBuilder<'a> {
     container: state::Container,
     server_info: Option<Arc<ServerInfo>>,
     metrics_reporter: Option<metrics::ThreadSafeReporter>,
+    discovery: Option<Box<dyn cluster::Discovery>>,
+    topic_resolver: Option<Arc<dyn TopicResolver>>,
+    router: Option<Arc<dyn cluster::Router>>,
 }
 
 impl<'a> Default for PitayaBuilder<'a> {
@@ -485,6 +510,9 @@ impl<'a> PitayaBuilder<'a> {
             container: state::Container::new(),
             server_info: None,
             metrics_reporter: None,
+            discovery: None,
+            topic_resolver: None,
+            router: None,
         }
     }
 
@@ -575,6 +603,27 @@ impl<'a> PitayaBuilder<'a> {
         self
     }
 
+    /// Specifies a custom service discovery implementation for pitaya to use. If not specified,
+    /// `EtcdLazy` will be created from the etcd settings.
+    pub fn with_discovery(mut self, discovery: Box<dyn cluster::Discovery>) -> Self {
+        self.discovery.replace(discovery);
+        self
+    }
+
+    /// Specifies the resolver for the NATS topics used by the server. This is useful for
+    /// communicating with deployments that do not use the default Pitaya topics.
+    pub fn with_topic_resolver(mut self, topic_resolver: Arc<dyn TopicResolver>) -> Self {
+        self.topic_resolver.replace(topic_resolver);
+        self
+    }
+
+    /// Specifies how the server that receives an RPC is picked among the servers of its kind
+    /// when using `Pitaya::send_rpc`. If not specified, a random server is picked.
+    pub fn with_router(mut self, router: Arc<dyn cluster::Router>) -> Self {
+        self.router.replace(router);
+        self
+    }
+
     /// Builds the Pitaya instance.
     ///
     /// A Pitaya instance will be returned and also a shutdown receiver.
@@ -604,14 +653,19 @@ impl<'a> PitayaBuilder<'a> {
             .metrics_reporter
             .unwrap_or_else(|| Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))));
 
-        let discovery: Arc<Mutex<Box<dyn cluster::Discovery>>> = Arc::new(Mutex::new(Box::new(
-            pitaya_etcd_nats_cluster::EtcdLazy::new(
-                logger.clone(),
-                server_info.clone(),
-                etcd_settings,
-            )
-            .await?,
-        )));
+        let discovery: Box<dyn cluster::Discovery> = match self.discovery {
+            Some(discovery) => discovery,
+            None => Box::new(
+                pitaya_etcd_nats_cluster::EtcdLazy::new(
+                    logger.clone(),
+                    server_info.clone(),
+                    etcd_settings,
+                )
+                .await?
+                .with_metrics_reporter(metrics_reporter.clone()),
+            ),
+        };
+        let discovery = Arc::new(Mutex::new(discovery));
 
         if !self.container.set(metrics_reporter.clone()) {
             panic!("should not fail to set metrics reporter state");
@@ -621,20 +675,29 @@ impl<'a> PitayaBuilder<'a> {
         // Freeze state, so we cannot modify it later.
         let container = Arc::new(self.container);
 
-        let rpc_server: Arc<dyn cluster::RpcServer> = Arc::new(NatsRpcServer::new(
-            logger.clone(),
-            server_info.clone(),
-            settings.nats.clone(),
-            tokio::runtime::Handle::current(),
-            metrics_reporter.clone(),
-        ));
-        let rpc_client: Arc<dyn cluster::RpcClient> = Arc::new(NatsRpcClient::new(
-            logger.clone(),
-            settings.nats.clone(),
-            server_info.clone(),
-            tokio::runtime::Handle::current(),
-            metrics_reporter.clone(),
-        ));
+        let topic_resolver = self
+            .topic_resolver
+            .unwrap_or_else(|| Arc::new(DefaultTopicResolver));
+        let rpc_server: Arc<dyn cluster::RpcServer> = Arc::new(
+            NatsRpcServer::new(
+                logger.clone(),
+                server_info.clone(),
+                settings.nats.clone(),
+                tokio::runtime::Handle::current(),
+                metrics_reporter.clone(),
+            )
+            .with_topic_resolver(topic_resolver.clone()),
+        );
+        let rpc_client: Arc<dyn cluster::RpcClient> = Arc::new(
+            NatsRpcClient::new(
+                logger.clone(),
+                settings.nats.clone(),
+                server_info.clone(),
+                tokio::runtime::Handle::current(),
+                metrics_reporter.clone(),
+            )
+            .with_topic_resolver(topic_resolver),
+        );
 
         let rpc_dispatch = if let Some(rpc_handler) = self.rpc_handler {
             service::RpcDispatch::Raw(rpc_handler)
@@ -652,6 +715,9 @@ impl<'a> PitayaBuilder<'a> {
                 discovery,
                 rpc_server,
                 rpc_client,
+                router: self
+                    .router
+                    .unwrap_or_else(|| Arc::new(cluster::RandomRouter)),
             },
             metrics_reporter,
             settings,
@@ -668,3 +734,120 @@ impl<'a> PitayaBuilder<'a> {
         Ok((p, shutdown_receiver))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cluster::Discovery;
+    use std::collections::HashMap;
+    use std::error::Error as StdError;
+
+    #[tokio::test]
+    async fn builder_uses_the_given_discovery() -> Result<(), Box<dyn StdError>> {
+        let this_server = Arc::new(ServerInfo {
+            id: ServerId::from("custom-sd-server"),
+            kind: ServerKind::from("custom-sd"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        });
+        let room = Arc::new(ServerInfo {
+            id: ServerId::from("static-room"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        });
+
+        // Building fails if etcd is contacted, since its url is invalid.
+        let settings = settings::Settings {
+            etcd: pitaya_etcd_nats_cluster::settings::Etcd {
+                url: "invalid-etcd-url:2379".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut p, _shutdown_receiver) = PitayaBuilder::new()
+            .with_server_info(this_server)
+            .with_logger(test_helpers::get_root_logger())
+            .with_rpc_handler(Box::new(|_rpc| {}))
+            .with_base_settings(settings)
+            .with_discovery(Box::new(cluster::StaticDiscovery::new(vec![room.clone()])))
+            .build()
+            .await?;
+
+        assert_eq!(p.server_by_id(&room.id, &room.kind).await?, Some(room));
+        p.shutdown().await?;
+        Ok(())
+    }
+
+    // A discovery whose background task can never be stopped cleanly.
+    struct UnstoppableDiscovery(cluster::StaticDiscovery);
+
+    #[async_trait::async_trait]
+    impl Discovery for UnstoppableDiscovery {
+        async fn server_by_id(
+            &mut self,
+            id: &ServerId,
+            kind: Option<&ServerKind>,
+        ) -> Result<Option<Arc<ServerInfo>>, cluster::Error> {
+            self.0.server_by_id(id, kind).await
+        }
+
+        async fn servers_by_kind(
+            &mut self,
+            kind: &ServerKind,
+        ) -> Result<Vec<Arc<ServerInfo>>, cluster::Error> {
+            self.0.servers_by_kind(kind).await
+        }
+
+        async fn start(
+            &mut self,
+            app_die_sender: broadcast::Sender<()>,
+        ) -> Result<(), cluster::Error> {
+            self.0.start(app_die_sender).await
+        }
+
+        async fn shutdown(&mut self) -> Result<(), cluster::Error> {
+            Err(cluster::Error::TaskNotStopped {
+                task: "keep_alive",
+                failure: cluster::TaskFailure::TimedOut,
+            })
+        }
+
+        fn subscribe(&mut self) -> broadcast::Receiver<cluster::Notification> {
+            self.0.subscribe()
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_returns_the_discovery_error() -> Result<(), Box<dyn StdError>> {
+        let this_server = Arc::new(ServerInfo {
+            id: ServerId::from("unstoppable-sd-server"),
+            kind: ServerKind::from("unstoppable-sd"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        });
+        let (p, _shutdown_receiver) = PitayaBuilder::new()
+            .with_server_info(this_server)
+            .with_logger(test_helpers::get_root_logger())
+            .with_rpc_handler(Box::new(|_rpc| {}))
+            .with_base_settings(settings::Settings::default())
+            .with_discovery(Box::new(UnstoppableDiscovery(
+                cluster::StaticDiscovery::new(vec![]),
+            )))
+            .build()
+            .await?;
+
+        // The rest of the server is still shut down before the error is returned.
+        assert!(matches!(
+            p.shutdown().await,
+            Err(Error::Cluster(cluster::Error::TaskNotStopped {
+                task: "keep_alive",
+                failure: cluster::TaskFailure::TimedOut,
+            }))
+        ));
+        Ok(())
+    }
+}
@@ -1,12 +1,22 @@
 fn main() {
-    prost_build::compile_protos(
-        &[
-            "./pitaya-protos/request.proto",
-            "./pitaya-protos/response.proto",
-            "./pitaya-protos/kick.proto",
-            "./pitaya-protos/push.proto",
-        ],
-        &["./pitaya-protos"],
-    )
-    .expect("failed to compile protos!");
+    prost_build::Config::new()
+        // Responses can also be sent as JSON (see the encoding module).
+        .type_attribute(
+            ".protos.Response",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .type_attribute(
+            ".protos.Error",
+            "#[derive(serde::Serialize, serde::Deserialize)]",
+        )
+        .compile_protos(
+            &[
+                "./pitaya-protos/request.proto",
+                "./pitaya-protos/response.proto",
+                "./pitaya-protos/kick.proto",
+                "./pitaya-protos/push.proto",
+            ],
+            &["./pitaya-protos"],
+        )
+        .expect("failed to compile protos!");
 }
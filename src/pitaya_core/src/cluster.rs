@@ -1,11 +1,21 @@
-use crate::{context, message, protos};
+use crate::{context, encoding, message, protos};
 use async_trait::async_trait;
+use std::collections::HashMap;
 use std::sync::Arc;
 use thiserror::Error;
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::{
+    stream::{Stream, StreamExt},
+    sync::{broadcast, mpsc, oneshot},
+};
 
+pub mod mock_rpc_client;
+pub mod router;
 pub mod server;
-pub use server::{ServerId, ServerInfo, ServerKind};
+pub mod static_discovery;
+pub use mock_rpc_client::{MockRpcClient, RecordedRpc};
+pub use router::{ConsistentHashRouter, RandomRouter, RoundRobinRouter, Router};
+pub use server::{ServerId, ServerInfo, ServerInfoBuilder, ServerKind};
+pub use static_discovery::StaticDiscovery;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -27,9 +37,15 @@ pub enum Error {
     #[error("no servers of kind {0:?} found")]
     NoServersFound(server::ServerKind),
 
+    #[error("unknown server kind {0:?}")]
+    UnknownServerKind(server::ServerKind),
+
     #[error("frontend server id {0:?} not found")]
     FrontendServerNotFound(server::ServerId),
 
+    #[error("server id {0:?} not found")]
+    ServerNotFound(server::ServerId),
+
     #[error("corrupt server: {0}")]
     CorruptServer(String),
 
@@ -39,39 +55,158 @@ pub enum Error {
     #[error("empty user id")]
     EmptyUserId,
 
+    #[error("invalid server info: {0}")]
+    InvalidServerInfo(String),
+
     #[error("internal: {0}")]
     Internal(String),
 
     #[error("invalid server response: {0}")]
     InvalidServerResponse(prost::DecodeError),
 
+    #[error("invalid server response: {0}")]
+    InvalidResponseEncoding(encoding::Error),
+
     #[error("rpc server already started")]
     RpcServerAlreadyStarted,
 
     #[error("already connected")]
     AlreadyConnected,
+
+    #[error("invalid settings: {0}")]
+    InvalidSettings(String),
+
+    #[error("rpc timed out")]
+    Timeout,
+
+    #[error("invalid route: {0:?}")]
+    InvalidRoute(String),
+
+    #[error("blocking call made from inside an async runtime, which could deadlock")]
+    BlockingCallInRuntime,
+
+    #[error("{task} task was not stopped cleanly: {failure}")]
+    TaskNotStopped {
+        task: &'static str,
+        failure: TaskFailure,
+    },
+
+    #[error("rpc failed with code {code}: {msg}")]
+    Rpc { code: String, msg: String },
+}
+
+// Why a background task could not be stopped cleanly.
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum TaskFailure {
+    #[error("task had already finished")]
+    AlreadyFinished,
+
+    #[error("failed to send stop request: {0}")]
+    StopFailed(String),
+
+    #[error("task panicked")]
+    Panicked,
+
+    #[error("task did not finish in time and was aborted")]
+    TimedOut,
 }
 
 // The Discovery trait allows the program to discover other pitaya servers in the cluster.
 #[async_trait]
 pub trait Discovery: Send + 'static {
     // Discover a server based on its id.
+    //
+    // `Ok(None)` means that the lookup succeeded and the server does not exist in the
+    // cluster. Failing to reach the cluster, on the other hand, always returns an error,
+    // typically `Error::ClusterCommunication`.
     async fn server_by_id(
         &mut self,
         id: &server::ServerId,
         kind: Option<&server::ServerKind>,
     ) -> Result<Option<Arc<ServerInfo>>, Error>;
 
+    // Discover a server based on its id like `server_by_id`, but fails with
+    // `Error::ServerNotFound` if it does not exist, so that callers that need the server
+    // can match an absent one apart from a failure to reach the cluster.
+    async fn required_server_by_id(
+        &mut self,
+        id: &server::ServerId,
+        kind: Option<&server::ServerKind>,
+    ) -> Result<Arc<ServerInfo>, Error> {
+        self.server_by_id(id, kind)
+            .await?
+            .ok_or_else(|| Error::ServerNotFound(id.clone()))
+    }
+
+    // Discover many servers at once, given their ids and kinds. Only the servers that
+    // exist are returned. Implementations may batch the lookups of the same kind.
+    async fn servers_by_ids(
+        &mut self,
+        ids: &[(server::ServerId, server::ServerKind)],
+    ) -> Result<HashMap<server::ServerId, Arc<ServerInfo>>, Error> {
+        let mut servers = HashMap::new();
+        for (id, kind) in ids {
+            if let Some(server) = self.server_by_id(id, Some(kind)).await? {
+                servers.insert(id.clone(), server);
+            }
+        }
+        Ok(servers)
+    }
+
     // Discover servers by a specified kind.
     async fn servers_by_kind(
         &mut self,
         kind: &server::ServerKind,
     ) -> Result<Vec<Arc<ServerInfo>>, Error>;
 
+    // Discover servers by a specified kind whose metadata matches the given predicate,
+    // like the servers of a given region. The servers are filtered after they are
+    // discovered, so this does not make more calls to the cluster than `servers_by_kind`.
+    async fn servers_by_kind_filtered(
+        &mut self,
+        kind: &server::ServerKind,
+        predicate: &(dyn Fn(&HashMap<String, String>) -> bool + Sync),
+    ) -> Result<Vec<Arc<ServerInfo>>, Error> {
+        Ok(self
+            .servers_by_kind(kind)
+            .await?
+            .into_iter()
+            .filter(|server| predicate(&server.metadata))
+            .collect())
+    }
+
+    // Discover the frontend servers of a specified kind, which hold the sessions of the
+    // users and can receive pushes for them.
+    async fn frontend_servers_by_kind(
+        &mut self,
+        kind: &server::ServerKind,
+    ) -> Result<Vec<Arc<ServerInfo>>, Error> {
+        Ok(self
+            .servers_by_kind(kind)
+            .await?
+            .into_iter()
+            .filter(|server| server.frontend)
+            .collect())
+    }
+
+    // Discover the backend servers of a specified kind.
+    async fn backend_servers_by_kind(
+        &mut self,
+        kind: &server::ServerKind,
+    ) -> Result<Vec<Arc<ServerInfo>>, Error> {
+        Ok(self
+            .servers_by_kind(kind)
+            .await?
+            .into_iter()
+            .filter(|server| !server.frontend)
+            .collect())
+    }
+
     // Starts the discovery.
     async fn start(&mut self, app_die_sender: broadcast::Sender<()>) -> Result<(), Error>;
 
-    // Stops the dicovery.
+    // Stops the dicovery. The discovery is always stopped, but an error is returned if
+    // one of its background tasks could not be stopped cleanly.
     async fn shutdown(&mut self) -> Result<(), Error>;
 
     // Allows the current server to subscribe for notifications of added and removed servers.
@@ -81,8 +216,12 @@ pub trait Discovery: Send + 'static {
 // Server represents a trait for handling RPCs comming from the cluster.
 #[async_trait]
 pub trait RpcServer: Sync + Send + 'static {
-    // Starts the server.
-    async fn start(&self) -> Result<mpsc::Receiver<Rpc>, Error>;
+    // Starts the server. The app die sender is used to signal the application to die
+    // when the server can no longer receive RPCs.
+    async fn start(
+        &self,
+        app_die_sender: broadcast::Sender<()>,
+    ) -> Result<mpsc::Receiver<Rpc>, Error>;
 
     // Shuts down the server.
     async fn shutdown(&self) -> Result<(), Error>;
@@ -100,6 +239,16 @@ pub trait RpcClient: Send + Sync + 'static {
         server_info: Arc<ServerInfo>,
     ) -> Result<protos::Response, Error>;
 
+    // Sends an RPC to a given server in the cluster without waiting for a response.
+    // Returns once the message was flushed to the cluster.
+    async fn notify(
+        &self,
+        ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        server_info: Arc<ServerInfo>,
+    ) -> Result<(), Error>;
+
     // Kicks a user connected to a specific frontend server.
     async fn kick_user(
         &self,
@@ -131,22 +280,48 @@ pub enum Notification {
     ServerRemoved(Arc<ServerInfo>),
 }
 
+// Turns a subscription to the discovery into a stream of notifications, for consumers
+// that prefer pulling them in their own task. Notifications missed because the consumer
+// lagged behind are skipped, and the stream ends when the discovery is dropped.
+pub fn notification_stream(
+    subscription: broadcast::Receiver<Notification>,
+) -> impl Stream<Item = Notification> {
+    subscription.filter_map(|notification| notification.ok())
+}
+
 // Represents an RPC that comes from another server in the cluster.
 #[derive(Debug)]
 pub struct Rpc {
     req: Vec<u8>,
     responder: oneshot::Sender<Vec<u8>>,
+    rpc_type: Option<protos::RpcType>,
 }
 
 impl Rpc {
     pub fn new(req: Vec<u8>, responder: oneshot::Sender<Vec<u8>>) -> Self {
-        Self { req, responder }
+        Self {
+            req,
+            responder,
+            rpc_type: None,
+        }
+    }
+
+    // Sets the type of the RPC, known once the request is decoded.
+    pub fn with_rpc_type(mut self, rpc_type: protos::RpcType) -> Self {
+        self.rpc_type = Some(rpc_type);
+        self
     }
 
     pub fn request(&self) -> &[u8] {
         &self.req
     }
 
+    // The type of the RPC, which tells system RPCs, like binds and kicks, apart from the
+    // ones sent to user handlers. It is unknown if the request could not be decoded.
+    pub fn rpc_type(&self) -> Option<protos::RpcType> {
+        self.rpc_type
+    }
+
     // Responds to the RPC with the given response. Returns true
     // on success and false if it was not able to answer.
     pub fn respond(self, res: Vec<u8>) -> bool {
@@ -157,3 +332,49 @@ impl Rpc {
         (self.req, self.responder)
     }
 }
+
+impl protos::Response {
+    // Converts the response into its data, or into an `Error::Rpc` if the server answered
+    // with an error, keeping its code (e.g. PIT-404) so callers can tell errors apart.
+    pub fn into_result(self) -> Result<Vec<u8>, Error> {
+        match self.error {
+            Some(err) => Err(Error::Rpc {
+                code: err.code,
+                msg: err.msg,
+            }),
+            None => Ok(self.data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+
+    #[test]
+    fn successful_responses_are_converted_into_their_data() {
+        let response = protos::Response {
+            data: b"hello".to_vec(),
+            error: None,
+        };
+        assert!(matches!(response.into_result(), Ok(data) if data == b"hello"));
+    }
+
+    #[test]
+    fn error_responses_are_converted_into_rpc_errors() {
+        let response = protos::Response {
+            data: vec![],
+            error: Some(protos::Error {
+                code: constants::CODE_NOT_FOUND.to_owned(),
+                msg: "route cannot be found".to_owned(),
+                ..Default::default()
+            }),
+        };
+        assert!(matches!(
+            response.into_result(),
+            Err(Error::Rpc { code, msg })
+                if code == constants::CODE_NOT_FOUND && msg == "route cannot be found"
+        ));
+    }
+}
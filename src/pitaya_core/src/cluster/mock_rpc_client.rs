@@ -0,0 +1,221 @@
+use super::{Error, RpcClient, ServerId, ServerInfo, ServerKind};
+use crate::{constants, context, message, protos};
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+// An RPC sent through the mock client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedRpc {
+    pub rpc_type: protos::RpcType,
+    pub kind: message::Kind,
+    pub route: String,
+    pub data: Vec<u8>,
+    pub server_id: ServerId,
+}
+
+// An RPC client that does not need a cluster, for unit testing code that sends RPCs. It
+// records everything it sends and answers calls with the responses configured for their
+// routes. Calls to routes without a response are answered with a not found error, like a
+// server without a handler for them would.
+#[derive(Default)]
+pub struct MockRpcClient {
+    responses: Mutex<HashMap<String, protos::Response>>,
+    rpcs: Mutex<Vec<RecordedRpc>>,
+    kicks: Mutex<Vec<protos::KickMsg>>,
+    pushes: Mutex<Vec<protos::Push>>,
+}
+
+impl MockRpcClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Answers the calls to the given route with the response.
+    pub fn with_response(self, route: impl ToString, response: protos::Response) -> Self {
+        self.set_response(route, response);
+        self
+    }
+
+    // Answers the calls to the given route with the response, replacing the previous one.
+    pub fn set_response(&self, route: impl ToString, response: protos::Response) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(route.to_string(), response);
+    }
+
+    // The calls and notifies sent so far, in the order they were sent.
+    pub fn rpcs(&self) -> Vec<RecordedRpc> {
+        self.rpcs.lock().unwrap().clone()
+    }
+
+    // The kicks sent so far.
+    pub fn kicks(&self) -> Vec<protos::KickMsg> {
+        self.kicks.lock().unwrap().clone()
+    }
+
+    // The pushes sent so far.
+    pub fn pushes(&self) -> Vec<protos::Push> {
+        self.pushes.lock().unwrap().clone()
+    }
+
+    fn record(&self, rpc_type: protos::RpcType, msg: message::Message, server_info: &ServerInfo) {
+        self.rpcs.lock().unwrap().push(RecordedRpc {
+            rpc_type,
+            kind: msg.kind,
+            route: msg.route,
+            data: msg.data,
+            server_id: server_info.id.clone(),
+        });
+    }
+}
+
+#[async_trait]
+impl RpcClient for MockRpcClient {
+    async fn call(
+        &self,
+        _ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        server_info: Arc<ServerInfo>,
+    ) -> Result<protos::Response, Error> {
+        let response = self.responses.lock().unwrap().get(&msg.route).cloned();
+        let response = response.unwrap_or_else(|| protos::Response {
+            error: Some(protos::Error {
+                code: constants::CODE_NOT_FOUND.to_owned(),
+                msg: format!("route not found: {}", msg.route),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        self.record(rpc_type, msg, &server_info);
+        Ok(response)
+    }
+
+    async fn notify(
+        &self,
+        _ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        server_info: Arc<ServerInfo>,
+    ) -> Result<(), Error> {
+        self.record(rpc_type, msg, &server_info);
+        Ok(())
+    }
+
+    async fn kick_user(
+        &self,
+        _server_id: ServerId,
+        _server_kind: ServerKind,
+        kick_msg: protos::KickMsg,
+    ) -> Result<protos::KickAnswer, Error> {
+        self.kicks.lock().unwrap().push(kick_msg);
+        Ok(protos::KickAnswer { kicked: true })
+    }
+
+    async fn push_to_user(
+        &self,
+        _server_kind: ServerKind,
+        push_msg: protos::Push,
+    ) -> Result<(), Error> {
+        self.pushes.lock().unwrap().push(push_msg);
+        Ok(())
+    }
+
+    async fn start(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    // Code under test, which asks a room server for the number of players in a room.
+    async fn players_in_room(client: &dyn RpcClient, room: Arc<ServerInfo>) -> Result<u32, Error> {
+        let response = client
+            .call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                message::Message {
+                    route: "room.room.players".to_owned(),
+                    data: b"lobby".to_vec(),
+                    ..Default::default()
+                },
+                room,
+            )
+            .await?;
+        let data = response.into_result()?;
+        String::from_utf8_lossy(&data)
+            .parse()
+            .map_err(|_| Error::Internal("invalid player count".to_owned()))
+    }
+
+    fn room_server() -> Arc<ServerInfo> {
+        Arc::new(ServerInfo {
+            id: ServerId::from("room-1"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        })
+    }
+
+    #[tokio::test]
+    async fn calls_are_answered_with_the_response_of_their_route() -> Result<(), Box<dyn StdError>>
+    {
+        let client = MockRpcClient::new().with_response(
+            "room.room.players",
+            protos::Response {
+                data: b"42".to_vec(),
+                error: None,
+            },
+        );
+
+        assert_eq!(players_in_room(&client, room_server()).await?, 42);
+        assert_eq!(
+            client.rpcs(),
+            vec![RecordedRpc {
+                rpc_type: protos::RpcType::User,
+                kind: message::Kind::Request,
+                route: "room.room.players".to_owned(),
+                data: b"lobby".to_vec(),
+                server_id: ServerId::from("room-1"),
+            }]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calls_to_routes_without_a_response_are_not_found() {
+        let client = MockRpcClient::new();
+        assert!(matches!(
+            players_in_room(&client, room_server()).await,
+            Err(Error::Rpc { code, .. }) if code == constants::CODE_NOT_FOUND
+        ));
+        assert_eq!(client.rpcs().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pushes_are_recorded() -> Result<(), Box<dyn StdError>> {
+        let client = MockRpcClient::new();
+        let push = protos::Push {
+            route: "room.onjoin".to_owned(),
+            uid: "user-1".to_owned(),
+            data: b"joined".to_vec(),
+        };
+        client
+            .push_to_user(ServerKind::from("connector"), push.clone())
+            .await?;
+        assert_eq!(client.pushes(), vec![push]);
+        Ok(())
+    }
+}
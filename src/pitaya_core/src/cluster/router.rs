@@ -0,0 +1,209 @@
+use super::ServerInfo;
+use crate::{context, utils};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+// A Router picks the server of a given kind that receives an RPC, among the servers
+// returned by the service discovery.
+pub trait Router: Send + Sync + 'static {
+    // Returns the server that should receive the RPC with the given context, or `None`
+    // if there are no servers.
+    fn route(&self, ctx: &context::Context, servers: &[Arc<ServerInfo>])
+        -> Option<Arc<ServerInfo>>;
+}
+
+// Picks a random server for each RPC.
+#[derive(Debug, Default)]
+pub struct RandomRouter;
+
+impl Router for RandomRouter {
+    fn route(
+        &self,
+        _ctx: &context::Context,
+        servers: &[Arc<ServerInfo>],
+    ) -> Option<Arc<ServerInfo>> {
+        utils::random_server(servers)
+    }
+}
+
+// Picks the servers one after the other. Since the discovery does not return the servers
+// in a stable order, they are sorted by id before picking one.
+#[derive(Debug, Default)]
+pub struct RoundRobinRouter {
+    next: AtomicUsize,
+}
+
+impl Router for RoundRobinRouter {
+    fn route(
+        &self,
+        _ctx: &context::Context,
+        servers: &[Arc<ServerInfo>],
+    ) -> Option<Arc<ServerInfo>> {
+        if servers.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<&Arc<ServerInfo>> = servers.iter().collect();
+        sorted.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % sorted.len();
+        Some(sorted[index].clone())
+    }
+}
+
+// Picks the same server for all RPCs with the same routing key (see
+// `Context::set_routing_key`), using rendezvous hashing: each server gets a score from
+// the hash of the key and its id, and the highest score wins. When a server joins or
+// leaves the cluster, only the keys it wins or was winning are routed differently.
+// RPCs without a routing key are sent to a random server.
+#[derive(Debug, Default)]
+pub struct ConsistentHashRouter;
+
+impl Router for ConsistentHashRouter {
+    fn route(
+        &self,
+        ctx: &context::Context,
+        servers: &[Arc<ServerInfo>],
+    ) -> Option<Arc<ServerInfo>> {
+        let key = match ctx.routing_key() {
+            Some(key) => key,
+            None => return utils::random_server(servers),
+        };
+
+        servers
+            .iter()
+            .max_by_key(|server| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                server.id.0.hash(&mut hasher);
+                // The id breaks ties, so that the order of the servers does not matter.
+                (hasher.finish(), &server.id.0)
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::{ServerId, ServerKind};
+    use std::collections::HashMap;
+
+    fn servers(ids: &[&str]) -> Vec<Arc<ServerInfo>> {
+        ids.iter()
+            .map(|id| {
+                Arc::new(ServerInfo {
+                    id: ServerId::from(*id),
+                    kind: ServerKind::from("room"),
+                    metadata: HashMap::new(),
+                    frontend: false,
+                    hostname: "".to_owned(),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_distributes_evenly() {
+        let router = RoundRobinRouter::default();
+        let ctx = context::Context::empty();
+        let servers = servers(&["c", "a", "b"]);
+
+        let picked: Vec<String> = (0..6)
+            .map(|_| router.route(&ctx, &servers).unwrap().id.0.clone())
+            .collect();
+        assert_eq!(picked, vec!["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn round_robin_ignores_discovery_order() {
+        let router = RoundRobinRouter::default();
+        let ctx = context::Context::empty();
+
+        let first = router.route(&ctx, &servers(&["b", "a"])).unwrap();
+        let second = router.route(&ctx, &servers(&["a", "b"])).unwrap();
+        assert_eq!(first.id.0, "a");
+        assert_eq!(second.id.0, "b");
+    }
+
+    #[test]
+    fn routers_return_none_without_servers() {
+        let ctx = context::Context::empty();
+        assert!(RoundRobinRouter::default().route(&ctx, &[]).is_none());
+        assert!(RandomRouter.route(&ctx, &[]).is_none());
+        assert!(ConsistentHashRouter.route(&ctx, &[]).is_none());
+    }
+
+    #[test]
+    fn random_router_picks_known_servers() {
+        let ctx = context::Context::empty();
+        let servers = servers(&["a", "b", "c"]);
+        for _ in 0..20 {
+            let picked = RandomRouter.route(&ctx, &servers).unwrap();
+            assert!(servers.contains(&picked));
+        }
+    }
+
+    fn routed_ids(router: &dyn Router, servers: &[Arc<ServerInfo>]) -> Vec<String> {
+        (0..1000)
+            .map(|i| {
+                let mut ctx = context::Context::empty();
+                ctx.set_routing_key(format!("user-{}", i));
+                router.route(&ctx, servers).unwrap().id.0.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn consistent_hash_is_stable() {
+        let router = ConsistentHashRouter;
+        let mut ctx = context::Context::empty();
+        ctx.set_routing_key("user-id");
+
+        let picked = router.route(&ctx, &servers(&["a", "b", "c"])).unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                router.route(&ctx, &servers(&["c", "b", "a"])).unwrap(),
+                picked
+            );
+        }
+    }
+
+    #[test]
+    fn consistent_hash_remaps_few_keys_on_membership_changes() {
+        let router = ConsistentHashRouter;
+        let ids: Vec<String> = (0..10).map(|i| format!("server-{}", i)).collect();
+        let ids: Vec<&str> = ids.iter().map(|id| id.as_str()).collect();
+
+        let before = routed_ids(&router, &servers(&ids));
+
+        // Only the keys of the server that left are moved.
+        let after_leave = routed_ids(&router, &servers(&ids[1..]));
+        for (before, after) in before.iter().zip(&after_leave) {
+            if before != "server-0" {
+                assert_eq!(before, after);
+            }
+        }
+
+        // Only the keys won by the server that joined are moved.
+        let mut joined = ids.clone();
+        joined.push("server-10");
+        let after_join = routed_ids(&router, &servers(&joined));
+        let moved = before
+            .iter()
+            .zip(&after_join)
+            .filter(|(before, after)| before != after)
+            .count();
+        assert!(after_join
+            .iter()
+            .zip(&before)
+            .all(|(after, before)| after == before || after == "server-10"));
+        assert!(moved < 200, "{} of 1000 keys were moved", moved);
+    }
+}
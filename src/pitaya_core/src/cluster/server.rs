@@ -1,5 +1,9 @@
+use super::Error;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Hash, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct ServerKind(pub String);
@@ -18,10 +22,30 @@ impl Default for ServerKind {
 
 impl<S> From<S> for ServerKind
 where
-    S: std::string::ToString,
+    S: Into<String>,
 {
     fn from(s: S) -> Self {
-        Self(s.to_string())
+        Self(s.into())
+    }
+}
+
+impl fmt::Display for ServerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for ServerKind {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(s))
+    }
+}
+
+impl AsRef<str> for ServerKind {
+    fn as_ref(&self) -> &str {
+        &self.0
     }
 }
 
@@ -42,10 +66,30 @@ impl Default for ServerId {
 
 impl<S> From<S> for ServerId
 where
-    S: std::string::ToString,
+    S: Into<String>,
 {
     fn from(s: S) -> Self {
-        Self(s.to_string())
+        Self(s.into())
+    }
+}
+
+impl fmt::Display for ServerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for ServerId {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(s))
+    }
+}
+
+impl AsRef<str> for ServerId {
+    fn as_ref(&self) -> &str {
+        &self.0
     }
 }
 
@@ -59,10 +103,178 @@ pub struct ServerInfo {
     pub frontend: bool,
 }
 
+impl ServerInfo {
+    // Returns a builder for a server of the given kind.
+    pub fn builder<K: Into<ServerKind>>(kind: K) -> ServerInfoBuilder {
+        ServerInfoBuilder::new(kind)
+    }
+}
+
+// Builds a ServerInfo, filling the fields that were not given. The id defaults to a new
+// random id and the hostname to the hostname of the machine.
+#[derive(Debug)]
+pub struct ServerInfoBuilder {
+    id: Option<ServerId>,
+    kind: ServerKind,
+    metadata: HashMap<String, String>,
+    hostname: Option<String>,
+    frontend: bool,
+    required_frontend_metadata: Vec<String>,
+}
+
+impl ServerInfoBuilder {
+    pub fn new<K: Into<ServerKind>>(kind: K) -> Self {
+        Self {
+            id: None,
+            kind: kind.into(),
+            metadata: HashMap::new(),
+            hostname: None,
+            frontend: false,
+            required_frontend_metadata: Vec::new(),
+        }
+    }
+
+    pub fn with_id<I: Into<ServerId>>(mut self, id: I) -> Self {
+        self.id.replace(id.into());
+        self
+    }
+
+    pub fn with_metadata<K: ToString, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn with_hostname<H: ToString>(mut self, hostname: H) -> Self {
+        self.hostname.replace(hostname.to_string());
+        self
+    }
+
+    pub fn with_frontend(mut self, frontend: bool) -> Self {
+        self.frontend = frontend;
+        self
+    }
+
+    // Makes `build` fail if the server is a frontend and the given metadata key is missing.
+    pub fn with_required_frontend_metadata<K: ToString>(mut self, key: K) -> Self {
+        self.required_frontend_metadata.push(key.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<ServerInfo, Error> {
+        if self.kind.0.is_empty() {
+            return Err(Error::EmptyServerKind);
+        }
+        if self.frontend {
+            if let Some(key) = self
+                .required_frontend_metadata
+                .iter()
+                .find(|key| !self.metadata.contains_key(*key))
+            {
+                return Err(Error::InvalidServerInfo(format!(
+                    "frontend server is missing the metadata key {:?}",
+                    key
+                )));
+            }
+        }
+
+        Ok(ServerInfo {
+            id: self.id.unwrap_or_else(new_server_id),
+            kind: self.kind,
+            metadata: self.metadata,
+            hostname: self.hostname.unwrap_or_else(os_hostname),
+            frontend: self.frontend,
+        })
+    }
+}
+
+// Generates a new random server id.
+fn new_server_id() -> ServerId {
+    ServerId(format!("{:032x}", rand::random::<u128>()))
+}
+
+// Returns the hostname of the machine, or an empty string if it is not known. The
+// standard library does not expose it, so it is read from the environment or from
+// /etc/hostname.
+fn os_hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|hostname| hostname.trim().to_owned())
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn ids_and_kinds_round_trip_through_strings() -> Result<(), Infallible> {
+        let id: ServerId = "my-id".parse()?;
+        assert_eq!(id, ServerId::from("my-id"));
+        assert_eq!(id.to_string(), "my-id");
+        assert_eq!(id.as_ref(), "my-id");
+
+        let kind: ServerKind = "room".parse()?;
+        assert_eq!(kind, ServerKind::from("room"));
+        assert_eq!(format!("{}", kind), "room");
+        assert_eq!(kind.to_string().parse::<ServerKind>()?, kind);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_fills_defaults() -> Result<(), Error> {
+        let first = ServerInfo::builder("room").build()?;
+        let second = ServerInfo::builder("room").build()?;
+
+        assert_eq!(first.kind, ServerKind::from("room"));
+        assert_eq!(first.hostname, os_hostname());
+        assert!(!first.frontend);
+        assert!(first.metadata.is_empty());
+        assert_eq!(first.id.0.len(), 32);
+        assert_ne!(first.id, second.id);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_uses_given_values() -> Result<(), Error> {
+        let server = ServerInfo::builder("connector")
+            .with_id("my-id")
+            .with_hostname("my-host")
+            .with_frontend(true)
+            .with_metadata("region", "us")
+            .with_required_frontend_metadata("region")
+            .build()?;
+
+        assert_eq!(server.id, ServerId::from("my-id"));
+        assert_eq!(server.hostname, "my-host");
+        assert!(server.frontend);
+        assert_eq!(
+            server.metadata.get("region").map(String::as_str),
+            Some("us")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn builder_validates_server() {
+        assert!(matches!(
+            ServerInfo::builder("").build(),
+            Err(Error::EmptyServerKind)
+        ));
+        assert!(matches!(
+            ServerInfo::builder("connector")
+                .with_frontend(true)
+                .with_required_frontend_metadata("region")
+                .build(),
+            Err(Error::InvalidServerInfo(_))
+        ));
+        // Backend servers do not need the frontend metadata.
+        assert!(ServerInfo::builder("room")
+            .with_required_frontend_metadata("region")
+            .build()
+            .is_ok());
+    }
+
     #[test]
     fn server_serialize() -> Result<(), serde_json::Error> {
         let sv = ServerInfo {
@@ -0,0 +1,180 @@
+use super::{Discovery, Error, Notification, ServerId, ServerInfo, ServerKind};
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::broadcast;
+
+// A discovery that knows a fixed list of servers, for running pitaya without etcd, like
+// in local development and tests. Servers never join or leave the cluster, so starting
+// and stopping it does nothing and subscribers are never notified.
+pub struct StaticDiscovery {
+    servers_by_id: HashMap<ServerId, Arc<ServerInfo>>,
+    servers_by_kind: HashMap<ServerKind, Vec<Arc<ServerInfo>>>,
+    // Kept so that subscriptions stay open, even though nothing is ever sent.
+    notification_sender: broadcast::Sender<Notification>,
+}
+
+impl StaticDiscovery {
+    pub fn new(servers: Vec<Arc<ServerInfo>>) -> Self {
+        let mut servers_by_id = HashMap::new();
+        let mut servers_by_kind: HashMap<ServerKind, Vec<Arc<ServerInfo>>> = HashMap::new();
+        for server in servers {
+            // A server given twice replaces the previous one with the same id.
+            if let Some(old) = servers_by_id.insert(server.id.clone(), server.clone()) {
+                if let Some(servers) = servers_by_kind.get_mut(&old.kind) {
+                    servers.retain(|s| s.id != old.id);
+                }
+            }
+            servers_by_kind
+                .entry(server.kind.clone())
+                .or_default()
+                .push(server);
+        }
+
+        Self {
+            servers_by_id,
+            servers_by_kind,
+            notification_sender: broadcast::channel(1).0,
+        }
+    }
+}
+
+#[async_trait]
+impl Discovery for StaticDiscovery {
+    async fn server_by_id(
+        &mut self,
+        id: &ServerId,
+        _kind: Option<&ServerKind>,
+    ) -> Result<Option<Arc<ServerInfo>>, Error> {
+        Ok(self.servers_by_id.get(id).cloned())
+    }
+
+    async fn servers_by_kind(&mut self, kind: &ServerKind) -> Result<Vec<Arc<ServerInfo>>, Error> {
+        Ok(self.servers_by_kind.get(kind).cloned().unwrap_or_default())
+    }
+
+    async fn start(&mut self, _app_die_sender: broadcast::Sender<()>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn subscribe(&mut self) -> broadcast::Receiver<Notification> {
+        self.notification_sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    fn server(kind: &str, id: &str, region: &str) -> Arc<ServerInfo> {
+        let mut metadata = HashMap::new();
+        metadata.insert("region".to_owned(), region.to_owned());
+        Arc::new(ServerInfo {
+            id: ServerId::from(id),
+            kind: ServerKind::from(kind),
+            metadata,
+            hostname: "".to_owned(),
+            frontend: false,
+        })
+    }
+
+    fn new_discovery() -> StaticDiscovery {
+        StaticDiscovery::new(vec![
+            server("room", "room-1", "us-east"),
+            server("room", "room-2", "us-west"),
+            server("metagame", "metagame-1", "us-east"),
+        ])
+    }
+
+    #[tokio::test]
+    async fn server_by_id_finds_known_servers() -> Result<(), Box<dyn StdError>> {
+        let mut sd = new_discovery();
+
+        let found = sd
+            .server_by_id(&ServerId::from("room-2"), Some(&ServerKind::from("room")))
+            .await?;
+        assert_eq!(found, Some(server("room", "room-2", "us-west")));
+
+        let found = sd.server_by_id(&ServerId::from("metagame-1"), None).await?;
+        assert_eq!(found, Some(server("metagame", "metagame-1", "us-east")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_by_id_returns_none_for_unknown_servers() -> Result<(), Box<dyn StdError>> {
+        let mut sd = new_discovery();
+        assert!(sd
+            .server_by_id(&ServerId::from("unknown"), None)
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_by_kind_returns_all_servers() -> Result<(), Box<dyn StdError>> {
+        let mut sd = new_discovery();
+
+        let rooms = sd.servers_by_kind(&ServerKind::from("room")).await?;
+        assert_eq!(
+            rooms,
+            vec![
+                server("room", "room-1", "us-east"),
+                server("room", "room-2", "us-west"),
+            ]
+        );
+        assert!(sd
+            .servers_by_kind(&ServerKind::from("unknown"))
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_can_be_filtered_by_metadata() -> Result<(), Box<dyn StdError>> {
+        let mut sd = new_discovery();
+        let east = sd
+            .servers_by_kind_filtered(&ServerKind::from("room"), &|metadata| {
+                metadata.get("region").map(String::as_str) == Some("us-east")
+            })
+            .await?;
+        assert_eq!(east, vec![server("room", "room-1", "us-east")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repeated_servers_replace_previous_ones() -> Result<(), Box<dyn StdError>> {
+        let mut sd = StaticDiscovery::new(vec![
+            server("room", "room-1", "us-east"),
+            server("room", "room-1", "us-west"),
+        ]);
+        assert_eq!(
+            sd.servers_by_kind(&ServerKind::from("room")).await?,
+            vec![server("room", "room-1", "us-west")]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn start_and_shutdown_do_nothing() -> Result<(), Box<dyn StdError>> {
+        let mut sd = new_discovery();
+        let mut subscription = sd.subscribe();
+
+        sd.start(broadcast::channel(1).0).await?;
+        sd.shutdown().await?;
+
+        // The subscription stays open, but nothing is ever notified.
+        assert!(matches!(
+            subscription.try_recv(),
+            Err(broadcast::TryRecvError::Empty)
+        ));
+        assert_eq!(
+            sd.servers_by_kind(&ServerKind::from("room")).await?.len(),
+            2
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{constants, protos};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unknown compression codec: {0}")]
+    UnknownCodec(String),
+
+    #[error("failed to compress data: {0}")]
+    Compress(std::io::Error),
+
+    #[error("failed to decompress data: {0}")]
+    Decompress(std::io::Error),
+
+    #[error("invalid request metadata: {0}")]
+    InvalidMetadata(serde_json::Error),
+}
+
+// The algorithm used to compress the data of a message.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Gzip
+    }
+}
+
+impl Codec {
+    // The name of the codec, sent in the request metadata.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "gzip" => Ok(Codec::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(Error::UnknownCodec(name.to_owned())),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Codec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).map_err(Error::Compress)?;
+                encoder.finish().map_err(Error::Compress)
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::encode_all(data, 0).map_err(Error::Compress),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Codec::Gzip => {
+                let mut decompressed = Vec::new();
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut decompressed)
+                    .map_err(Error::Decompress)?;
+                Ok(decompressed)
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::decode_all(data).map_err(Error::Decompress),
+        }
+    }
+}
+
+// Compresses the message data of a request with the given codec, unless it is smaller
+// than `min_size`. The codec is added to the request metadata so that the receiving
+// server knows how to decompress it. Returns whether the data was compressed.
+pub fn compress_request(
+    req: &mut protos::Request,
+    codec: Codec,
+    min_size: usize,
+) -> Result<bool, Error> {
+    let msg = match req.msg.as_mut() {
+        Some(msg) if msg.data.len() >= min_size => msg,
+        _ => return Ok(false),
+    };
+
+    let mut metadata = parse_metadata(&req.metadata)?;
+    msg.data = codec.compress(&msg.data)?;
+    metadata.insert(constants::COMPRESSION_KEY.to_owned(), codec.name().into());
+    req.metadata = serde_json::to_vec(&metadata).map_err(Error::InvalidMetadata)?;
+    Ok(true)
+}
+
+// Decompresses the message data of a request, if it was compressed. The codec is removed
+// from the request metadata. Returns whether the data was decompressed.
+pub fn decompress_request(req: &mut protos::Request) -> Result<bool, Error> {
+    let mut metadata = parse_metadata(&req.metadata)?;
+    let codec = match metadata.remove(constants::COMPRESSION_KEY) {
+        Some(serde_json::Value::String(name)) => Codec::from_name(&name)?,
+        Some(value) => return Err(Error::UnknownCodec(value.to_string())),
+        None => return Ok(false),
+    };
+
+    if let Some(msg) = req.msg.as_mut() {
+        msg.data = codec.decompress(&msg.data)?;
+    }
+    req.metadata = serde_json::to_vec(&metadata).map_err(Error::InvalidMetadata)?;
+    Ok(true)
+}
+
+fn parse_metadata(metadata: &[u8]) -> Result<HashMap<String, serde_json::Value>, Error> {
+    if metadata.is_empty() {
+        return Ok(HashMap::new());
+    }
+    serde_json::from_slice(metadata).map_err(Error::InvalidMetadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_data(data: Vec<u8>) -> protos::Request {
+        protos::Request {
+            msg: Some(protos::Msg {
+                data,
+                route: "room.room.join".to_owned(),
+                ..protos::Msg::default()
+            }),
+            metadata: br#"{"pitaya.trace_id":"abcdef"}"#.to_vec(),
+            ..protos::Request::default()
+        }
+    }
+
+    fn round_trip(codec: Codec) {
+        let data = b"a large payload that repeats itself. ".repeat(1000);
+        let mut req = request_with_data(data.clone());
+
+        assert!(compress_request(&mut req, codec, 1024).unwrap());
+        let compressed = &req.msg.as_ref().unwrap().data;
+        assert!(compressed.len() < data.len());
+
+        assert!(decompress_request(&mut req).unwrap());
+        assert_eq!(req.msg.as_ref().unwrap().data, data);
+        assert_eq!(req.metadata, br#"{"pitaya.trace_id":"abcdef"}"#.to_vec());
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        round_trip(Codec::Gzip);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(Codec::Zstd);
+    }
+
+    #[test]
+    fn small_payloads_are_not_compressed() {
+        let mut req = request_with_data(b"small".to_vec());
+        assert!(!compress_request(&mut req, Codec::Gzip, 1024).unwrap());
+        assert_eq!(req.msg.as_ref().unwrap().data, b"small");
+
+        assert!(!decompress_request(&mut req).unwrap());
+        assert_eq!(req.msg.as_ref().unwrap().data, b"small");
+    }
+
+    #[test]
+    fn unknown_codecs_are_rejected() {
+        let mut req = request_with_data(b"data".to_vec());
+        req.metadata = br#"{"pitaya.compression":"lz4"}"#.to_vec();
+        assert!(matches!(
+            decompress_request(&mut req),
+            Err(Error::UnknownCodec(_))
+        ));
+    }
+}
@@ -1,7 +1,20 @@
 pub const PEER_ID_KEY: &str = "peer.id";
 pub const PEER_SERVICE_KEY: &str = "peer.service";
+pub const DEADLINE_KEY: &str = "pitaya.deadline";
+pub const TRACE_ID_KEY: &str = "pitaya.trace_id";
+pub const REQUEST_ID_KEY: &str = "pitaya.request_id";
+pub const COMPRESSION_KEY: &str = "pitaya.compression";
+pub const SESSION_UID_KEY: &str = "pitaya.session_uid";
+pub const ROUTING_KEY: &str = "pitaya.routing_key";
+pub const RESPONSE_FORMAT_KEY: &str = "pitaya.response_format";
+
+// The route of the frontend handler that kicks a user.
+pub const KICK_ROUTE: &str = "sys.kick";
 
 pub const CODE_INTERNAL_ERROR: &str = "PIT-500";
 pub const CODE_SERVICE_UNAVAILABLE: &str = "PIT-502";
 pub const CODE_BAD_FORMAT: &str = "PIT-400";
 pub const CODE_NOT_FOUND: &str = "PIT-404";
+pub const CODE_DEADLINE_EXCEEDED: &str = "PIT-504";
+pub const CODE_PAYLOAD_TOO_LARGE: &str = "PIT-413";
+pub const CODE_TOO_MANY_REQUESTS: &str = "PIT-429";
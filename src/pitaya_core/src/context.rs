@@ -1,6 +1,10 @@
-use crate::protos;
+use crate::{constants, encoding, protos};
 use serde::Serialize;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub struct NotFound(pub String);
 
@@ -10,6 +14,7 @@ pub trait FromContext<'c>: Sized {
 
 // Context represents the context that is associated with an RPC. This context will be propagated
 // through RPCs in different pitaya servers.
+#[derive(Clone)]
 pub struct Context {
     map: HashMap<String, serde_json::Value>,
     container: Arc<state::Container>,
@@ -24,6 +29,13 @@ impl Context {
         Ok(Self { map, container })
     }
 
+    // Rebuilds the context sent along with a request by another server, like the trace id,
+    // the deadline and the uid of the session. This is meant for consumers of raw RPCs,
+    // since handlers already receive the context of their requests.
+    pub fn from_request(req: &protos::Request) -> Result<Self, serde_json::Error> {
+        Self::new(req, Arc::new(state::Container::new()))
+    }
+
     pub fn empty() -> Self {
         Self {
             map: HashMap::new(),
@@ -46,6 +58,161 @@ impl Context {
         let json_val = serde_json::to_value(val)?;
         Ok(self.map.insert(key.to_string(), json_val).is_some())
     }
+
+    // Sets the deadline of the RPC associated with this context. The deadline is sent
+    // along with the RPC, so the receiving server can also respect it.
+    pub fn set_deadline(&mut self, deadline: SystemTime) {
+        let millis = deadline
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.map
+            .insert(constants::DEADLINE_KEY.to_string(), millis.into());
+    }
+
+    // Sets the deadline of the RPC to the given timeout from now.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.set_deadline(SystemTime::now() + timeout);
+    }
+
+    // Returns the deadline of the RPC, if there is one.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.map
+            .get(constants::DEADLINE_KEY)
+            .and_then(deadline_from_value)
+    }
+
+    // Returns how much time is left until the deadline. If the deadline has already
+    // passed, zero is returned.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline().map(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
+    // Sets the trace id of the RPC. The trace id is propagated to the servers that
+    // receive the RPC, which allows correlating their logs.
+    pub fn set_trace_id<T: ToString>(&mut self, trace_id: T) {
+        self.map.insert(
+            constants::TRACE_ID_KEY.to_string(),
+            trace_id.to_string().into(),
+        );
+    }
+
+    // Returns the trace id of the RPC, if there is one.
+    pub fn trace_id(&self) -> Option<&str> {
+        self.map
+            .get(constants::TRACE_ID_KEY)
+            .and_then(|v| v.as_str())
+    }
+
+    // Sets the id of this request, which unlike the trace id is not shared with the RPCs
+    // made while handling it. It is logged by both servers and echoed back in errors.
+    pub fn set_request_id<T: ToString>(&mut self, request_id: T) {
+        self.map.insert(
+            constants::REQUEST_ID_KEY.to_string(),
+            request_id.to_string().into(),
+        );
+    }
+
+    // Returns the id of the request, if there is one.
+    pub fn request_id(&self) -> Option<&str> {
+        self.map
+            .get(constants::REQUEST_ID_KEY)
+            .and_then(|v| v.as_str())
+    }
+
+    // Returns the uid of the user whose session the RPC is about, if there is one.
+    pub fn session_uid(&self) -> Option<&str> {
+        self.map
+            .get(constants::SESSION_UID_KEY)
+            .and_then(|v| v.as_str())
+    }
+
+    // Sets the key used by routers to send related RPCs to the same server, like the id
+    // of a user.
+    pub fn set_routing_key<T: ToString>(&mut self, routing_key: T) {
+        self.map.insert(
+            constants::ROUTING_KEY.to_string(),
+            routing_key.to_string().into(),
+        );
+    }
+
+    // Returns the routing key of the RPC. If none was set, the uid of the session the
+    // RPC is about is used instead.
+    pub fn routing_key(&self) -> Option<&str> {
+        self.map
+            .get(constants::ROUTING_KEY)
+            .or_else(|| self.map.get(constants::SESSION_UID_KEY))
+            .and_then(|v| v.as_str())
+    }
+
+    // Sets the format in which the server should encode the response of the RPC.
+    pub fn set_response_format(&mut self, format: encoding::Format) {
+        self.map.insert(
+            constants::RESPONSE_FORMAT_KEY.to_string(),
+            format.name().into(),
+        );
+    }
+}
+
+// Generates a new random trace id.
+pub fn new_trace_id() -> String {
+    random_id()
+}
+
+// Generates a new random request id.
+pub fn new_request_id() -> String {
+    random_id()
+}
+
+fn random_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+// The values from the metadata of a request that are needed before handling it.
+#[derive(Debug, Default, PartialEq)]
+pub struct RequestMetadata {
+    pub deadline: Option<SystemTime>,
+    pub trace_id: Option<String>,
+    pub request_id: Option<String>,
+    pub response_format: Option<encoding::Format>,
+}
+
+impl RequestMetadata {
+    // Parses the metadata of a request without creating a context. Missing or invalid
+    // values are ignored.
+    pub fn parse(metadata: &[u8]) -> Self {
+        let map: HashMap<String, serde_json::Value> = match serde_json::from_slice(metadata) {
+            Ok(map) => map,
+            Err(_) => return Self::default(),
+        };
+        Self {
+            deadline: map
+                .get(constants::DEADLINE_KEY)
+                .and_then(deadline_from_value),
+            trace_id: map
+                .get(constants::TRACE_ID_KEY)
+                .and_then(|v| v.as_str())
+                .map(String::from),
+            request_id: map
+                .get(constants::REQUEST_ID_KEY)
+                .and_then(|v| v.as_str())
+                .map(String::from),
+            response_format: map
+                .get(constants::RESPONSE_FORMAT_KEY)
+                .and_then(|v| v.as_str())
+                .and_then(|name| encoding::Format::from_name(name).ok()),
+        }
+    }
+}
+
+fn deadline_from_value(value: &serde_json::Value) -> Option<SystemTime> {
+    value
+        .as_u64()
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
 }
 
 impl<'s> Into<Vec<u8>> for Context {
@@ -54,3 +221,111 @@ impl<'s> Into<Vec<u8>> for Context {
         serde_json::to_vec(&self.map).expect("context map should be a valid json")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_is_propagated_in_metadata() {
+        let mut ctx = Context::empty();
+        assert!(ctx.deadline().is_none());
+        assert!(ctx.remaining().is_none());
+
+        let deadline = UNIX_EPOCH + Duration::from_millis(1_600_000_000_123);
+        ctx.set_deadline(deadline);
+        assert_eq!(ctx.deadline(), Some(deadline));
+        // The deadline has already passed.
+        assert_eq!(ctx.remaining(), Some(Duration::from_secs(0)));
+
+        let metadata: Vec<u8> = ctx.into();
+        assert_eq!(RequestMetadata::parse(&metadata).deadline, Some(deadline));
+    }
+
+    #[test]
+    fn trace_id_is_propagated_in_metadata() {
+        let mut ctx = Context::empty();
+        assert!(ctx.trace_id().is_none());
+
+        ctx.set_trace_id("abcdef");
+        assert_eq!(ctx.trace_id(), Some("abcdef"));
+
+        let metadata: Vec<u8> = ctx.into();
+        assert_eq!(
+            RequestMetadata::parse(&metadata),
+            RequestMetadata {
+                deadline: None,
+                trace_id: Some("abcdef".to_owned()),
+                request_id: None,
+                response_format: None,
+            }
+        );
+    }
+
+    #[test]
+    fn request_id_is_propagated_in_metadata() {
+        let mut ctx = Context::empty();
+        assert!(ctx.request_id().is_none());
+
+        ctx.set_request_id("123456");
+        assert_eq!(ctx.request_id(), Some("123456"));
+
+        let metadata: Vec<u8> = ctx.into();
+        let metadata = RequestMetadata::parse(&metadata);
+        assert_eq!(metadata.request_id, Some("123456".to_owned()));
+        assert_eq!(metadata.trace_id, None);
+    }
+
+    #[test]
+    fn invalid_metadata_is_ignored() {
+        assert_eq!(
+            RequestMetadata::parse(b"not json"),
+            RequestMetadata::default()
+        );
+    }
+
+    #[test]
+    fn timeout_sets_a_future_deadline() {
+        let mut ctx = Context::empty();
+        ctx.set_timeout(Duration::from_secs(60));
+        let remaining = ctx.remaining().unwrap();
+        assert!(remaining > Duration::from_secs(50));
+        assert!(remaining <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn context_is_rebuilt_from_request() {
+        let mut ctx = Context::empty();
+        let deadline = UNIX_EPOCH + Duration::from_millis(1_600_000_000_123);
+        ctx.set_deadline(deadline);
+        ctx.set_trace_id("abcdef");
+        ctx.add(constants::SESSION_UID_KEY, "user-id").unwrap();
+
+        let req = protos::Request {
+            metadata: ctx.into(),
+            ..Default::default()
+        };
+        let ctx = Context::from_request(&req).unwrap();
+        assert_eq!(ctx.deadline(), Some(deadline));
+        assert_eq!(ctx.trace_id(), Some("abcdef"));
+        assert_eq!(ctx.session_uid(), Some("user-id"));
+
+        assert!(Context::from_request(&protos::Request {
+            metadata: b"not json".to_vec(),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn routing_key_falls_back_to_session_uid() {
+        let mut ctx = Context::empty();
+        assert!(ctx.routing_key().is_none());
+
+        ctx.add(constants::SESSION_UID_KEY, "user-id").unwrap();
+        assert_eq!(ctx.routing_key(), Some("user-id"));
+
+        ctx.set_routing_key("room-id");
+        assert_eq!(ctx.routing_key(), Some("room-id"));
+    }
+}
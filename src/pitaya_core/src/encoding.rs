@@ -0,0 +1,157 @@
+use crate::{protos, utils};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unknown encoding format: {0}")]
+    UnknownFormat(String),
+
+    #[error("failed to encode response: {0}")]
+    Encode(String),
+
+    #[error("failed to decode response: {0}")]
+    Decode(String),
+}
+
+// A Codec converts RPC responses to and from the bytes sent over the wire.
+pub trait Codec: Send + Sync {
+    fn encode_response(&self, res: &protos::Response) -> Result<Vec<u8>, Error>;
+
+    fn decode_response(&self, data: &[u8]) -> Result<protos::Response, Error>;
+}
+
+// Encodes responses as protobuf, which is what pitaya servers use by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProtobufCodec;
+
+impl Codec for ProtobufCodec {
+    fn encode_response(&self, res: &protos::Response) -> Result<Vec<u8>, Error> {
+        Ok(utils::encode_proto(res))
+    }
+
+    fn decode_response(&self, data: &[u8]) -> Result<protos::Response, Error> {
+        protos::Response::decode(data).map_err(|e| Error::Decode(e.to_string()))
+    }
+}
+
+// Encodes responses as JSON, which is easier to debug and to use from clients that do
+// not speak protobuf.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode_response(&self, res: &protos::Response) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(res).map_err(|e| Error::Encode(e.to_string()))
+    }
+
+    fn decode_response(&self, data: &[u8]) -> Result<protos::Response, Error> {
+        serde_json::from_slice(data).map_err(|e| Error::Decode(e.to_string()))
+    }
+}
+
+// The format of the responses sent over the wire. Clients send the format they expect
+// in the request metadata, so that servers answer them accordingly.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Protobuf,
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Protobuf
+    }
+}
+
+impl Format {
+    // The name of the format, sent in the request metadata.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Protobuf => "protobuf",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "protobuf" => Ok(Format::Protobuf),
+            "json" => Ok(Format::Json),
+            _ => Err(Error::UnknownFormat(name.to_owned())),
+        }
+    }
+
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            Format::Protobuf => &ProtobufCodec,
+            Format::Json => &JsonCodec,
+        }
+    }
+}
+
+// Converts a protobuf encoded response, like the ones given by handlers, to the given
+// format.
+pub fn encode_protobuf_response(response: Vec<u8>, format: Format) -> Result<Vec<u8>, Error> {
+    match format {
+        Format::Protobuf => Ok(response),
+        _ => format
+            .codec()
+            .encode_response(&ProtobufCodec.decode_response(&response)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn response() -> protos::Response {
+        let mut metadata = HashMap::new();
+        metadata.insert("reason".to_owned(), "testing".to_owned());
+        protos::Response {
+            data: b"some response data".to_vec(),
+            error: Some(protos::Error {
+                code: "PIT-500".to_owned(),
+                msg: "something failed".to_owned(),
+                metadata,
+            }),
+        }
+    }
+
+    fn round_trip(format: Format) {
+        let codec = format.codec();
+        let data = codec.encode_response(&response()).unwrap();
+        assert_eq!(codec.decode_response(&data).unwrap(), response());
+    }
+
+    #[test]
+    fn protobuf_round_trip() {
+        round_trip(Format::Protobuf);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(Format::Json);
+    }
+
+    #[test]
+    fn protobuf_responses_are_converted() {
+        let protobuf = utils::encode_proto(&response());
+        let json = encode_protobuf_response(protobuf.clone(), Format::Json).unwrap();
+        assert_eq!(JsonCodec.decode_response(&json).unwrap(), response());
+        assert_eq!(
+            encode_protobuf_response(protobuf.clone(), Format::Protobuf).unwrap(),
+            protobuf
+        );
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        assert!(matches!(
+            Format::from_name("xml"),
+            Err(Error::UnknownFormat(_))
+        ));
+    }
+}
@@ -1,6 +1,8 @@
 pub mod cluster;
+pub mod compression;
 pub mod constants;
 pub mod context;
+pub mod encoding;
 pub mod handler;
 pub mod message;
 pub mod metrics;
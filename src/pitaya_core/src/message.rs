@@ -1,13 +1,21 @@
+use crate::{constants, protos, utils};
+use prost::Message as _;
+
 // A MessageKind can be either a Request that receives a response
 // or a Notify, in which the server calls an RPC without expecting response.
-#[derive(Debug, PartialEq)]
+// A Push is sent from the server to a client connected to a frontend, and a Kick asks
+// the frontend to disconnect one of its clients.
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Kind {
     Request = 0,
     Notify = 1,
+    Push = 2,
+    Kick = 3,
 }
 
 // Represents a message that is going to be sent to another server.
 // This can be either a message received from a client (device), or another server as well.
+#[derive(Clone)]
 pub struct Message {
     pub kind: Kind,
     // Unique message id. Zero when notify.
@@ -34,3 +42,88 @@ impl Default for Message {
         }
     }
 }
+
+impl Message {
+    // Converts the message into a push for the user connected to a frontend server.
+    pub fn into_push(self, uid: impl ToString) -> protos::Push {
+        protos::Push {
+            route: self.route,
+            uid: uid.to_string(),
+            data: self.data,
+        }
+    }
+}
+
+impl From<protos::Push> for Message {
+    fn from(push: protos::Push) -> Self {
+        Self {
+            kind: Kind::Push,
+            route: push.route,
+            data: push.data,
+            ..Self::default()
+        }
+    }
+}
+
+impl Message {
+    // Converts the message back into the kick it was created from, failing if its data is
+    // not an encoded kick.
+    pub fn into_kick(self) -> Result<protos::KickMsg, prost::DecodeError> {
+        protos::KickMsg::decode(self.data.as_slice())
+    }
+}
+
+// Kicks are sent to the kick route of the frontend where the user is connected.
+impl From<protos::KickMsg> for Message {
+    fn from(kick: protos::KickMsg) -> Self {
+        Self {
+            kind: Kind::Kick,
+            route: constants::KICK_ROUTE.to_owned(),
+            data: utils::encode_proto(&kick),
+            ..Self::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_round_trip() {
+        let msg = Message {
+            kind: Kind::Push,
+            route: "room.onjoin".to_owned(),
+            data: b"someone joined".to_vec(),
+            ..Message::default()
+        };
+
+        let buf = utils::encode_proto(&msg.into_push("user-id"));
+        let push = protos::Push::decode(buf.as_slice()).unwrap();
+        assert_eq!(push.uid, "user-id");
+
+        let msg = Message::from(push);
+        assert_eq!(msg.kind, Kind::Push);
+        assert_eq!(msg.route, "room.onjoin");
+        assert_eq!(msg.data, b"someone joined");
+    }
+
+    #[test]
+    fn kick_round_trip() {
+        let kick = protos::KickMsg {
+            user_id: "user-id".to_owned(),
+        };
+
+        let msg = Message::from(kick.clone());
+        assert_eq!(msg.kind, Kind::Kick);
+        assert_eq!(msg.route, constants::KICK_ROUTE);
+        assert_eq!(msg.into_kick().unwrap(), kick);
+
+        let invalid = Message {
+            kind: Kind::Kick,
+            data: b"not a kick".to_vec(),
+            ..Message::default()
+        };
+        assert!(invalid.into_kick().is_err());
+    }
+}
@@ -10,9 +10,12 @@ pub enum Error {
 
     #[error("failed to start metrics server: {0}")]
     FailedToStartServer(String),
+
+    #[error("metric already registered: {0}")]
+    MetricAlreadyRegistered(String),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MetricKind {
     Counter,
     Gauge,
@@ -105,15 +108,62 @@ impl Reporter for DummyReporter {
     }
 }
 
-pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> BucketOpts {
-    assert!(count >= 1);
-    assert!(start > 0.0);
-    assert!(factor > 1.0);
-    BucketOpts {
+/// Creates `count` histogram buckets, where the first one has an upper bound of `start`
+/// and each following bucket is `factor` times the previous one.
+pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> Result<BucketOpts, Error> {
+    if count < 1 {
+        return Err(Error::InvalidMetric(
+            "exponential buckets need a positive count".to_owned(),
+        ));
+    }
+    if start <= 0.0 {
+        return Err(Error::InvalidMetric(format!(
+            "exponential buckets need a positive start, got {}",
+            start
+        )));
+    }
+    if factor <= 1.0 {
+        return Err(Error::InvalidMetric(format!(
+            "exponential buckets need a factor greater than 1, got {}",
+            factor
+        )));
+    }
+    Ok(BucketOpts {
         kind: "exponential".to_string(),
         start,
         inc: factor,
         count,
+    })
+}
+
+/// Creates `count` histogram buckets, where the first one has an upper bound of `start`
+/// and each following bucket is `width` wider than the previous one.
+pub fn linear_buckets(start: f64, width: f64, count: usize) -> Result<BucketOpts, Error> {
+    if count < 1 {
+        return Err(Error::InvalidMetric(
+            "linear buckets need a positive count".to_owned(),
+        ));
+    }
+    if width <= 0.0 {
+        return Err(Error::InvalidMetric(format!(
+            "linear buckets need a positive width, got {}",
+            width
+        )));
+    }
+    Ok(BucketOpts {
+        kind: "linear".to_string(),
+        start,
+        inc: width,
+        count,
+    })
+}
+
+/// Treats a metric that was already registered as successfully registered. This allows
+/// components to register their metrics again when they are restarted.
+pub fn allow_already_registered(err: Error) -> Result<(), Error> {
+    match err {
+        Error::MetricAlreadyRegistered(_) => Ok(()),
+        err => Err(err),
     }
 }
 
@@ -125,10 +175,20 @@ pub async fn record_histogram_duration<'a>(
     labels: &'a [&'a str],
 ) {
     let elapsed = std::time::Instant::now() - start;
+    record_histogram(logger, reporter, name, elapsed, labels).await;
+}
+
+pub async fn record_histogram<'a>(
+    logger: slog::Logger,
+    reporter: ThreadSafeReporter,
+    name: &'a str,
+    duration: std::time::Duration,
+    labels: &'a [&'a str],
+) {
     if let Err(e) = reporter
         .read()
         .await
-        .observe_hist(name, elapsed.as_secs_f64(), labels)
+        .observe_hist(name, duration.as_secs_f64(), labels)
     {
         slog::warn!(logger, "observe_hist failed"; "err" => %e);
     }
@@ -145,3 +205,181 @@ pub async fn add_to_gauge<'a>(
         slog::warn!(logger, "add_gauge failed"; "err" => %e);
     }
 }
+
+pub async fn inc_counter<'a>(
+    logger: slog::Logger,
+    reporter: ThreadSafeReporter,
+    name: &'a str,
+    labels: &'a [&'a str],
+) {
+    if let Err(e) = reporter.read().await.inc_counter(name, labels) {
+        slog::warn!(logger, "inc_counter failed"; "err" => %e);
+    }
+}
+
+pub async fn set_gauge<'a>(
+    logger: slog::Logger,
+    reporter: ThreadSafeReporter,
+    name: &'a str,
+    value: f64,
+    labels: &'a [&'a str],
+) {
+    if let Err(e) = reporter.read().await.set_gauge(name, value, labels) {
+        slog::warn!(logger, "set_gauge failed"; "err" => %e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // A reporter that records the values of counters and gauges.
+    #[derive(Default)]
+    struct RecordingReporter {
+        counters: Arc<Mutex<Vec<(String, Vec<String>)>>>,
+        gauges: Arc<Mutex<Vec<(String, f64, Vec<String>)>>>,
+    }
+
+    #[async_trait]
+    impl Reporter for RecordingReporter {
+        fn register_counter(&mut self, _opts: Opts) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn register_histogram(&mut self, _opts: Opts) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn register_gauge(&mut self, _opts: Opts) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn start(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn shutdown(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn inc_counter(&self, name: &str, labels: &[&str]) -> Result<(), Error> {
+            self.counters.lock().unwrap().push((
+                name.to_owned(),
+                labels.iter().map(|l| l.to_string()).collect(),
+            ));
+            Ok(())
+        }
+
+        fn observe_hist(&self, _name: &str, _value: f64, _labels: &[&str]) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn set_gauge(&self, name: &str, value: f64, labels: &[&str]) -> Result<(), Error> {
+            self.gauges.lock().unwrap().push((
+                name.to_owned(),
+                value,
+                labels.iter().map(|l| l.to_string()).collect(),
+            ));
+            Ok(())
+        }
+
+        fn add_gauge(&self, _name: &str, _value: f64, _labels: &[&str]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn inc_counter_reaches_reporter() {
+        let recording = RecordingReporter::default();
+        let counters = recording.counters.clone();
+        let reporter: ThreadSafeReporter = Arc::new(RwLock::new(Box::new(recording)));
+
+        inc_counter(
+            test_helpers::get_root_logger(),
+            reporter.clone(),
+            "rpcs",
+            &["room.room.join"],
+        )
+        .await;
+        inc_counter(
+            test_helpers::get_root_logger(),
+            reporter,
+            "rpcs",
+            &["room.room.leave"],
+        )
+        .await;
+
+        assert_eq!(
+            *counters.lock().unwrap(),
+            vec![
+                ("rpcs".to_owned(), vec!["room.room.join".to_owned()]),
+                ("rpcs".to_owned(), vec!["room.room.leave".to_owned()]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn set_gauge_reaches_reporter() {
+        let recording = RecordingReporter::default();
+        let gauges = recording.gauges.clone();
+        let reporter: ThreadSafeReporter = Arc::new(RwLock::new(Box::new(recording)));
+
+        set_gauge(test_helpers::get_root_logger(), reporter, "depth", 7.0, &[]).await;
+
+        assert_eq!(
+            *gauges.lock().unwrap(),
+            vec![("depth".to_owned(), 7.0, vec![])]
+        );
+    }
+
+    #[test]
+    fn exponential_buckets_are_validated() {
+        assert_eq!(
+            exponential_buckets(0.0005, 2.0, 20).unwrap(),
+            BucketOpts {
+                kind: "exponential".to_owned(),
+                start: 0.0005,
+                inc: 2.0,
+                count: 20,
+            }
+        );
+        assert!(matches!(
+            exponential_buckets(0.0005, 1.0, 20),
+            Err(Error::InvalidMetric(_))
+        ));
+        assert!(matches!(
+            exponential_buckets(0.0, 2.0, 20),
+            Err(Error::InvalidMetric(_))
+        ));
+        assert!(matches!(
+            exponential_buckets(0.0005, 2.0, 0),
+            Err(Error::InvalidMetric(_))
+        ));
+    }
+
+    #[test]
+    fn linear_buckets_are_validated() {
+        assert_eq!(
+            linear_buckets(0.0, 0.5, 4).unwrap(),
+            BucketOpts {
+                kind: "linear".to_owned(),
+                start: 0.0,
+                inc: 0.5,
+                count: 4,
+            }
+        );
+        assert!(matches!(
+            linear_buckets(0.0, 0.0, 4),
+            Err(Error::InvalidMetric(_))
+        ));
+        assert!(matches!(
+            linear_buckets(0.0, -1.0, 4),
+            Err(Error::InvalidMetric(_))
+        ));
+        assert!(matches!(
+            linear_buckets(0.0, 0.5, 0),
+            Err(Error::InvalidMetric(_))
+        ));
+    }
+}
@@ -222,21 +222,213 @@ impl Remote {
         req: &protos::Request,
     ) {
         let maybe_method = handlers.get(&route);
+        let request_id = ctx.request_id().map(String::from);
+        let logger = match &request_id {
+            Some(request_id) => logger.new(o!("request_id" => request_id.clone())),
+            None => logger,
+        };
 
-        let response = if maybe_method.is_none() {
+        let mut response = if maybe_method.is_none() {
             warn!(logger, "route was not found"; "route" => %route.as_str());
-            utils::build_error_response(
-                constants::CODE_NOT_FOUND,
-                format!("route not found: {}", route.as_str()),
-            )
+            protos::Response {
+                error: Some(protos::Error {
+                    code: constants::CODE_NOT_FOUND.to_owned(),
+                    msg: format!("route not found: {}", route.as_str()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
         } else {
             let method = maybe_method.unwrap();
-            let res = method(ctx, session, req).await;
-            utils::encode_proto(&res)
+            method(ctx, session, req).await
         };
 
-        if !rpc.respond(response) {
+        // Only errors have metadata in the response protos, so successful responses are
+        // correlated with their requests through the logs.
+        if let (Some(err), Some(request_id)) = (response.error.as_mut(), request_id) {
+            err.metadata
+                .insert(constants::REQUEST_ID_KEY.to_owned(), request_id);
+        }
+
+        if !rpc.respond(utils::encode_proto(&response)) {
             error!(logger, "failed to respond to rpc");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cluster::{ServerId, ServerInfo, ServerKind, StaticDiscovery},
+        handler::StaticHandlerInfo,
+        message,
+    };
+    use async_trait::async_trait;
+    use std::{collections::HashMap, error::Error as StdError, future::Future, pin::Pin};
+    use tokio::sync::oneshot;
+
+    // The dispatch of user RPCs does not send RPCs to other servers, so this client fails
+    // if one is sent.
+    struct UnusedRpcClient;
+
+    fn unexpected_call(method: &str) -> cluster::Error {
+        cluster::Error::Internal(format!("unexpected call to UnusedRpcClient::{}", method))
+    }
+
+    #[async_trait]
+    impl cluster::RpcClient for UnusedRpcClient {
+        async fn call(
+            &self,
+            _ctx: Context,
+            _rpc_type: protos::RpcType,
+            _msg: message::Message,
+            _server_info: Arc<ServerInfo>,
+        ) -> Result<protos::Response, cluster::Error> {
+            Err(unexpected_call("call"))
+        }
+
+        async fn notify(
+            &self,
+            _ctx: Context,
+            _rpc_type: protos::RpcType,
+            _msg: message::Message,
+            _server_info: Arc<ServerInfo>,
+        ) -> Result<(), cluster::Error> {
+            Err(unexpected_call("notify"))
+        }
+
+        async fn kick_user(
+            &self,
+            _server_id: ServerId,
+            _server_kind: ServerKind,
+            _kick_msg: protos::KickMsg,
+        ) -> Result<protos::KickAnswer, cluster::Error> {
+            Err(unexpected_call("kick_user"))
+        }
+
+        async fn push_to_user(
+            &self,
+            _server_kind: ServerKind,
+            _push_msg: protos::Push,
+        ) -> Result<(), cluster::Error> {
+            Err(unexpected_call("push_to_user"))
+        }
+
+        async fn start(&self) -> Result<(), cluster::Error> {
+            Ok(())
+        }
+
+        async fn shutdown(&self) -> Result<(), cluster::Error> {
+            Ok(())
+        }
+    }
+
+    fn join(
+        _ctx: Context,
+        _session: Option<Session>,
+        req: &protos::Request,
+    ) -> Pin<Box<dyn Future<Output = protos::Response> + Send + 'static>> {
+        let data = req.msg.as_ref().unwrap().data.clone();
+        Box::pin(async move {
+            protos::Response {
+                data: [b"joined: ".to_vec(), data].concat(),
+                error: None,
+            }
+        })
+    }
+
+    fn new_remote() -> Remote {
+        let mut server = Handlers::new();
+        server.add(&StaticHandlerInfo {
+            handler_name: "room",
+            method_name: "join",
+            method: join,
+        });
+        Remote::new(
+            test_helpers::get_root_logger(),
+            Arc::new(Mutex::new(
+                Box::new(StaticDiscovery::new(vec![])) as Box<dyn cluster::Discovery>
+            )),
+            Arc::new(UnusedRpcClient),
+            RpcDispatch::Handlers {
+                client: Arc::new(Handlers::new()),
+                server: Arc::new(server),
+            },
+        )
+    }
+
+    async fn process(remote: &Remote, route: &str) -> Result<protos::Response, Box<dyn StdError>> {
+        process_with_context(remote, route, Context::empty()).await
+    }
+
+    async fn process_with_context(
+        remote: &Remote,
+        route: &str,
+        ctx: Context,
+    ) -> Result<protos::Response, Box<dyn StdError>> {
+        let req = utils::build_request(
+            ctx,
+            protos::RpcType::User,
+            message::Message {
+                kind: message::Kind::Request,
+                id: 1,
+                data: b"lobby".to_vec(),
+                route: route.to_owned(),
+                compressed: false,
+                err: false,
+            },
+            Arc::new(ServerInfo {
+                id: ServerId::from("caller"),
+                kind: ServerKind::from("connector"),
+                metadata: HashMap::new(),
+                frontend: false,
+                hostname: "".to_owned(),
+            }),
+        )?;
+        let (responder, response) = oneshot::channel();
+        remote
+            .process_rpc(
+                cluster::Rpc::new(utils::encode_proto(&req), responder),
+                Arc::new(state::Container::new()),
+            )
+            .await;
+        Ok(protos::Response::decode(response.await?.as_slice())?)
+    }
+
+    #[tokio::test]
+    async fn rpcs_are_dispatched_to_the_handler_of_their_route() -> Result<(), Box<dyn StdError>> {
+        let res = process(&new_remote(), "room.room.join").await?;
+        assert!(res.error.is_none());
+        assert_eq!(res.data, b"joined: lobby");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rpcs_to_unknown_routes_are_answered_with_not_found() -> Result<(), Box<dyn StdError>> {
+        let remote = new_remote();
+        for route in &["room.room.leave", "room.lobby.join"] {
+            let res = process(&remote, route).await?;
+            assert!(matches!(
+                res.error,
+                Some(protos::Error { ref code, .. }) if code == constants::CODE_NOT_FOUND
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_id_is_echoed_in_error_responses() -> Result<(), Box<dyn StdError>> {
+        let mut ctx = Context::empty();
+        ctx.set_request_id("123456");
+        let res = process_with_context(&new_remote(), "room.room.leave", ctx).await?;
+        let err = res.error.expect("should fail");
+        assert_eq!(
+            err.metadata
+                .get(constants::REQUEST_ID_KEY)
+                .map(String::as_str),
+            Some("123456")
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
     cluster::{self, Discovery, RpcClient, ServerId},
+    constants,
     context::Context,
     message, protos, utils,
 };
@@ -10,7 +11,6 @@ use tokio::sync::Mutex;
 
 const BIND_ROUTE: &str = "sys.bindsession";
 const SESSION_PUSH_ROUTE: &str = "sys.pushsession";
-const KICK_ROUTE: &str = "sys.kick";
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -172,11 +172,11 @@ impl Session {
             return Err(Error::SessionNotBound);
         }
 
-        let buf = utils::encode_proto(&protos::KickMsg {
+        let msg = message::Message::from(protos::KickMsg {
             user_id: self.uid.clone(),
         });
 
-        self.send_request_to_frontend(KICK_ROUTE, buf).await
+        self.send_request_to_frontend(&msg.route, msg.data).await
     }
 
     pub async fn update_in_front(&self) -> Result<(), Error> {
@@ -245,10 +245,17 @@ impl Session {
             }
         };
 
+        // The frontend uses the uid to find the session targeted by the request.
+        let mut ctx = Context::empty();
+        if self.is_bound() {
+            ctx.add(constants::SESSION_UID_KEY, &self.uid)
+                .expect("should not fail");
+        }
+
         let res = self
             .rpc_client
             .call(
-                Context::empty(), // TODO(lhahn): should this not be empty?
+                ctx,
                 protos::RpcType::User,
                 message::Message {
                     kind: message::Kind::Request,
@@ -6,19 +6,24 @@ use rand::Rng;
 use std::sync::Arc;
 
 pub fn user_kick_topic(user_id: &str, server_kind: &ServerKind) -> String {
-    format!("pitaya/{}/user/{}/kick", server_kind.0, user_id)
+    format!("pitaya/{}/user/{}/kick", server_kind, user_id)
 }
 
 pub fn user_messages_topic(user_id: &str, server_kind: &ServerKind) -> String {
-    format!("pitaya/{}/user/{}/push", server_kind.0, user_id)
+    format!("pitaya/{}/user/{}/push", server_kind, user_id)
 }
 
 pub fn topic_for_server(server: &ServerInfo) -> String {
-    format!("pitaya/servers/{}/{}", server.kind.0, server.id.0)
+    format!("pitaya/servers/{}/{}", server.kind, server.id)
+}
+
+// The topic shared by all servers of a kind, which is not used by Pitaya in Go.
+pub fn topic_for_server_kind(server_kind: &ServerKind) -> String {
+    format!("pitaya/servers/{}", server_kind)
 }
 
 pub fn server_kind_prefix(server_kind: &ServerKind) -> String {
-    format!("pitaya/servers/{}/", server_kind.0)
+    format!("pitaya/servers/{}/", server_kind)
 }
 
 pub fn random_server(servers: &[Arc<ServerInfo>]) -> Option<Arc<ServerInfo>> {
@@ -82,13 +87,20 @@ pub fn build_request(
         ));
     }
 
+    // Start a new trace if this RPC is not part of an existing one.
+    if ctx.trace_id().is_none() {
+        ctx.set_trace_id(context::new_trace_id());
+    }
+
     let req = protos::Request {
         r#type: rpc_type as i32,
         msg: Some(protos::Msg {
-            r#type: if msg.kind == message::Kind::Request {
-                protos::MsgType::MsgRequest as i32
-            } else {
-                protos::MsgType::MsgNotify as i32
+            r#type: match msg.kind {
+                message::Kind::Request => protos::MsgType::MsgRequest as i32,
+                message::Kind::Notify => protos::MsgType::MsgNotify as i32,
+                message::Kind::Push => protos::MsgType::MsgPush as i32,
+                // Kicks are requests to the frontend, which answers with a `KickAnswer`.
+                message::Kind::Kick => protos::MsgType::MsgRequest as i32,
             },
             data: msg.data,
             route: msg.route,
@@ -3,12 +3,41 @@ use std::time::Duration;
 pub const LOCAL_ETCD_URL: &str = "localhost:2379";
 pub const LOCAL_NATS_URL: &str = "http://localhost:4222";
 
+pub const ETCD_ENV_PREFIX: &str = "PITAYA_ETCD";
+pub const NATS_ENV_PREFIX: &str = "PITAYA_NATS";
+
 pub const DEFAULT_ETCD_PREFIX: &str = "pitaya";
 pub const DEFAULT_ETCD_LEASE_TTL: Duration = Duration::from_secs(60);
+pub const DEFAULT_ETCD_KEEP_ALIVE_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_ETCD_KEEP_ALIVE_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+pub const DEFAULT_ETCD_KEEP_ALIVE_MAX_RECONNECTIONS: u32 = 3;
+pub const DEFAULT_ETCD_KEEP_ALIVE_RENEWAL_FRACTION: f64 = 2.0 / 3.0;
+pub const DEFAULT_ETCD_KEEP_ALIVE_JITTER: f64 = 0.1;
+pub const MIN_ETCD_KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(100);
+pub const DEFAULT_ETCD_SERVER_NOT_FOUND_TTL: Duration = Duration::from_secs(0);
+pub const DEFAULT_ETCD_AUTH_USER: &str = "";
+pub const DEFAULT_ETCD_AUTH_PASS: &str = "";
+pub const DEFAULT_ETCD_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_ETCD_METRICS_NAMESPACE: &str = "pitaya";
+pub const DEFAULT_ETCD_METRICS_SUBSYSTEM: &str = "discovery";
+pub const DEFAULT_ETCD_WATCH_MAX_RECONNECTIONS: u32 = 3;
+pub const DEFAULT_ETCD_WATCH_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+pub const DEFAULT_ETCD_CACHE_PAGE_SIZE: u32 = 0;
 
 pub const DEFAULT_NATS_CONN_TIMEOUT: Duration = Duration::from_secs(5);
 pub const DEFAULT_NATS_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 pub const DEFAULT_NATS_MAX_RECONN_ATTEMPTS: u32 = 5;
 pub const DEFAULT_NATS_MAX_RPCS_QUEUED: u32 = 100;
+pub const DEFAULT_NATS_QUEUE_FULL_WAIT: Duration = Duration::from_secs(0);
+pub const DEFAULT_NATS_MAX_RPCS_ANSWERING: u32 = 1000;
 pub const DEFAULT_NATS_AUTH_USER: &str = "";
 pub const DEFAULT_NATS_AUTH_PASS: &str = "";
+pub const DEFAULT_NATS_AUTH_TOKEN: &str = "";
+pub const DEFAULT_NATS_OVERLOAD_ERROR_CODE: &str = "PIT-503";
+pub const DEFAULT_NATS_OVERLOAD_ERROR_MESSAGE: &str = "server is overloaded";
+pub const DEFAULT_NATS_SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_NATS_METRICS_NAMESPACE: &str = "pitaya";
+pub const DEFAULT_NATS_METRICS_SUBSYSTEM: &str = "rpc";
+pub const DEFAULT_NATS_COMPRESSION_MIN_SIZE: usize = 1024;
+pub const DEFAULT_NATS_MAX_REQUEST_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_NATS_FLUSH_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
@@ -1,13 +1,26 @@
-use crate::{settings, tasks};
+use crate::{
+    etcd_api::{EtcdApi, KeyValue},
+    settings, tasks, SessionService,
+};
 use async_trait::async_trait;
-use etcd_client::GetOptions;
-use pitaya_core::cluster::{Discovery, Error, Notification, ServerId, ServerInfo, ServerKind};
-use slog::{debug, error, info, o, warn};
+use futures::future::{self, Future};
+use pitaya_core::{
+    cluster::{Discovery, Error, Notification, ServerId, ServerInfo, ServerKind, TaskFailure},
+    metrics,
+};
+use slog::{debug, error, info, o, trace, warn};
 use std::collections::HashMap;
 use std::iter::FromIterator;
-use std::sync::{Arc, RwLock};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 
+const DISCOVERY_CACHE_HITS_METRIC: &str = "discovery_cache_hits";
+const DISCOVERY_CACHE_MISSES_METRIC: &str = "discovery_cache_misses";
+
 pub(crate) struct ServersCache {
     servers_by_id: HashMap<ServerId, Arc<ServerInfo>>,
     servers_by_kind: HashMap<ServerKind, HashMap<ServerId, Arc<ServerInfo>>>,
@@ -60,7 +73,40 @@ impl ServersCache {
             debug!(self.logger, "server removed from cache"; "server_id" => &server_id.0);
             self.notify(Notification::ServerRemoved(server));
         }
-        self.servers_by_kind.remove(server_kind);
+        // Kinds without servers are dropped, so that the cache does not keep growing with
+        // every kind that was ever queried.
+        if let Some(servers) = self.servers_by_kind.get_mut(server_kind) {
+            servers.remove(server_id);
+            if servers.is_empty() {
+                self.servers_by_kind.remove(server_kind);
+            }
+        }
+    }
+
+    // Replaces the cached servers of the given kind, or of all kinds, with the given ones.
+    // Listeners are only notified of the servers that were added or removed.
+    pub(crate) fn replace(
+        &mut self,
+        server_kind: Option<&ServerKind>,
+        servers: Vec<Arc<ServerInfo>>,
+    ) {
+        let current: HashMap<ServerId, Arc<ServerInfo>> =
+            servers.iter().map(|s| (s.id.clone(), s.clone())).collect();
+        let removed: Vec<Arc<ServerInfo>> = self
+            .servers_by_id
+            .values()
+            .filter(|s| server_kind.map_or(true, |kind| &s.kind == kind))
+            .filter(|s| !current.contains_key(&s.id))
+            .cloned()
+            .collect();
+        for server in removed {
+            self.remove(&server.kind, &server.id);
+        }
+        for server in servers {
+            if self.by_id(&server.id).as_ref() != Some(&server) {
+                self.insert(server);
+            }
+        }
     }
 
     fn subscribe(&self) -> broadcast::Receiver<Notification> {
@@ -78,18 +124,62 @@ impl ServersCache {
     }
 }
 
+// A task spawned by the service discovery, that is aborted if it does not finish in time
+// when the discovery is shut down.
+struct Task {
+    handle: tokio::task::JoinHandle<Result<(), future::Aborted>>,
+    abort_handle: future::AbortHandle,
+}
+
+impl Task {
+    fn spawn<F>(task: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (task, abort_handle) = future::abortable(task);
+        Self {
+            handle: tokio::spawn(task),
+            abort_handle,
+        }
+    }
+
+    // Waits for the task to finish for at most `timeout`, aborting it otherwise.
+    async fn join(self, timeout: Duration) -> Result<(), TaskFailure> {
+        let Task {
+            handle,
+            abort_handle,
+        } = self;
+        match tokio::time::timeout(timeout, handle).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) if e.is_panic() => Err(TaskFailure::Panicked),
+            Ok(Err(e)) => Err(TaskFailure::StopFailed(e.to_string())),
+            Err(_) => {
+                abort_handle.abort();
+                Err(TaskFailure::TimedOut)
+            }
+        }
+    }
+}
+
 // This service discovery is a lazy implementation.
-pub struct EtcdLazy {
+//
+// It talks to etcd through `EtcdApi`, which is implemented by the etcd client and, in
+// tests, by an in-memory etcd.
+pub struct EtcdLazy<C: EtcdApi = etcd_client::Client> {
     settings: Arc<settings::Etcd>,
-    client: etcd_client::Client,
+    client: C,
     this_server: Arc<ServerInfo>,
     lease_id: Option<i64>,
-    keep_alive_task: Option<(
-        tokio::task::JoinHandle<()>,
-        tokio::sync::oneshot::Sender<()>,
-    )>,
-    watch_task: Option<(tokio::task::JoinHandle<()>, etcd_client::Watcher)>,
+    keep_alive_task: Option<(Task, tokio::sync::oneshot::Sender<()>)>,
+    // Set by the keep alive task when it gives up on a lost lease and finishes by itself.
+    lease_lost: Arc<AtomicBool>,
+    // One watch for all servers, or one for each of the watched kinds.
+    watch_tasks: Vec<(Task, tokio::sync::oneshot::Sender<()>)>,
     servers_cache: Arc<RwLock<ServersCache>>,
+    // Servers that were recently not found in etcd, with the time of the lookup.
+    not_found_servers: HashMap<ServerId, Instant>,
+    reporter: metrics::ThreadSafeReporter,
+    metrics_registered: bool,
     logger: slog::Logger,
 }
 
@@ -99,13 +189,36 @@ impl EtcdLazy {
         server: Arc<ServerInfo>,
         settings: Arc<settings::Etcd>,
     ) -> Result<Self, Error> {
-        info!(logger, "connecting to etcd"; "url" => &settings.url);
-        let client = etcd_client::Client::connect([&settings.url], None)
-            .await
-            .map_err(|e| Error::Connection(e.to_string()))?;
+        let settings = validate_settings(settings)?;
+        info!(
+            logger, "connecting to etcd";
+            "url" => &settings.url, "authenticated" => etcd_credentials(&settings).is_some()
+        );
+        let client =
+            etcd_client::Client::connect([&settings.url], Some(etcd_connect_options(&settings)))
+                .await
+                .map_err(|e| Error::Connection(e.to_string()))?;
+        Ok(Self::with_client(logger, server, settings, client))
+    }
+}
+
+impl<C: EtcdApi> EtcdLazy<C> {
+    // Creates the discovery with a client that is already connected. The settings should
+    // have been validated with `validate_settings`. Everything logged by the discovery
+    // includes the id of this server and the etcd prefix.
+    fn with_client(
+        logger: slog::Logger,
+        server: Arc<ServerInfo>,
+        settings: Arc<settings::Etcd>,
+        client: C,
+    ) -> Self {
+        let logger = logger.new(o!(
+            "server_id" => server.id.0.clone(),
+            "etcd_prefix" => settings.prefix.clone(),
+        ));
         // TODO(lhahn): remove hardcoded max channel size.
         let max_chan_size = 80;
-        Ok(Self {
+        Self {
             settings,
             client,
             this_server: server,
@@ -115,21 +228,58 @@ impl EtcdLazy {
             ))),
             lease_id: None,
             keep_alive_task: None,
-            watch_task: None,
+            lease_lost: Arc::new(AtomicBool::new(false)),
+            watch_tasks: Vec::new(),
+            not_found_servers: HashMap::new(),
+            reporter: Arc::new(tokio::sync::RwLock::new(Box::new(
+                metrics::DummyReporter {},
+            ))),
+            metrics_registered: false,
             logger,
-        })
+        }
     }
 
-    fn server_kind_prefix(&self, server_kind: Option<&ServerKind>) -> String {
-        if let Some(kind) = server_kind {
-            format!("{}/servers/{}/", self.settings.prefix, kind.0)
-        } else {
-            format!("{}/servers/", self.settings.prefix)
+    // Reports the cache hits and misses of server lookups with the given reporter.
+    pub fn with_metrics_reporter(mut self, reporter: metrics::ThreadSafeReporter) -> Self {
+        self.reporter = reporter;
+        self.metrics_registered = false;
+        self
+    }
+
+    async fn register_metrics(&mut self) {
+        if self.metrics_registered {
+            return;
+        }
+        let mut reporter = self.reporter.write().await;
+
+        for (name, help) in &[
+            (
+                DISCOVERY_CACHE_HITS_METRIC,
+                "number of server lookups answered by the cache",
+            ),
+            (
+                DISCOVERY_CACHE_MISSES_METRIC,
+                "number of server lookups that had to query etcd",
+            ),
+        ] {
+            reporter
+                .register_counter(metrics::Opts {
+                    kind: metrics::MetricKind::Counter,
+                    namespace: self.settings.metrics_namespace.clone(),
+                    subsystem: self.settings.metrics_subsystem.clone(),
+                    name: String::from(*name),
+                    help: String::from(*help),
+                    variable_labels: vec![],
+                    buckets: None,
+                })
+                .or_else(metrics::allow_already_registered)
+                .unwrap_or_else(|e| warn!(self.logger, "failed to register metric"; "error" => %e));
         }
+        self.metrics_registered = true;
     }
 
-    async fn revoke_lease(&mut self) -> Result<(), etcd_client::Error> {
-        if let Some(lease_id) = self.lease_id {
+    async fn revoke_lease(&mut self) -> Result<(), Error> {
+        if let Some(lease_id) = self.lease_id.take() {
             self.client.lease_revoke(lease_id).await?;
             info!(self.logger, "lease revoked"; "lease_id" => lease_id);
         } else {
@@ -138,11 +288,14 @@ impl EtcdLazy {
         Ok(())
     }
 
-    async fn cache_servers(&mut self, server_kind: Option<&ServerKind>) -> Result<(), Error> {
+    // Fills the cache with the servers stored in etcd. Servers that cannot be parsed are
+    // skipped, so that they do not prevent the others from being cached. Returns how many
+    // servers were skipped.
+    async fn cache_servers(&mut self, server_kind: Option<&ServerKind>) -> Result<usize, Error> {
         if let Some(kind) = server_kind {
             debug!(
                 self.logger,
-                "server id not found in cache, filling cache for kind {}", kind.0,
+                "server id not found in cache, filling cache for kind {}", kind,
             );
         } else {
             warn!(
@@ -150,110 +303,227 @@ impl EtcdLazy {
                 "server id not found in cache, filling all ETCD servers",
             );
         }
-        let resp = {
-            let key_prefix = self.server_kind_prefix(server_kind);
-            self.client
-                .get(key_prefix, Some(GetOptions::new().with_prefix()))
-                .await
-                .map_err(|e| Error::ClusterCommunication(e.to_string()))?
-        };
-        // TODO(lhahn): add a metric here to know how much keys a server is fetching in one
-        // single request. This might be useful in the future for debugging issues with
-        // ETCD load.
-        debug!(self.logger, "etcd returned {} keys", resp.kvs().len());
-        for kv in resp.kvs() {
+        let key_prefix = servers_key(&self.settings.prefix, server_kind);
+        if self.settings.cache_page_size == 0 {
+            let resp = self.client.get(key_prefix).await?.kvs;
+            // TODO(lhahn): add a metric here to know how much keys a server is fetching in one
+            // single request. This might be useful in the future for debugging issues with
+            // ETCD load.
+            debug!(self.logger, "etcd returned {} keys", resp.len());
+            return Ok(self.cache_kvs(&resp));
+        }
+
+        let limit = i64::from(self.settings.cache_page_size);
+        let mut from_key = key_prefix.as_bytes().to_vec();
+        let mut revision = None;
+        let mut skipped = 0;
+        loop {
+            let resp = self
+                .client
+                .get_page(key_prefix.clone(), from_key, limit, revision)
+                .await?;
+            debug!(self.logger, "etcd returned {} keys", resp.kvs.len(); "more" => resp.more);
+            skipped += self.cache_kvs(&resp.kvs);
+            // The next page starts right after the last key of this one.
+            match resp.kvs.last() {
+                Some(last) if resp.more => {
+                    from_key = last.key.clone();
+                    from_key.push(0);
+                    revision = revision.or(Some(resp.revision));
+                }
+                _ => return Ok(skipped),
+            }
+        }
+    }
+
+    // Inserts the servers stored in the given keys into the cache, returning how many of
+    // them could not be parsed.
+    fn cache_kvs(&self, kvs: &[KeyValue]) -> usize {
+        let mut skipped = 0;
+        for kv in kvs {
             match kv.value_str() {
                 Ok(server_str) => {
-                    let new_server: Arc<ServerInfo> =
-                        Arc::new(match serde_json::from_str(server_str) {
+                    let new_server: Arc<ServerInfo> = Arc::new(
+                        match serde_json::from_str(server_str) {
                             Ok(s) => s,
-                            Err(_e) => {
-                                warn!(self.logger, "corrupt server"; "server_str" => server_str);
+                            Err(e) => {
+                                warn!(
+                                    self.logger, "corrupt server";
+                                    "key" => kv.key_str().unwrap_or("<invalid key>"), "error" => %e
+                                );
+                                trace!(self.logger, "corrupt server value"; "server_str" => server_str);
+                                skipped += 1;
                                 continue;
                             }
-                        });
+                        },
+                    );
                     self.servers_cache.write().unwrap().insert(new_server);
                 }
                 Err(e) => {
                     warn!(self.logger, "could not get value from etcd key"; "err" => %e);
+                    skipped += 1;
                 }
             }
         }
-        Ok(())
+        if skipped > 0 {
+            warn!(self.logger, "skipped corrupt servers"; "count" => skipped);
+        }
+        skipped
     }
 
     async fn grant_lease(&mut self, app_die_sender: broadcast::Sender<()>) -> Result<(), Error> {
         assert!(self.lease_id.is_none());
         assert!(self.keep_alive_task.is_none());
 
-        let lease_response = self
+        let lease_id = self
             .client
-            .lease_grant(self.settings.lease_ttl.as_secs() as i64, None)
-            .await
-            .map_err(|e| Error::ClusterCommunication(e.to_string()))?;
-        self.lease_id = Some(lease_response.id());
+            .lease_grant(self.settings.lease_ttl.as_secs() as i64)
+            .await?;
+        self.lease_id = Some(lease_id);
 
-        let (keeper, stream) = self
-            .client
-            .lease_keep_alive(lease_response.id())
-            .await
-            .map_err(|e| Error::ClusterCommunication(e.to_string()))?;
+        let renewer = self.client.lease_keep_alive(lease_id).await?;
         let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
 
+        let keep_alive = tasks::lease_keep_alive(
+            self.logger
+                .new(o!("task" => "keep_alive", "lease_id" => lease_id)),
+            self.settings.clone(),
+            renewer,
+            stop_receiver,
+            app_die_sender,
+        );
+        let lease_lost = self.lease_lost.clone();
         self.keep_alive_task = Some((
-            tokio::spawn(tasks::lease_keep_alive(
-                self.logger.new(o!("task" => "keep_alive")),
-                self.settings.lease_ttl,
-                keeper,
-                stream,
-                stop_receiver,
-                app_die_sender,
-            )),
+            Task::spawn(async move {
+                if keep_alive.await {
+                    lease_lost.store(true, Ordering::SeqCst);
+                }
+            }),
             stop_sender,
         ));
 
         Ok(())
     }
 
-    fn get_etcd_server_key(&self) -> String {
-        format!(
-            "{}/servers/{}/{}",
-            self.settings.prefix, self.this_server.kind.0, self.this_server.id.0
+    // Returns the key under which this server is registered in etcd, which is useful for
+    // inspecting the registration when debugging.
+    pub fn registration_key(&self) -> String {
+        server_key(
+            &self.settings.prefix,
+            &self.this_server.kind,
+            &self.this_server.id,
         )
     }
 
+    // Deletes the key left by a previous instance of this server, if there is one.
+    async fn remove_stale_registration(&mut self) -> Result<(), Error> {
+        let key = self.registration_key();
+        if self.client.delete(key.clone()).await? {
+            warn!(self.logger, "removed stale registration of this server"; "key" => key);
+        }
+        Ok(())
+    }
+
     async fn add_server_to_etcd(&mut self) -> Result<(), Error> {
         assert!(self.lease_id.is_some());
-        let key = self.get_etcd_server_key();
+        let key = self.registration_key();
         let server_json = serde_json::to_vec(&*self.this_server).unwrap();
-        let lease_id = self.lease_id.unwrap();
-        let options = etcd_client::PutOptions::new().with_lease(lease_id);
-        self.client
-            .put(key, server_json, Some(options))
-            .await
-            .map_err(|e| Error::ClusterCommunication(e.to_string()))?;
+        self.client.put(key, server_json, self.lease_id).await?;
         info!(self.logger, "added server to etcd");
         Ok(())
     }
 
-    async fn start_watch(&mut self, app_die_sender: broadcast::Sender<()>) -> Result<(), Error> {
-        let watch_prefix = format!("{}/servers/", self.settings.prefix);
-        let options = etcd_client::WatchOptions::new().with_prefix();
-        let (watcher, watch_stream) = self
-            .client
-            .watch(watch_prefix, Some(options))
-            .await
-            .map_err(|e| Error::ClusterCommunication(e.to_string()))?;
-
-        info!(self.logger, "starting etcd watch");
-        let handle = tokio::spawn(tasks::watch_task(
-            self.logger.new(o!("task" => "watch")),
-            self.servers_cache.clone(),
+    // Returns the id of the lease this server is registered under, if the discovery was
+    // started.
+    pub fn current_lease_id(&self) -> Option<i64> {
+        self.lease_id
+    }
+
+    // Returns a service for binding sessions to this server, which shares the etcd
+    // connection and the lease of the discovery. Since the lease is granted when the
+    // discovery starts, sessions bound by a service created before that fail.
+    pub fn session_service(&self) -> SessionService<C> {
+        SessionService::new(
+            self.logger.clone(),
+            self.client.clone(),
             self.settings.prefix.clone(),
-            watch_stream,
-            app_die_sender,
-        ));
-        self.watch_task = Some((handle, watcher));
+            self.this_server.clone(),
+            self.lease_id,
+        )
+    }
+
+    // Replaces the metadata of this server. If the discovery was already started, the
+    // server is written again to etcd under the same lease, so the keep alive task is
+    // not affected and other servers see the new metadata through their watches.
+    pub async fn update_metadata(
+        &mut self,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), Error> {
+        self.this_server = Arc::new(ServerInfo {
+            id: self.this_server.id.clone(),
+            kind: self.this_server.kind.clone(),
+            metadata,
+            hostname: self.this_server.hostname.clone(),
+            frontend: self.this_server.frontend,
+        });
+
+        if self.lease_id.is_some() {
+            self.add_server_to_etcd().await?;
+            debug!(self.logger, "updated server metadata in etcd");
+        }
+        Ok(())
+    }
+
+    // Fails if known kinds are configured and the given kind is not one of them. The kind
+    // of this server is always known.
+    fn check_known_kind(&self, server_kind: &ServerKind) -> Result<(), Error> {
+        let known_kinds = &self.settings.known_server_kinds;
+        if known_kinds.is_empty()
+            || *server_kind == self.this_server.kind
+            || known_kinds.iter().any(|kind| *kind == server_kind.0)
+        {
+            return Ok(());
+        }
+        warn!(self.logger, "unknown server kind"; "server_kind" => &server_kind.0);
+        Err(Error::UnknownServerKind(server_kind.clone()))
+    }
+
+    // Returns the kinds watched for changes, or `None` for watching all servers if no
+    // kinds are configured.
+    fn watched_kinds(&self) -> Vec<Option<ServerKind>> {
+        if self.settings.watched_server_kinds.is_empty() {
+            return vec![None];
+        }
+        self.settings
+            .watched_server_kinds
+            .iter()
+            .map(|kind| Some(ServerKind::from(kind.as_str())))
+            .collect()
+    }
+
+    async fn start_watch(&mut self, app_die_sender: broadcast::Sender<()>) -> Result<(), Error> {
+        for server_kind in self.watched_kinds() {
+            let watch_prefix = servers_key(&self.settings.prefix, server_kind.as_ref());
+            let watch = tasks::ServersWatch::open(
+                self.client.clone(),
+                self.settings.prefix.clone(),
+                server_kind,
+            )
+            .await?;
+            let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+
+            info!(self.logger, "starting etcd watch"; "prefix" => &watch_prefix);
+            let task = Task::spawn(tasks::watch_task(
+                self.logger
+                    .new(o!("task" => "watch", "prefix" => watch_prefix)),
+                self.settings.clone(),
+                watch,
+                self.servers_cache.clone(),
+                stop_receiver,
+                app_die_sender.clone(),
+            ));
+            self.watch_tasks.push((task, stop_sender));
+        }
 
         Ok(())
     }
@@ -271,6 +541,24 @@ impl EtcdLazy {
             .unwrap_or_default()
     }
 
+    fn recently_not_found(&self, server_id: &ServerId) -> bool {
+        self.not_found_servers
+            .get(server_id)
+            .map(|at| at.elapsed() < self.settings.server_not_found_ttl)
+            .unwrap_or(false)
+    }
+
+    fn remember_not_found(&mut self, server_id: &ServerId) {
+        let ttl = self.settings.server_not_found_ttl;
+        if ttl.as_nanos() == 0 {
+            return;
+        }
+        // Drop expired entries, so that the map does not grow indefinitely.
+        self.not_found_servers.retain(|_, at| at.elapsed() < ttl);
+        self.not_found_servers
+            .insert(server_id.clone(), Instant::now());
+    }
+
     // This function only returns the server without trying to cache servers.
     fn only_server_by_id(&mut self, server_id: &ServerId) -> Option<Arc<ServerInfo>> {
         self.servers_cache.read().unwrap().by_id(server_id)
@@ -278,9 +566,13 @@ impl EtcdLazy {
 }
 
 #[async_trait]
-impl Discovery for EtcdLazy {
+impl<C: EtcdApi> Discovery for EtcdLazy<C> {
     async fn start(&mut self, app_die_sender: broadcast::Sender<()>) -> Result<(), Error> {
+        self.register_metrics().await;
         self.grant_lease(app_die_sender.clone()).await?;
+        if self.settings.remove_stale_registration {
+            self.remove_stale_registration().await?;
+        }
         self.add_server_to_etcd().await?;
         self.start_watch(app_die_sender).await?;
         Ok(())
@@ -288,28 +580,52 @@ impl Discovery for EtcdLazy {
 
     async fn shutdown(&mut self) -> Result<(), Error> {
         info!(self.logger, "stopping etcd service discovery");
-        if let Some((handle, sender)) = self.keep_alive_task.take() {
+        // Every task is stopped even if a previous one fails, and the first failure is
+        // returned.
+        let mut result = Ok(());
+
+        if let Some((task, sender)) = self.keep_alive_task.take() {
             info!(self.logger, "cancelling keep alive task");
-            if sender.send(()).is_err() {
-                error!(self.logger, "failed to send stop message");
-            }
-            if let Err(e) = handle.await {
-                error!(self.logger, "failed to wait for keep alive task"; "error" => %e);
+            // The receiver is only dropped when the task finishes.
+            let stopped = sender.send(()).map_err(|_| TaskFailure::AlreadyFinished);
+            let joined = task.join(self.settings.shutdown_timeout).await;
+            // A task that already finished because the lease was lost has nothing left to
+            // stop, and the app was already told to die.
+            let stopped = match stopped {
+                Err(TaskFailure::AlreadyFinished) if self.lease_lost.load(Ordering::SeqCst) => {
+                    info!(
+                        self.logger,
+                        "keep alive task already finished after losing the lease"
+                    );
+                    Ok(())
+                }
+                stopped => stopped,
+            };
+            if let Err(failure) = joined.and(stopped) {
+                error!(self.logger, "failed to stop keep alive task"; "error" => %failure);
+                result = result.and(Err(Error::TaskNotStopped {
+                    task: "keep_alive",
+                    failure,
+                }));
             }
         }
-        if let Some((handle, mut watcher)) = self.watch_task.take() {
+        for (task, sender) in std::mem::take(&mut self.watch_tasks) {
             info!(self.logger, "cancelling watcher");
-            if let Err(e) = watcher.cancel().await {
-                error!(self.logger, "failed to cancel watcher"; "error" => %e);
-            }
-            if let Err(e) = handle.await {
-                error!(self.logger, "failed to wait for watcher"; "error" => %e);
+            // The task cancels the watch before finishing.
+            let stopped = sender.send(()).map_err(|_| TaskFailure::AlreadyFinished);
+            let joined = task.join(self.settings.shutdown_timeout).await;
+            if let Err(failure) = joined.and(stopped) {
+                error!(self.logger, "failed to stop watch task"; "error" => %failure);
+                result = result.and(Err(Error::TaskNotStopped {
+                    task: "watch",
+                    failure,
+                }));
             }
         }
         if let Err(e) = self.revoke_lease().await {
             error!(self.logger, "failed to revoke lease"; "error" => %e);
         }
-        Ok(())
+        result
     }
 
     async fn server_by_id(
@@ -318,15 +634,98 @@ impl Discovery for EtcdLazy {
         server_kind: Option<&ServerKind>,
     ) -> Result<Option<Arc<ServerInfo>>, Error> {
         debug!(self.logger, "finding server by id");
+        if let Some(server_kind) = server_kind {
+            self.check_known_kind(server_kind)?;
+        }
+        self.register_metrics().await;
         if let Some(server) = self.only_server_by_id(server_id) {
+            metrics::inc_counter(
+                self.logger.clone(),
+                self.reporter.clone(),
+                DISCOVERY_CACHE_HITS_METRIC,
+                &[],
+            )
+            .await;
             return Ok(Some(server));
         }
 
+        if self.recently_not_found(server_id) {
+            debug!(self.logger, "server was recently not found"; "server_id" => &server_id.0);
+            return Ok(None);
+        }
+
+        metrics::inc_counter(
+            self.logger.clone(),
+            self.reporter.clone(),
+            DISCOVERY_CACHE_MISSES_METRIC,
+            &[],
+        )
+        .await;
+
+        // Since the discovery is borrowed mutably, concurrent lookups are serialized and
+        // the ones waiting for this fetch will find the server in the cache above, instead
+        // of fetching it again.
+        //
         // If a server id was provided, we can cache it from ETCD, otherwise we'll
         // do an expensive search.
         self.cache_servers(server_kind).await?;
 
-        Ok(self.only_server_by_id(server_id))
+        let server = self.only_server_by_id(server_id);
+        if server.is_none() {
+            self.remember_not_found(server_id);
+        }
+        Ok(server)
+    }
+
+    async fn servers_by_ids(
+        &mut self,
+        ids: &[(ServerId, ServerKind)],
+    ) -> Result<HashMap<ServerId, Arc<ServerInfo>>, Error> {
+        debug!(self.logger, "finding servers by ids"; "count" => ids.len());
+        for (_, kind) in ids {
+            self.check_known_kind(kind)?;
+        }
+        self.register_metrics().await;
+        let mut servers = HashMap::new();
+        let mut missing = Vec::new();
+        for (id, kind) in ids {
+            match self.only_server_by_id(id) {
+                Some(server) => {
+                    servers.insert(id.clone(), server);
+                }
+                None if self.recently_not_found(id) => {}
+                None => missing.push((id, kind)),
+            }
+        }
+
+        let lookups = [
+            (DISCOVERY_CACHE_HITS_METRIC, servers.len()),
+            (DISCOVERY_CACHE_MISSES_METRIC, missing.len()),
+        ];
+        for (metric, count) in lookups.iter() {
+            for _ in 0..*count {
+                metrics::inc_counter(self.logger.clone(), self.reporter.clone(), metric, &[]).await;
+            }
+        }
+
+        // The cache is filled once for each kind with missing servers.
+        let mut filled_kinds = Vec::new();
+        for (_, kind) in &missing {
+            if !filled_kinds.contains(kind) {
+                self.cache_servers(Some(kind)).await?;
+                filled_kinds.push(kind);
+            }
+        }
+
+        for (id, _) in missing {
+            match self.only_server_by_id(id) {
+                Some(server) => {
+                    servers.insert(id.clone(), server);
+                }
+                None => self.remember_not_found(id),
+            }
+        }
+        Ok(servers)
     }
 
     async fn servers_by_kind(
@@ -334,6 +733,7 @@ impl Discovery for EtcdLazy {
         server_kind: &ServerKind,
     ) -> Result<Vec<Arc<ServerInfo>>, Error> {
         debug!(self.logger, "finding servers by kind");
+        self.check_known_kind(server_kind)?;
         let servers = self.only_servers_by_kind(server_kind);
         if servers.is_empty() {
             // No servers were found, we'll try to fetch servers information from etcd.
@@ -347,12 +747,88 @@ impl Discovery for EtcdLazy {
     }
 }
 
+// Returns the key under which the servers of the given kind are stored in etcd, or all
+// servers if no kind is given. It always ends with a slash.
+pub(crate) fn servers_key(prefix: &str, server_kind: Option<&ServerKind>) -> String {
+    match server_kind {
+        Some(kind) => format!("{}/servers/{}/", prefix, kind),
+        None => format!("{}/servers/", prefix),
+    }
+}
+
+// Returns the key under which a server is stored in etcd.
+pub(crate) fn server_key(prefix: &str, server_kind: &ServerKind, server_id: &ServerId) -> String {
+    format!("{}{}", servers_key(prefix, Some(server_kind)), server_id)
+}
+
+// Checks the etcd settings, returning them with a normalized prefix.
+fn validate_settings(settings: Arc<settings::Etcd>) -> Result<Arc<settings::Etcd>, Error> {
+    if settings.lease_ttl.as_secs() == 0 {
+        return Err(Error::InvalidSettings(format!(
+            "etcd lease ttl should be at least one second, got {:?}",
+            settings.lease_ttl
+        )));
+    }
+    let settings = normalize_prefix(settings)?;
+    let fraction = settings.keep_alive_renewal_fraction;
+    if fraction.is_nan() || fraction <= 0.0 || fraction > 1.0 {
+        return Err(Error::InvalidSettings(format!(
+            "etcd keep alive renewal fraction should be in (0, 1], got {}",
+            fraction
+        )));
+    }
+    let jitter = settings.keep_alive_jitter;
+    if jitter.is_nan() || jitter < 0.0 || jitter >= 1.0 {
+        return Err(Error::InvalidSettings(format!(
+            "etcd keep alive jitter should be in [0, 1), got {}",
+            jitter
+        )));
+    }
+    Ok(settings)
+}
+
+// Removes the trailing slashes of the etcd prefix, so that keys never have empty
+// components. An empty prefix is rejected.
+fn normalize_prefix(settings: Arc<settings::Etcd>) -> Result<Arc<settings::Etcd>, Error> {
+    let prefix = settings.prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return Err(Error::InvalidSettings(format!(
+            "etcd prefix should not be empty, got {:?}",
+            settings.prefix
+        )));
+    }
+    if prefix.len() == settings.prefix.len() {
+        return Ok(settings);
+    }
+    Ok(Arc::new(settings::Etcd {
+        prefix: prefix.to_owned(),
+        ..(*settings).clone()
+    }))
+}
+
+// Returns the user and password used for authenticating with etcd, if configured.
+fn etcd_credentials(settings: &settings::Etcd) -> Option<(&str, &str)> {
+    if settings.auth_user.is_empty() {
+        None
+    } else {
+        Some((&settings.auth_user, &settings.auth_pass))
+    }
+}
+
+fn etcd_connect_options(settings: &settings::Etcd) -> etcd_client::ConnectOptions {
+    let options = etcd_client::ConnectOptions::new();
+    match etcd_credentials(settings) {
+        Some((user, pass)) => options.with_user(user, pass),
+        None => options,
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::constants;
+    use crate::{constants, etcd_api::tests::MemoryEtcd, rpc_server::tests::RecordingReporter};
     use std::error::Error as StdError;
-    use std::time::Duration;
+    use tokio::stream::StreamExt;
 
     const INVALID_ETCD_URL: &str = "localhost:1234";
 
@@ -366,22 +842,213 @@ mod tests {
         })
     }
 
+    // Stores a server directly in etcd, like another pitaya server would. Tests use kinds
+    // of their own, so that they do not see each other's servers when sharing an etcd.
+    async fn put_server(
+        etcd: &test_helpers::EtcdServer,
+        kind: &str,
+        id: &str,
+    ) -> Result<Arc<ServerInfo>, Box<dyn StdError>> {
+        let server = Arc::new(ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from(id),
+            kind: ServerKind::from(kind),
+            metadata: HashMap::new(),
+        });
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        client
+            .put(
+                server_key("pitaya", &server.kind, &server.id),
+                serde_json::to_vec(&*server)?,
+                None,
+            )
+            .await?;
+        Ok(server)
+    }
+
+    async fn delete_servers(
+        etcd: &test_helpers::EtcdServer,
+        kind: &str,
+    ) -> Result<(), Box<dyn StdError>> {
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        client
+            .delete(
+                servers_key("pitaya", Some(&ServerKind::from(kind))),
+                Some(etcd_client::DeleteOptions::new().with_prefix()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Creates a discovery that uses the in-memory etcd, and does not need an etcd server.
+    pub(crate) fn new_memory_sd(
+        etcd: &MemoryEtcd,
+        settings: settings::Etcd,
+    ) -> EtcdLazy<MemoryEtcd> {
+        let settings = validate_settings(Arc::new(settings)).unwrap();
+        EtcdLazy::with_client(
+            test_helpers::get_root_logger(),
+            new_server(),
+            settings,
+            etcd.clone(),
+        )
+    }
+
+    #[test]
+    fn cache_supports_concurrent_access() {
+        let cache = Arc::new(RwLock::new(ServersCache::new(
+            test_helpers::get_root_logger(),
+            80,
+        )));
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for j in 0..100 {
+                        let server = Arc::new(ServerInfo {
+                            frontend: false,
+                            hostname: "".to_owned(),
+                            id: ServerId::from(format!("{}-{}", i, j)),
+                            kind: ServerKind::from("room"),
+                            metadata: HashMap::new(),
+                        });
+                        cache.write().unwrap().insert(server.clone());
+                        cache.write().unwrap().remove(&server.kind, &server.id);
+                    }
+                })
+            })
+            .collect();
+
+        let readers: Vec<_> = (0..4)
+            .map(|i| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for j in 0..100 {
+                        let _ = cache
+                            .read()
+                            .unwrap()
+                            .by_id(&ServerId::from(format!("{}-{}", i, j)));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in writers.into_iter().chain(readers.into_iter()) {
+            handle.join().expect("thread should not panic");
+        }
+
+        assert!(cache.read().unwrap().servers_by_id.is_empty());
+        assert!(cache.read().unwrap().servers_by_kind.is_empty());
+    }
+
+    #[test]
+    fn cache_drops_removed_servers_and_empty_kinds() {
+        let mut cache = ServersCache::new(test_helpers::get_root_logger(), 80);
+        let servers: Vec<_> = ["first-id", "second-id"]
+            .iter()
+            .map(|id| {
+                Arc::new(ServerInfo {
+                    frontend: false,
+                    hostname: "".to_owned(),
+                    id: ServerId::from(*id),
+                    kind: ServerKind::from("room"),
+                    metadata: HashMap::new(),
+                })
+            })
+            .collect();
+        for server in &servers {
+            cache.insert(server.clone());
+        }
+
+        // Removing one server keeps the others of the same kind.
+        cache.remove(&servers[0].kind, &servers[0].id);
+        assert_eq!(cache.servers_by_id.len(), 1);
+        assert_eq!(
+            cache
+                .servers_by_kind
+                .get(&ServerKind::from("room"))
+                .map(|s| s.len()),
+            Some(1)
+        );
+
+        cache.remove(&servers[1].kind, &servers[1].id);
+        assert!(cache.servers_by_id.is_empty());
+        assert!(cache.servers_by_kind.is_empty());
+    }
+
+    #[test]
+    fn etcd_credentials_are_only_used_when_provided() {
+        let settings = settings::Etcd::default();
+        assert_eq!(etcd_credentials(&settings), None);
+
+        let settings = settings::Etcd {
+            auth_user: "user".to_owned(),
+            auth_pass: "secret".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(etcd_credentials(&settings), Some(("user", "secret")));
+    }
+
+    #[test]
+    fn etcd_settings_do_not_show_password() {
+        let settings = settings::Etcd {
+            auth_user: "user".to_owned(),
+            auth_pass: "secret".to_owned(),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", settings);
+        assert!(debug.contains("user"));
+        assert!(!debug.contains("secret"));
+    }
+
     #[tokio::test]
     async fn sd_can_be_create() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
         let server = new_server();
         let _sd = EtcdLazy::new(
             test_helpers::get_root_logger(),
             server,
             Arc::new(settings::Etcd {
                 prefix: "pitaya".to_owned(),
-                url: constants::LOCAL_ETCD_URL.to_owned(),
+                url: etcd.url().to_owned(),
                 lease_ttl: Duration::from_secs(60),
+                ..Default::default()
             }),
         )
         .await?;
         Ok(())
     }
 
+    #[tokio::test]
+    async fn sd_can_be_used_as_trait_object() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let server = new_server();
+        let mut sd: Box<dyn Discovery> = Box::new(
+            EtcdLazy::new(
+                test_helpers::get_root_logger(),
+                server.clone(),
+                Arc::new(settings::Etcd {
+                    prefix: "pitaya".to_owned(),
+                    url: etcd.url().to_owned(),
+                    lease_ttl: Duration::from_secs(60),
+                    ..Default::default()
+                }),
+            )
+            .await?,
+        );
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        let found = sd.server_by_id(&server.id, Some(&server.kind)).await?;
+        assert_eq!(found.expect("server should be registered").id, server.id);
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
     #[tokio::test]
     #[should_panic]
     async fn sd_can_fail_creation() {
@@ -393,6 +1060,7 @@ mod tests {
                 prefix: "pitaya".to_owned(),
                 url: INVALID_ETCD_URL.to_owned(),
                 lease_ttl: Duration::from_secs(60),
+                ..Default::default()
             }),
         )
         .await
@@ -400,43 +1068,290 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn cache_empty_on_start() -> Result<(), Box<dyn StdError>> {
-        let server = new_server();
-        let sd = EtcdLazy::new(
+    async fn sd_rejects_zero_lease_ttl() {
+        let res = EtcdLazy::new(
             test_helpers::get_root_logger(),
-            server,
+            new_server(),
             Arc::new(settings::Etcd {
                 prefix: "pitaya".to_owned(),
                 url: constants::LOCAL_ETCD_URL.to_owned(),
-                lease_ttl: Duration::from_secs(60),
+                lease_ttl: Duration::from_millis(500),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::InvalidSettings(_))));
+    }
+
+    #[tokio::test]
+    async fn sd_rejects_invalid_renewal_fraction() {
+        for fraction in &[0.0, -0.5, 1.5, f64::NAN] {
+            let res = EtcdLazy::new(
+                test_helpers::get_root_logger(),
+                new_server(),
+                Arc::new(settings::Etcd {
+                    prefix: "pitaya".to_owned(),
+                    url: constants::LOCAL_ETCD_URL.to_owned(),
+                    keep_alive_renewal_fraction: *fraction,
+                    ..Default::default()
+                }),
+            )
+            .await;
+            assert!(matches!(res, Err(Error::InvalidSettings(_))));
+        }
+    }
+
+    #[test]
+    fn sd_rejects_invalid_keep_alive_jitter() {
+        for jitter in &[-0.1, 1.0, f64::NAN] {
+            let res = validate_settings(Arc::new(settings::Etcd {
+                keep_alive_jitter: *jitter,
+                ..Default::default()
+            }));
+            assert!(matches!(res, Err(Error::InvalidSettings(_))));
+        }
+    }
+
+    #[test]
+    fn server_keys_have_the_expected_format() {
+        let kind = ServerKind::from("room");
+        let id = ServerId::from("server-id");
+        assert_eq!(servers_key("pitaya", None), "pitaya/servers/");
+        assert_eq!(servers_key("pitaya", Some(&kind)), "pitaya/servers/room/");
+        assert_eq!(
+            server_key("pitaya", &kind, &id),
+            "pitaya/servers/room/server-id"
+        );
+    }
+
+    #[test]
+    fn prefix_trailing_slashes_are_removed() {
+        for prefix in &["pitaya", "pitaya/", "pitaya//"] {
+            let settings = normalize_prefix(Arc::new(settings::Etcd {
+                prefix: prefix.to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+            assert_eq!(settings.prefix, "pitaya");
+        }
+    }
+
+    #[tokio::test]
+    async fn sd_rejects_empty_prefix() {
+        for prefix in &["", "/"] {
+            let res = EtcdLazy::new(
+                test_helpers::get_root_logger(),
+                new_server(),
+                Arc::new(settings::Etcd {
+                    prefix: prefix.to_string(),
+                    url: constants::LOCAL_ETCD_URL.to_owned(),
+                    ..Default::default()
+                }),
+            )
+            .await;
+            assert!(matches!(res, Err(Error::InvalidSettings(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn sd_uses_normalized_prefix_for_keys() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let server = new_server();
+        let sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            server.clone(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya/".to_owned(),
+                url: etcd.url().to_owned(),
+                ..Default::default()
             }),
         )
         .await?;
-        assert_eq!(sd.servers_cache.read().unwrap().servers_by_id.len(), 0);
-        assert_eq!(sd.servers_cache.read().unwrap().servers_by_kind.len(), 0);
+        assert_eq!(
+            sd.registration_key(),
+            format!("pitaya/servers/{}/{}", server.kind, server.id)
+        );
         Ok(())
     }
 
     #[tokio::test]
-    async fn server_by_id_works() -> Result<(), Box<dyn StdError>> {
+    async fn slow_tasks_are_aborted_on_shutdown() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
         let mut sd = EtcdLazy::new(
             test_helpers::get_root_logger(),
             new_server(),
             Arc::new(settings::Etcd {
                 prefix: "pitaya".to_owned(),
-                url: constants::LOCAL_ETCD_URL.to_owned(),
-                lease_ttl: Duration::from_secs(60),
+                url: etcd.url().to_owned(),
+                shutdown_timeout: Duration::from_millis(100),
+                ..Default::default()
             }),
         )
         .await?;
 
-        let server = sd
-            .server_by_id(
-                &ServerId::from("random-id"),
-                Some(&ServerKind::from("room")),
-            )
-            .await?;
-        assert!(server.is_none());
+        // The task ignores the stop message and would take much longer than the timeout.
+        let finished = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let task_finished = finished.clone();
+        let (stop_sender, _stop_receiver) = tokio::sync::oneshot::channel();
+        sd.keep_alive_task = Some((
+            Task::spawn(async move {
+                tokio::time::delay_for(Duration::from_millis(500)).await;
+                task_finished.store(true, std::sync::atomic::Ordering::SeqCst);
+            }),
+            stop_sender,
+        ));
+
+        let start = Instant::now();
+        assert!(matches!(
+            sd.shutdown().await,
+            Err(Error::TaskNotStopped {
+                task: "keep_alive",
+                failure: TaskFailure::TimedOut
+            })
+        ));
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        tokio::time::delay_for(Duration::from_millis(600)).await;
+        assert!(!finished.load(std::sync::atomic::Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finished_tasks_are_reported_on_shutdown() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        // The task exits without waiting for the stop message, dropping its receiver.
+        let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+        sd.keep_alive_task = Some((
+            Task::spawn(async move {
+                drop(stop_receiver);
+            }),
+            stop_sender,
+        ));
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        assert!(matches!(
+            sd.shutdown().await,
+            Err(Error::TaskNotStopped {
+                task: "keep_alive",
+                failure: TaskFailure::AlreadyFinished
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn panicked_tasks_are_reported_on_shutdown() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+        sd.keep_alive_task = Some((
+            Task::spawn(async move {
+                let _ = stop_receiver.await;
+                panic!("keep alive task failed");
+            }),
+            stop_sender,
+        ));
+
+        assert!(matches!(
+            sd.shutdown().await,
+            Err(Error::TaskNotStopped {
+                task: "keep_alive",
+                failure: TaskFailure::Panicked
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stopped_tasks_do_not_fail_shutdown() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
+        sd.keep_alive_task = Some((
+            Task::spawn(async move {
+                let _ = stop_receiver.await;
+            }),
+            stop_sender,
+        ));
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_empty_on_start() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let server = new_server();
+        let sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            server,
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+        assert_eq!(sd.servers_cache.read().unwrap().servers_by_id.len(), 0);
+        assert_eq!(sd.servers_cache.read().unwrap().servers_by_kind.len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_by_id_works() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+        let room = put_server(&etcd, "by-id-room", "by-id-server-id").await?;
+
+        let server = sd
+            .server_by_id(
+                &ServerId::from("random-id"),
+                Some(&ServerKind::from("by-id-room")),
+            )
+            .await?;
+        assert!(server.is_none());
         assert_eq!(sd.servers_cache.read().unwrap().servers_by_id.len(), 1);
 
         let mut server_id: Option<ServerId> = None;
@@ -445,7 +1360,10 @@ mod tests {
         }
 
         let server = sd
-            .server_by_id(server_id.as_ref().unwrap(), Some(&ServerKind::from("room")))
+            .server_by_id(
+                server_id.as_ref().unwrap(),
+                Some(&ServerKind::from("by-id-room")),
+            )
             .await?;
 
         assert!(server.is_some());
@@ -456,39 +1374,102 @@ mod tests {
                 .read()
                 .unwrap()
                 .servers_by_kind
-                .get(&ServerKind::from("room"))
+                .get(&ServerKind::from("by-id-room"))
                 .unwrap()
                 .len(),
             1
         );
-        assert_eq!(server_id.unwrap(), server.unwrap().id);
+        assert_eq!(server_id.unwrap(), room.id);
+        assert_eq!(server.unwrap(), room);
+
+        delete_servers(&etcd, "by-id-room").await?;
         Ok(())
     }
 
     #[tokio::test]
     async fn server_by_kind_works() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
         let mut sd = EtcdLazy::new(
             test_helpers::get_root_logger(),
             new_server(),
             Arc::new(settings::Etcd {
                 prefix: "pitaya".to_owned(),
-                url: constants::LOCAL_ETCD_URL.to_owned(),
+                url: etcd.url().to_owned(),
                 lease_ttl: Duration::from_secs(60),
+                ..Default::default()
             }),
         )
         .await?;
+        let room = put_server(&etcd, "by-kind-room", "by-kind-server-id").await?;
 
-        let servers = sd.servers_by_kind(&ServerKind::from("room")).await?;
-        assert_eq!(servers.len(), 1);
+        let servers = sd
+            .servers_by_kind(&ServerKind::from("by-kind-room"))
+            .await?;
+        assert_eq!(servers, vec![room]);
 
-        let servers = sd.servers_by_kind(&ServerKind::from("room2")).await?;
+        let servers = sd
+            .servers_by_kind(&ServerKind::from("by-kind-room2"))
+            .await?;
         assert_eq!(servers.len(), 0);
 
+        delete_servers(&etcd, "by-kind-room").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_by_kind_returns_all_servers() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        for id in &["first-id", "second-id"] {
+            let server = ServerInfo {
+                frontend: false,
+                hostname: "".to_owned(),
+                id: ServerId::from(*id),
+                kind: ServerKind::from("two-servers-kind"),
+                metadata: HashMap::new(),
+            };
+            client
+                .put(
+                    format!("pitaya/servers/two-servers-kind/{}", id),
+                    serde_json::to_vec(&server)?,
+                    None,
+                )
+                .await?;
+        }
+
+        let mut servers = sd
+            .servers_by_kind(&ServerKind::from("two-servers-kind"))
+            .await?;
+        servers.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].id, ServerId::from("first-id"));
+        assert_eq!(servers[1].id, ServerId::from("second-id"));
+
+        client
+            .delete(
+                "pitaya/servers/two-servers-kind/",
+                Some(etcd_client::DeleteOptions::new().with_prefix()),
+            )
+            .await?;
         Ok(())
     }
 
     #[tokio::test]
     async fn server_lease_works() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
         let server = new_server();
         let (app_die_sender, _app_die_recv) = broadcast::channel(10);
 
@@ -497,8 +1478,9 @@ mod tests {
             server,
             Arc::new(settings::Etcd {
                 prefix: "pitaya".to_owned(),
-                url: constants::LOCAL_ETCD_URL.to_owned(),
+                url: etcd.url().to_owned(),
                 lease_ttl: Duration::from_secs(60),
+                ..Default::default()
             }),
         )
         .await?;
@@ -510,19 +1492,51 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn server_lease_uses_configured_ttl() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(30),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        sd.start(app_die_sender).await?;
+        let lease = sd
+            .client
+            .lease_time_to_live(sd.lease_id.unwrap(), None)
+            .await?;
+        assert_eq!(lease.granted_ttl(), 30);
+        sd.shutdown().await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn server_watch_works() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
         let server = new_server();
         let mut sd = EtcdLazy::new(
             test_helpers::get_root_logger(),
             server,
             Arc::new(settings::Etcd {
                 prefix: "pitaya".to_owned(),
-                url: constants::LOCAL_ETCD_URL.to_owned(),
+                url: etcd.url().to_owned(),
                 lease_ttl: Duration::from_secs(60),
+                ..Default::default()
             }),
         )
         .await?;
+        // The server is stored before the watch starts, so it is only added to the cache
+        // when it is fetched below.
+        let room = put_server(&etcd, "watch-room", "watch-server-id").await?;
 
         let mut subscribe_chan = sd.subscribe();
 
@@ -565,18 +1579,1169 @@ mod tests {
         assert_eq!(servers_added.read().unwrap().len(), 0);
         assert_eq!(servers_removed.read().unwrap().len(), 0);
 
-        let servers = sd.servers_by_kind(&ServerKind::from("room")).await?;
+        let servers = sd.servers_by_kind(&ServerKind::from("watch-room")).await?;
 
         // Wait a little bit, otherwise we'll have a rece condition reading both
         // RwLocks below.
         tokio::time::delay_for(Duration::from_millis(50)).await;
 
-        assert_eq!(servers.len(), 1);
-        assert_eq!(servers_added.read().unwrap().len(), 1);
+        assert_eq!(servers, vec![room.clone()]);
+        assert_eq!(*servers_added.read().unwrap(), vec![room]);
         assert_eq!(servers_removed.read().unwrap().len(), 0);
 
         sd.shutdown().await?;
+        delete_servers(&etcd, "watch-room").await?;
+
+        Ok(())
+    }
+
+    // Waits for the next notification that refers to the given server id.
+    async fn next_notification_for(
+        subscription: &mut broadcast::Receiver<Notification>,
+        id: &ServerId,
+    ) -> Notification {
+        loop {
+            let notification = tokio::time::timeout(Duration::from_secs(2), subscription.recv())
+                .await
+                .expect("should receive a notification in time")
+                .expect("subscription should not fail");
+            match &notification {
+                Notification::ServerAdded(s) | Notification::ServerRemoved(s) if &s.id == id => {
+                    return notification
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_events_notify_subscribers() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        let notified_server = Arc::new(ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from("notified-server-id"),
+            kind: ServerKind::from("notified-kind"),
+            metadata: HashMap::new(),
+        });
+        let key = "pitaya/servers/notified-kind/notified-server-id";
+
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        client
+            .put(key, serde_json::to_vec(&*notified_server)?, None)
+            .await?;
+
+        match next_notification_for(&mut subscription, &notified_server.id).await {
+            Notification::ServerAdded(s) => assert_eq!(s, notified_server),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+
+        client.delete(key, None).await?;
+
+        match next_notification_for(&mut subscription, &notified_server.id).await {
+            Notification::ServerRemoved(s) => assert_eq!(s, notified_server),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caching_corrupt_servers_does_not_fail() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let key = "pitaya/servers/corrupt-kind/corrupt-server-id";
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        client.put(key, "{not a server", None).await?;
+
+        let servers = sd
+            .servers_by_kind(&ServerKind::from("corrupt-kind"))
+            .await?;
+        assert!(servers.is_empty());
+
+        client.delete(key, None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_servers_do_not_prevent_caching_others() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
 
+        let kind = ServerKind::from("mixed-kind");
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        // The corrupt key comes first, so that it is processed before the valid ones.
+        client
+            .put(
+                "pitaya/servers/mixed-kind/a-corrupt-id",
+                "{not a server",
+                None,
+            )
+            .await?;
+        for id in &["b-valid-id", "c-valid-id"] {
+            let server = ServerInfo {
+                frontend: false,
+                hostname: "".to_owned(),
+                id: ServerId::from(*id),
+                kind: kind.clone(),
+                metadata: HashMap::new(),
+            };
+            client
+                .put(
+                    format!("pitaya/servers/mixed-kind/{}", id),
+                    serde_json::to_vec(&server)?,
+                    None,
+                )
+                .await?;
+        }
+
+        assert_eq!(sd.cache_servers(Some(&kind)).await?, 1);
+        let mut servers = sd.only_servers_by_kind(&kind);
+        servers.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].id, ServerId::from("b-valid-id"));
+        assert_eq!(servers[1].id, ServerId::from("c-valid-id"));
+
+        client
+            .delete(
+                "pitaya/servers/mixed-kind/",
+                Some(etcd_client::DeleteOptions::new().with_prefix()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_removes_server_from_etcd() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        let key = sd.registration_key();
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        assert_eq!(client.get(key.as_str(), None).await?.kvs().len(), 1);
+
+        sd.shutdown().await?;
+        assert!(sd.lease_id.is_none());
+        assert!(client.get(key.as_str(), None).await?.kvs().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_metadata_rewrites_server_in_etcd() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let lease_id = sd.lease_id;
+
+        let mut metadata = HashMap::new();
+        metadata.insert("players".to_owned(), "42".to_owned());
+        sd.update_metadata(metadata.clone()).await?;
+        assert_eq!(sd.lease_id, lease_id);
+
+        let key = sd.registration_key();
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        let resp = client.get(key.as_str(), None).await?;
+        let kv = &resp.kvs()[0];
+        let server: ServerInfo = serde_json::from_slice(kv.value())?;
+        assert_eq!(server.metadata, metadata);
+        assert_eq!(server.id, sd.this_server.id);
+        assert_eq!(kv.lease(), lease_id.unwrap());
+
+        sd.shutdown().await?;
+        assert!(client.get(key.as_str(), None).await?.kvs().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_by_id_miss_fetches_from_etcd_once() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, Default::default());
+
+        let server = memory_server("room", "uncached-room");
+        put_memory_server(&etcd, &server).await?;
+
+        let found = sd.server_by_id(&server.id, Some(&server.kind)).await?;
+        assert_eq!(found, Some(server));
+        assert_eq!(etcd.gets(), 1);
+        Ok(())
+    }
+
+    // Lookups take `&mut self`, so tasks sharing a discovery go through a lock, like
+    // `Pitaya` does. The first miss fills the cache before the others run, so there is no
+    // stampede of fetches to deduplicate.
+    #[tokio::test]
+    async fn misses_from_tasks_sharing_a_discovery_fetch_once() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let sd: Box<dyn Discovery> = Box::new(new_memory_sd(&etcd, Default::default()));
+        let sd = Arc::new(tokio::sync::Mutex::new(sd));
+
+        let server = memory_server("stampede-kind", "stampede-server-id");
+        put_memory_server(&etcd, &server).await?;
+
+        let lookups: Vec<_> = (0..10)
+            .map(|_| {
+                let sd = sd.clone();
+                let (id, kind) = (server.id.clone(), server.kind.clone());
+                tokio::spawn(async move { sd.lock().await.server_by_id(&id, Some(&kind)).await })
+            })
+            .collect();
+        for lookup in lookups {
+            assert_eq!(lookup.await??, Some(server.clone()));
+        }
+        assert_eq!(etcd.gets(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_by_id_reports_cache_hits_and_misses() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let recording = RecordingReporter::default();
+        let counters = recording.counters.clone();
+        let qualified_names = recording.qualified_names.clone();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?
+        .with_metrics_reporter(Arc::new(tokio::sync::RwLock::new(Box::new(recording))));
+
+        let server = ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from("metrics-server-id"),
+            kind: ServerKind::from("metrics-kind"),
+            metadata: HashMap::new(),
+        };
+        let key = "pitaya/servers/metrics-kind/metrics-server-id";
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        client.put(key, serde_json::to_vec(&server)?, None).await?;
+
+        // The first lookup fills the cache, and the second one is answered by it.
+        assert!(sd
+            .server_by_id(&server.id, Some(&server.kind))
+            .await?
+            .is_some());
+        assert!(sd
+            .server_by_id(&server.id, Some(&server.kind))
+            .await?
+            .is_some());
+
+        let names: Vec<String> = counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        assert_eq!(
+            names,
+            vec![DISCOVERY_CACHE_MISSES_METRIC, DISCOVERY_CACHE_HITS_METRIC]
+        );
+        assert!(qualified_names
+            .lock()
+            .unwrap()
+            .contains(&"pitaya_discovery_discovery_cache_hits".to_owned()));
+
+        client.delete(key, None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_not_found_is_remembered_for_ttl() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        // The discovery is not started, so the cache is only filled by lookups.
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                server_not_found_ttl: Duration::from_millis(500),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let server = ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from("late-server-id"),
+            kind: ServerKind::from("late-kind"),
+            metadata: HashMap::new(),
+        };
+        assert!(sd
+            .server_by_id(&server.id, Some(&server.kind))
+            .await?
+            .is_none());
+
+        let key = "pitaya/servers/late-kind/late-server-id";
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        client.put(key, serde_json::to_vec(&server)?, None).await?;
+
+        // Etcd is not queried again while the id is remembered as missing.
+        assert!(sd
+            .server_by_id(&server.id, Some(&server.kind))
+            .await?
+            .is_none());
+
+        tokio::time::delay_for(Duration::from_millis(600)).await;
+        assert!(sd
+            .server_by_id(&server.id, Some(&server.kind))
+            .await?
+            .is_some());
+
+        client.delete(key, None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_by_id_returns_none_for_absent_server() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let server = sd
+            .server_by_id(
+                &ServerId::from("absent-server-id"),
+                Some(&ServerKind::from("absent-kind")),
+            )
+            .await?;
+        assert!(server.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sd_reports_unreachable_etcd_as_error() {
+        let res = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: INVALID_ETCD_URL.to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::Connection(_))));
+    }
+
+    #[tokio::test]
+    async fn server_by_id_reports_etcd_gone_after_start_as_error() -> Result<(), Box<dyn StdError>>
+    {
+        let mut etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                shutdown_timeout: Duration::from_millis(100),
+                ..Default::default()
+            }),
+        )
+        .await?;
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        let id = ServerId::from("absent-server-id");
+        let kind = ServerKind::from("absent-kind");
+        assert!(matches!(
+            sd.required_server_by_id(&id, Some(&kind)).await,
+            Err(Error::ServerNotFound(not_found)) if not_found == id
+        ));
+
+        // A shared etcd cannot be stopped by the test.
+        if !etcd.stop() {
+            return Ok(());
+        }
+        assert!(matches!(
+            sd.server_by_id(&id, Some(&kind)).await,
+            Err(Error::ClusterCommunication(_))
+        ));
+        assert!(matches!(
+            sd.required_server_by_id(&id, Some(&kind)).await,
+            Err(Error::ClusterCommunication(_))
+        ));
+
+        // Revoking the lease fails, since etcd is gone.
+        let _ = sd.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_keeps_cache_in_sync() -> Result<(), Box<dyn StdError>> {
+        let etcd = test_helpers::EtcdServer::start();
+        let mut sd = EtcdLazy::new(
+            test_helpers::get_root_logger(),
+            new_server(),
+            Arc::new(settings::Etcd {
+                prefix: "pitaya".to_owned(),
+                url: etcd.url().to_owned(),
+                lease_ttl: Duration::from_secs(60),
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        let watched_server = Arc::new(ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from("watched-server-id"),
+            kind: ServerKind::from("watched-kind"),
+            metadata: HashMap::new(),
+        });
+        let key = "pitaya/servers/watched-kind/watched-server-id";
+
+        let mut client = etcd_client::Client::connect([etcd.url()], None).await?;
+        client
+            .put(key, serde_json::to_vec(&*watched_server)?, None)
+            .await?;
+
+        // Give the watch task some time to receive the event.
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        assert_eq!(
+            sd.only_server_by_id(&watched_server.id),
+            Some(watched_server.clone())
+        );
+
+        client.delete(key, None).await?;
+
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        assert_eq!(sd.only_server_by_id(&watched_server.id), None);
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn start_registers_server_under_lease() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, settings::Etcd::default());
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        let key = sd.registration_key();
+        let stored: ServerInfo = serde_json::from_slice(&etcd.value(&key).unwrap())?;
+        assert_eq!(stored, *sd.this_server);
+        assert!(sd.current_lease_id().is_some());
+        assert_eq!(etcd.lease_of(&key), sd.current_lease_id());
+
+        sd.shutdown().await?;
+        assert!(sd.current_lease_id().is_none());
+        assert_eq!(etcd.value(&key), None);
+        assert!(etcd.leases().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn logs_include_server_id_and_prefix() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let (logger, logs) = test_helpers::get_capturing_logger();
+        let settings = validate_settings(Arc::new(settings::Etcd {
+            prefix: "logged-app".to_owned(),
+            ..Default::default()
+        }))?;
+        let mut sd = EtcdLazy::with_client(logger, new_server(), settings, etcd.clone());
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        sd.shutdown().await?;
+
+        let added = logs
+            .lines()
+            .into_iter()
+            .find(|line| line.starts_with("added server to etcd"))
+            .expect("registration should be logged");
+        assert!(added.contains(&format!("server_id={}", sd.this_server.id)));
+        assert!(added.contains("etcd_prefix=logged-app"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn start_replaces_stale_registration() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                remove_stale_registration: true,
+                ..Default::default()
+            },
+        );
+
+        // A previous instance of the server crashed without revoking its lease.
+        let key = sd.registration_key();
+        let stale_lease = etcd.clone().lease_grant(60).await?;
+        let mut metadata = HashMap::new();
+        metadata.insert("stale".to_owned(), "true".to_owned());
+        let stale_server = ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: sd.this_server.id.clone(),
+            kind: sd.this_server.kind.clone(),
+            metadata,
+        };
+        etcd.clone()
+            .put(
+                key.clone(),
+                serde_json::to_vec(&stale_server)?,
+                Some(stale_lease),
+            )
+            .await?;
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        let stored: ServerInfo = serde_json::from_slice(&etcd.value(&key).unwrap())?;
+        assert_eq!(stored, *sd.this_server);
+        assert_eq!(etcd.lease_of(&key), sd.current_lease_id());
+
+        // The stale lease expiring does not affect the new registration.
+        etcd.clone().lease_revoke(stale_lease).await?;
+        assert!(etcd.value(&key).is_some());
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_events_update_cache_without_etcd() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, settings::Etcd::default());
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        let server = Arc::new(ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from("memory-server-id"),
+            kind: ServerKind::from("memory-kind"),
+            metadata: HashMap::new(),
+        });
+        let key = server_key("pitaya", &server.kind, &server.id);
+        etcd.clone()
+            .put(key.clone(), serde_json::to_vec(&*server)?, None)
+            .await?;
+
+        match next_notification_for(&mut subscription, &server.id).await {
+            Notification::ServerAdded(s) => assert_eq!(s, server),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+        assert_eq!(sd.only_server_by_id(&server.id), Some(server.clone()));
+
+        etcd.delete(&key);
+        match next_notification_for(&mut subscription, &server.id).await {
+            Notification::ServerRemoved(s) => assert_eq!(s, server),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+        assert_eq!(sd.only_server_by_id(&server.id), None);
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_that_cannot_be_reopened_signals_app_die() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                watch_retry_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+        );
+
+        let (app_die_sender, mut app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        etcd.reject_watches(true);
+        etcd.fail_watches();
+        tokio::time::timeout(Duration::from_secs(2), app_die_recv.recv()).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lost_lease_signals_app_die_and_shuts_down() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_max_retries: 0,
+                ..Default::default()
+            },
+        );
+
+        let (app_die_sender, mut app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+
+        // The lease is revoked behind the discovery's back, so the next renewal fails.
+        etcd.clone().lease_revoke(sd.lease_id.unwrap()).await?;
+        tokio::time::timeout(Duration::from_secs(2), app_die_recv.recv()).await??;
+
+        // The keep alive task already finished by itself, which is not a shutdown failure.
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn all_die_signals_use_the_start_channel() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_max_retries: 0,
+                watch_retry_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+        );
+
+        // Both the watch and the keep alive tasks fail, and each one signals the channel
+        // given to `start` exactly once.
+        let (app_die_sender, mut app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        etcd.reject_watches(true);
+        etcd.fail_watches();
+        etcd.clone().lease_revoke(sd.lease_id.unwrap()).await?;
+
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(2), app_die_recv.recv()).await??;
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(app_die_recv.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_server_kinds_are_rejected() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                known_server_kinds: vec!["room".to_owned()],
+                ..Default::default()
+            },
+        );
+        sd.start(broadcast::channel(10).0).await?;
+
+        let room = Arc::new(ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from("room-server-id"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+        });
+        etcd.clone()
+            .put(
+                server_key("pitaya", &room.kind, &room.id),
+                serde_json::to_vec(&*room)?,
+                None,
+            )
+            .await?;
+
+        assert_eq!(sd.servers_by_kind(&room.kind).await?, vec![room.clone()]);
+        assert_eq!(
+            sd.server_by_id(&room.id, Some(&room.kind)).await?,
+            Some(room.clone())
+        );
+        // The kind of this server does not need to be registered.
+        let this_kind = sd.this_server.kind.clone();
+        assert_eq!(sd.servers_by_kind(&this_kind).await?.len(), 1);
+
+        let typo = ServerKind::from("rooom");
+        assert!(matches!(
+            sd.servers_by_kind(&typo).await,
+            Err(Error::UnknownServerKind(kind)) if kind == typo
+        ));
+        assert!(matches!(
+            sd.server_by_id(&room.id, Some(&typo)).await,
+            Err(Error::UnknownServerKind(_))
+        ));
+        assert!(matches!(
+            sd.servers_by_ids(&[(room.id.clone(), typo.clone())]).await,
+            Err(Error::UnknownServerKind(_))
+        ));
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_are_cached_in_pages() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                cache_page_size: 2,
+                ..Default::default()
+            },
+        );
+        sd.start(broadcast::channel(10).0).await?;
+
+        let kind = ServerKind::from("room");
+        for (id, kind) in &[
+            ("room-1", "room"),
+            ("room-2", "room"),
+            ("room-3", "room"),
+            ("room-4", "room"),
+            ("room-5", "room"),
+            ("roomy-1", "roomy"),
+        ] {
+            let server = ServerInfo {
+                frontend: false,
+                hostname: "".to_owned(),
+                id: ServerId::from(*id),
+                kind: ServerKind::from(*kind),
+                metadata: HashMap::new(),
+            };
+            etcd.clone()
+                .put(
+                    server_key("pitaya", &server.kind, &server.id),
+                    serde_json::to_vec(&server)?,
+                    None,
+                )
+                .await?;
+        }
+
+        let gets = etcd.gets();
+        let mut ids: Vec<_> = sd
+            .servers_by_kind(&kind)
+            .await?
+            .iter()
+            .map(|s| s.id.0.clone())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["room-1", "room-2", "room-3", "room-4", "room-5"]);
+        // Five servers in pages of two.
+        assert_eq!(etcd.gets() - gets, 3);
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_watched_kinds_are_notified() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                watched_server_kinds: vec!["watched-kind".to_owned()],
+                ..Default::default()
+            },
+        );
+
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        let servers: Vec<_> = ["ignored-kind", "watched-kind"]
+            .iter()
+            .map(|kind| {
+                Arc::new(ServerInfo {
+                    frontend: false,
+                    hostname: "".to_owned(),
+                    id: ServerId::from(format!("{}-server-id", kind)),
+                    kind: ServerKind::from(*kind),
+                    metadata: HashMap::new(),
+                })
+            })
+            .collect();
+        // The ignored server is stored first, so its notification would come first.
+        for server in &servers {
+            etcd.clone()
+                .put(
+                    server_key("pitaya", &server.kind, &server.id),
+                    serde_json::to_vec(&**server)?,
+                    None,
+                )
+                .await?;
+        }
+
+        let notification =
+            tokio::time::timeout(Duration::from_secs(2), subscription.recv()).await??;
+        match notification {
+            Notification::ServerAdded(s) => assert_eq!(s, servers[1]),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+        assert_eq!(sd.only_server_by_id(&servers[0].id), None);
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    fn memory_server(kind: &str, id: &str) -> Arc<ServerInfo> {
+        Arc::new(ServerInfo {
+            frontend: false,
+            hostname: "".to_owned(),
+            id: ServerId::from(id),
+            kind: ServerKind::from(kind),
+            metadata: HashMap::new(),
+        })
+    }
+
+    async fn put_memory_server(
+        etcd: &MemoryEtcd,
+        server: &ServerInfo,
+    ) -> Result<(), Box<dyn StdError>> {
+        etcd.clone()
+            .put(
+                server_key("pitaya", &server.kind, &server.id),
+                serde_json::to_vec(server)?,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broken_watch_resumes_without_missing_changes() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                watch_retry_backoff: Duration::from_millis(200),
+                ..Default::default()
+            },
+        );
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        let first = memory_server("resumed-kind", "first-id");
+        put_memory_server(&etcd, &first).await?;
+        next_notification_for(&mut subscription, &first.id).await;
+
+        // The changes made while the watch is broken are received once it is reopened.
+        etcd.reject_watches(true);
+        etcd.fail_watches();
+        let second = memory_server("resumed-kind", "second-id");
+        put_memory_server(&etcd, &second).await?;
+        etcd.delete(&server_key("pitaya", &first.kind, &first.id));
+        etcd.reject_watches(false);
+
+        match next_notification_for(&mut subscription, &second.id).await {
+            Notification::ServerAdded(s) => assert_eq!(s, second),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+        match next_notification_for(&mut subscription, &first.id).await {
+            Notification::ServerRemoved(s) => assert_eq!(s, first),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compacted_watch_fetches_servers_again() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                watch_retry_backoff: Duration::from_millis(200),
+                ..Default::default()
+            },
+        );
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        let first = memory_server("compacted-kind", "first-id");
+        put_memory_server(&etcd, &first).await?;
+        next_notification_for(&mut subscription, &first.id).await;
+
+        // The changes made while the watch is broken are compacted, so the servers are
+        // fetched again instead.
+        etcd.reject_watches(true);
+        etcd.fail_watches();
+        let second = memory_server("compacted-kind", "second-id");
+        put_memory_server(&etcd, &second).await?;
+        etcd.delete(&server_key("pitaya", &first.kind, &first.id));
+        etcd.compact();
+        etcd.reject_watches(false);
+
+        match next_notification_for(&mut subscription, &first.id).await {
+            Notification::ServerRemoved(s) => assert_eq!(s, first),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+        match next_notification_for(&mut subscription, &second.id).await {
+            Notification::ServerAdded(s) => assert_eq!(s, second),
+            n => panic!("unexpected notification: {:?}", n),
+        }
+
+        // Changes after the servers were fetched are watched again.
+        let third = memory_server("compacted-kind", "third-id");
+        put_memory_server(&etcd, &third).await?;
+        next_notification_for(&mut subscription, &third.id).await;
+        assert_eq!(sd.only_servers_by_kind(&third.kind).len(), 2);
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_can_be_filtered_by_metadata() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, Default::default());
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        let mut servers = Vec::new();
+        for (id, region) in &[
+            ("east-1", "us-east"),
+            ("west-1", "us-west"),
+            ("east-2", "us-east"),
+        ] {
+            let mut metadata = HashMap::new();
+            metadata.insert("region".to_owned(), region.to_string());
+            let server = Arc::new(ServerInfo {
+                frontend: false,
+                hostname: "".to_owned(),
+                id: ServerId::from(*id),
+                kind: ServerKind::from("regional-kind"),
+                metadata,
+            });
+            put_memory_server(&etcd, &server).await?;
+            next_notification_for(&mut subscription, &server.id).await;
+            servers.push(server);
+        }
+        let no_region = memory_server("regional-kind", "no-region");
+        put_memory_server(&etcd, &no_region).await?;
+        next_notification_for(&mut subscription, &no_region.id).await;
+
+        // The watched servers are in the cache, so etcd is not called.
+        let gets = etcd.gets();
+        let kind = ServerKind::from("regional-kind");
+        let mut east = sd
+            .servers_by_kind_filtered(&kind, &|metadata| {
+                metadata.get("region").map(String::as_str) == Some("us-east")
+            })
+            .await?;
+        east.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+        assert_eq!(east, vec![servers[0].clone(), servers[2].clone()]);
+
+        let without_region = sd
+            .servers_by_kind_filtered(&kind, &|metadata| !metadata.contains_key("region"))
+            .await?;
+        assert_eq!(without_region, vec![no_region]);
+
+        let none = sd
+            .servers_by_kind_filtered(&kind, &|metadata| {
+                metadata.get("region").map(String::as_str) == Some("eu-west")
+            })
+            .await?;
+        assert!(none.is_empty());
+        assert_eq!(etcd.gets(), gets);
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_can_be_filtered_by_frontend() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, Default::default());
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        let mut servers = Vec::new();
+        for (id, frontend) in &[("front-1", true), ("back-1", false), ("front-2", true)] {
+            let server = Arc::new(ServerInfo {
+                frontend: *frontend,
+                hostname: "".to_owned(),
+                id: ServerId::from(*id),
+                kind: ServerKind::from("mixed-kind"),
+                metadata: HashMap::new(),
+            });
+            put_memory_server(&etcd, &server).await?;
+            next_notification_for(&mut subscription, &server.id).await;
+            servers.push(server);
+        }
+
+        let kind = ServerKind::from("mixed-kind");
+        let mut frontends = sd.frontend_servers_by_kind(&kind).await?;
+        frontends.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+        assert_eq!(frontends, vec![servers[0].clone(), servers[2].clone()]);
+        assert_eq!(
+            sd.backend_servers_by_kind(&kind).await?,
+            vec![servers[1].clone()]
+        );
+        assert!(sd
+            .frontend_servers_by_kind(&ServerKind::from("unknown-kind"))
+            .await?
+            .is_empty());
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifications_can_be_consumed_as_a_stream() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, Default::default());
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut notifications = pitaya_core::cluster::notification_stream(sd.subscribe());
+
+        let first = memory_server("streamed-kind", "first-id");
+        let second = memory_server("streamed-kind", "second-id");
+        put_memory_server(&etcd, &first).await?;
+        put_memory_server(&etcd, &second).await?;
+        etcd.delete(&server_key("pitaya", &first.kind, &first.id));
+
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let notification = tokio::time::timeout(Duration::from_secs(2), notifications.next())
+                .await?
+                .expect("stream should not end");
+            received.push(match notification {
+                Notification::ServerAdded(s) => ("added", s),
+                Notification::ServerRemoved(s) => ("removed", s),
+            });
+        }
+        assert_eq!(
+            received,
+            vec![
+                ("added", first.clone()),
+                ("added", second),
+                ("removed", first)
+            ]
+        );
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_by_ids_fills_the_cache_once_per_kind() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(
+            &etcd,
+            settings::Etcd {
+                watched_server_kinds: vec!["watched-kind".to_owned()],
+                ..Default::default()
+            },
+        );
+        let (app_die_sender, _app_die_recv) = broadcast::channel(10);
+        sd.start(app_die_sender).await?;
+        let mut subscription = sd.subscribe();
+
+        // The watched server is cached, and the others have to be fetched from etcd.
+        let cached = memory_server("watched-kind", "cached-id");
+        put_memory_server(&etcd, &cached).await?;
+        next_notification_for(&mut subscription, &cached.id).await;
+        let first = memory_server("unwatched-kind", "first-id");
+        let second = memory_server("unwatched-kind", "second-id");
+        put_memory_server(&etcd, &first).await?;
+        put_memory_server(&etcd, &second).await?;
+
+        let gets = etcd.gets();
+        let servers = sd
+            .servers_by_ids(&[
+                (cached.id.clone(), cached.kind.clone()),
+                (first.id.clone(), first.kind.clone()),
+                (second.id.clone(), second.kind.clone()),
+                (ServerId::from("missing-id"), first.kind.clone()),
+                (ServerId::from("other-id"), ServerKind::from("other-kind")),
+            ])
+            .await?;
+
+        let mut expected = HashMap::new();
+        for server in &[cached, first, second] {
+            expected.insert(server.id.clone(), server.clone());
+        }
+        assert_eq!(servers, expected);
+        assert_eq!(etcd.gets(), gets + 2);
+
+        sd.shutdown().await?;
         Ok(())
     }
 }
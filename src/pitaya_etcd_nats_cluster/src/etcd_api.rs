@@ -0,0 +1,703 @@
+use crate::tasks::{EtcdLeaseRenewer, LeaseRenewer};
+use async_trait::async_trait;
+use pitaya_core::cluster::Error;
+
+// The operations on etcd used by the service discovery. `EtcdLazy` is generic over it,
+// so that it can be tested without an etcd server.
+//
+// Keys are always read and watched by prefix, since that is all the discovery needs. Clones
+// should share the same connection, so that the watch task can use its own.
+#[async_trait]
+pub trait EtcdApi: Clone + Send + 'static {
+    type Renewer: LeaseRenewer;
+    type Watcher: Watcher;
+    type WatchStream: WatchStream;
+
+    // Returns the keys that start with the given prefix.
+    async fn get(&mut self, prefix: String) -> Result<GetResponse, Error>;
+
+    // Returns up to `limit` keys that start with the given prefix, sorted by key, starting
+    // at `from_key`. If a revision is given, the keys are read as they were at it, so that
+    // the pages of a listing are consistent with each other.
+    async fn get_page(
+        &mut self,
+        prefix: String,
+        from_key: Vec<u8>,
+        limit: i64,
+        revision: Option<i64>,
+    ) -> Result<GetResponse, Error>;
+
+    // Stores the value under the key, attached to the lease if one is given.
+    async fn put(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        lease_id: Option<i64>,
+    ) -> Result<(), Error>;
+
+    // Deletes the key, returning whether it existed.
+    async fn delete(&mut self, key: String) -> Result<bool, Error>;
+
+    // Grants a lease with the given TTL in seconds, returning its id.
+    async fn lease_grant(&mut self, ttl: i64) -> Result<i64, Error>;
+
+    // Returns a renewer that keeps the lease alive.
+    async fn lease_keep_alive(&mut self, lease_id: i64) -> Result<Self::Renewer, Error>;
+
+    // Revokes the lease, deleting the keys attached to it.
+    async fn lease_revoke(&mut self, lease_id: i64) -> Result<(), Error>;
+
+    // Watches the changes of the keys that start with the given prefix. If a start
+    // revision is given, the changes made since that revision are received first.
+    async fn watch(
+        &mut self,
+        prefix: String,
+        start_revision: Option<i64>,
+    ) -> Result<(Self::Watcher, Self::WatchStream), Error>;
+}
+
+// Cancels a watch started with `EtcdApi::watch`.
+#[async_trait]
+pub trait Watcher: Send + 'static {
+    async fn cancel(&mut self) -> Result<(), Error>;
+}
+
+// The responses of a watch started with `EtcdApi::watch`.
+#[async_trait]
+pub trait WatchStream: Send + 'static {
+    // Returns the next response, or `None` if the watch was closed.
+    async fn message(&mut self) -> Result<Option<WatchResponse>, Error>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyValue {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl KeyValue {
+    pub fn key_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.key)
+    }
+
+    pub fn value_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GetResponse {
+    pub kvs: Vec<KeyValue>,
+    // The revision of etcd when the keys were read.
+    pub revision: i64,
+    // Whether there are more keys than the limit of the request.
+    pub more: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    Put,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchEvent {
+    pub event_type: EventType,
+    pub kv: Option<KeyValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WatchResponse {
+    // Whether the watch was canceled, in which case no more responses are sent.
+    pub canceled: bool,
+    // If the watch was canceled because its start revision was compacted, the oldest
+    // revision that can still be watched.
+    pub compact_revision: i64,
+    // The revision of etcd when the response was sent.
+    pub revision: i64,
+    pub events: Vec<WatchEvent>,
+}
+
+fn communication_error(e: etcd_client::Error) -> Error {
+    Error::ClusterCommunication(e.to_string())
+}
+
+// Returns the end of the range of keys that start with the given prefix, which is the
+// prefix with its last byte incremented. Bytes that cannot be incremented are dropped.
+fn prefix_range_end(prefix: &str) -> Vec<u8> {
+    let mut end = prefix.as_bytes().to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every key is after the prefix.
+    vec![0]
+}
+
+impl From<&etcd_client::KeyValue> for KeyValue {
+    fn from(kv: &etcd_client::KeyValue) -> Self {
+        Self {
+            key: kv.key().to_vec(),
+            value: kv.value().to_vec(),
+        }
+    }
+}
+
+#[async_trait]
+impl EtcdApi for etcd_client::Client {
+    type Renewer = EtcdLeaseRenewer;
+    type Watcher = etcd_client::Watcher;
+    type WatchStream = etcd_client::WatchStream;
+
+    async fn get(&mut self, prefix: String) -> Result<GetResponse, Error> {
+        let resp = etcd_client::Client::get(
+            self,
+            prefix,
+            Some(etcd_client::GetOptions::new().with_prefix()),
+        )
+        .await
+        .map_err(communication_error)?;
+        Ok(GetResponse {
+            kvs: resp.kvs().iter().map(KeyValue::from).collect(),
+            revision: resp.header().map(|h| h.revision()).unwrap_or_default(),
+            more: resp.more(),
+        })
+    }
+
+    async fn get_page(
+        &mut self,
+        prefix: String,
+        from_key: Vec<u8>,
+        limit: i64,
+        revision: Option<i64>,
+    ) -> Result<GetResponse, Error> {
+        let mut options = etcd_client::GetOptions::new()
+            .with_range(prefix_range_end(&prefix))
+            .with_limit(limit)
+            .with_sort(etcd_client::SortTarget::Key, etcd_client::SortOrder::Ascend);
+        if let Some(revision) = revision {
+            options = options.with_revision(revision);
+        }
+        let resp = etcd_client::Client::get(self, from_key, Some(options))
+            .await
+            .map_err(communication_error)?;
+        Ok(GetResponse {
+            kvs: resp.kvs().iter().map(KeyValue::from).collect(),
+            revision: resp.header().map(|h| h.revision()).unwrap_or_default(),
+            more: resp.more(),
+        })
+    }
+
+    async fn put(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        lease_id: Option<i64>,
+    ) -> Result<(), Error> {
+        let options = lease_id.map(|id| etcd_client::PutOptions::new().with_lease(id));
+        etcd_client::Client::put(self, key, value, options)
+            .await
+            .map_err(communication_error)?;
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: String) -> Result<bool, Error> {
+        let resp = etcd_client::Client::delete(self, key, None)
+            .await
+            .map_err(communication_error)?;
+        Ok(resp.deleted() > 0)
+    }
+
+    async fn lease_grant(&mut self, ttl: i64) -> Result<i64, Error> {
+        let resp = etcd_client::Client::lease_grant(self, ttl, None)
+            .await
+            .map_err(communication_error)?;
+        Ok(resp.id())
+    }
+
+    async fn lease_keep_alive(&mut self, lease_id: i64) -> Result<Self::Renewer, Error> {
+        let (keeper, stream) = etcd_client::Client::lease_keep_alive(self, lease_id)
+            .await
+            .map_err(communication_error)?;
+        Ok(EtcdLeaseRenewer::new(
+            self.lease_client(),
+            lease_id,
+            keeper,
+            stream,
+        ))
+    }
+
+    async fn lease_revoke(&mut self, lease_id: i64) -> Result<(), Error> {
+        etcd_client::Client::lease_revoke(self, lease_id)
+            .await
+            .map_err(communication_error)?;
+        Ok(())
+    }
+
+    async fn watch(
+        &mut self,
+        prefix: String,
+        start_revision: Option<i64>,
+    ) -> Result<(Self::Watcher, Self::WatchStream), Error> {
+        let mut options = etcd_client::WatchOptions::new().with_prefix();
+        if let Some(revision) = start_revision {
+            options = options.with_start_revision(revision);
+        }
+        etcd_client::Client::watch(self, prefix, Some(options))
+            .await
+            .map_err(communication_error)
+    }
+}
+
+#[async_trait]
+impl Watcher for etcd_client::Watcher {
+    async fn cancel(&mut self) -> Result<(), Error> {
+        etcd_client::Watcher::cancel(self)
+            .await
+            .map_err(communication_error)
+    }
+}
+
+#[async_trait]
+impl WatchStream for etcd_client::WatchStream {
+    async fn message(&mut self) -> Result<Option<WatchResponse>, Error> {
+        let resp = match etcd_client::WatchStream::message(self)
+            .await
+            .map_err(communication_error)?
+        {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+        let events = resp
+            .events()
+            .iter()
+            .map(|event| WatchEvent {
+                event_type: match event.event_type() {
+                    etcd_client::EventType::Put => EventType::Put,
+                    etcd_client::EventType::Delete => EventType::Delete,
+                },
+                kv: event.kv().map(KeyValue::from),
+            })
+            .collect();
+        Ok(Some(WatchResponse {
+            canceled: resp.canceled(),
+            compact_revision: resp.compact_revision(),
+            revision: resp.header().map(|h| h.revision()).unwrap_or_default(),
+            events,
+        }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    type WatchSender = mpsc::UnboundedSender<Result<WatchResponse, Error>>;
+
+    #[derive(Default)]
+    struct State {
+        // The values and the lease they are attached to, by key.
+        kvs: BTreeMap<String, (Vec<u8>, Option<i64>)>,
+        // The TTL of the leases that were not revoked, by id.
+        leases: BTreeMap<i64, i64>,
+        next_lease_id: i64,
+        // The revision of the last change, and the changes that were not compacted, so
+        // that watches can start from a past revision.
+        revision: i64,
+        history: Vec<(i64, WatchEvent)>,
+        compact_revision: i64,
+        watches: Vec<(String, WatchSender)>,
+        // Whether new watches are rejected, like when etcd is unreachable.
+        reject_watches: bool,
+        // How many times keys were fetched, so tests can check when etcd is called.
+        gets: usize,
+    }
+
+    impl State {
+        fn change(&mut self, event_type: EventType, key: &str, value: Vec<u8>) {
+            self.revision += 1;
+            let event = WatchEvent {
+                event_type,
+                kv: Some(KeyValue {
+                    key: key.as_bytes().to_vec(),
+                    value,
+                }),
+            };
+            self.history.push((self.revision, event.clone()));
+
+            let response = WatchResponse {
+                revision: self.revision,
+                events: vec![event],
+                ..Default::default()
+            };
+            // Watches whose stream was dropped are forgotten.
+            self.watches.retain(|(prefix, sender)| {
+                !key.starts_with(prefix.as_str()) || sender.send(Ok(response.clone())).is_ok()
+            });
+        }
+    }
+
+    // An in-memory etcd, for testing the discovery without an etcd server. Clones share the
+    // same keys and leases, so a test can keep one to act as the other servers of the
+    // cluster.
+    #[derive(Clone, Default)]
+    pub(crate) struct MemoryEtcd {
+        state: Arc<Mutex<State>>,
+    }
+
+    impl MemoryEtcd {
+        pub(crate) fn value(&self, key: &str) -> Option<Vec<u8>> {
+            let state = self.state.lock().unwrap();
+            state.kvs.get(key).map(|(value, _)| value.clone())
+        }
+
+        pub(crate) fn lease_of(&self, key: &str) -> Option<i64> {
+            let state = self.state.lock().unwrap();
+            state.kvs.get(key).and_then(|(_, lease_id)| *lease_id)
+        }
+
+        pub(crate) fn leases(&self) -> Vec<i64> {
+            let state = self.state.lock().unwrap();
+            state.leases.keys().cloned().collect()
+        }
+
+        pub(crate) fn delete(&self, key: &str) -> bool {
+            let mut state = self.state.lock().unwrap();
+            if state.kvs.remove(key).is_none() {
+                return false;
+            }
+            state.change(EventType::Delete, key, vec![]);
+            true
+        }
+
+        // Fails all watches, like etcd does when the connection is lost.
+        pub(crate) fn fail_watches(&self) {
+            let mut state = self.state.lock().unwrap();
+            for (_, sender) in state.watches.drain(..) {
+                let _ = sender.send(Err(Error::ClusterCommunication(
+                    "watch stream failed".to_owned(),
+                )));
+            }
+        }
+
+        pub(crate) fn gets(&self) -> usize {
+            self.state.lock().unwrap().gets
+        }
+
+        pub(crate) fn reject_watches(&self, reject: bool) {
+            self.state.lock().unwrap().reject_watches = reject;
+        }
+
+        // Forgets the history of changes, so that watches can only start from the current
+        // revision.
+        pub(crate) fn compact(&self) {
+            let mut state = self.state.lock().unwrap();
+            state.history.clear();
+            state.compact_revision = state.revision + 1;
+        }
+    }
+
+    #[async_trait]
+    impl EtcdApi for MemoryEtcd {
+        type Renewer = MemoryRenewer;
+        type Watcher = MemoryWatcher;
+        type WatchStream = MemoryWatchStream;
+
+        async fn get(&mut self, prefix: String) -> Result<GetResponse, Error> {
+            let mut state = self.state.lock().unwrap();
+            state.gets += 1;
+            let kvs = state
+                .kvs
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .map(|(key, (value, _))| KeyValue {
+                    key: key.as_bytes().to_vec(),
+                    value: value.clone(),
+                })
+                .collect();
+            Ok(GetResponse {
+                kvs,
+                revision: state.revision,
+                more: false,
+            })
+        }
+
+        // Keys are always read as they are now, since past revisions are not kept.
+        async fn get_page(
+            &mut self,
+            prefix: String,
+            from_key: Vec<u8>,
+            limit: i64,
+            _revision: Option<i64>,
+        ) -> Result<GetResponse, Error> {
+            let mut state = self.state.lock().unwrap();
+            state.gets += 1;
+            let from_key = String::from_utf8(from_key)
+                .map_err(|e| Error::ClusterCommunication(e.to_string()))?;
+            let mut kvs: Vec<_> = state
+                .kvs
+                .range(from_key..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .take(limit as usize + 1)
+                .map(|(key, (value, _))| KeyValue {
+                    key: key.as_bytes().to_vec(),
+                    value: value.clone(),
+                })
+                .collect();
+            let more = kvs.len() > limit as usize;
+            kvs.truncate(limit as usize);
+            Ok(GetResponse {
+                kvs,
+                revision: state.revision,
+                more,
+            })
+        }
+
+        async fn put(
+            &mut self,
+            key: String,
+            value: Vec<u8>,
+            lease_id: Option<i64>,
+        ) -> Result<(), Error> {
+            let mut state = self.state.lock().unwrap();
+            if let Some(id) = lease_id {
+                if !state.leases.contains_key(&id) {
+                    return Err(Error::ClusterCommunication(format!(
+                        "lease {} not found",
+                        id
+                    )));
+                }
+            }
+            state.kvs.insert(key.clone(), (value.clone(), lease_id));
+            state.change(EventType::Put, &key, value);
+            Ok(())
+        }
+
+        async fn delete(&mut self, key: String) -> Result<bool, Error> {
+            Ok(MemoryEtcd::delete(self, &key))
+        }
+
+        async fn lease_grant(&mut self, ttl: i64) -> Result<i64, Error> {
+            let mut state = self.state.lock().unwrap();
+            state.next_lease_id += 1;
+            let id = state.next_lease_id;
+            state.leases.insert(id, ttl);
+            Ok(id)
+        }
+
+        async fn lease_keep_alive(&mut self, lease_id: i64) -> Result<Self::Renewer, Error> {
+            Ok(MemoryRenewer {
+                state: self.state.clone(),
+                lease_id,
+            })
+        }
+
+        async fn lease_revoke(&mut self, lease_id: i64) -> Result<(), Error> {
+            let mut state = self.state.lock().unwrap();
+            if state.leases.remove(&lease_id).is_none() {
+                return Err(Error::ClusterCommunication(format!(
+                    "lease {} not found",
+                    lease_id
+                )));
+            }
+            let keys: Vec<String> = state
+                .kvs
+                .iter()
+                .filter(|(_, (_, id))| *id == Some(lease_id))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                state.kvs.remove(&key);
+                state.change(EventType::Delete, &key, vec![]);
+            }
+            Ok(())
+        }
+
+        async fn watch(
+            &mut self,
+            prefix: String,
+            start_revision: Option<i64>,
+        ) -> Result<(Self::Watcher, Self::WatchStream), Error> {
+            let mut state = self.state.lock().unwrap();
+            if state.reject_watches {
+                return Err(Error::ClusterCommunication(
+                    "etcd is unreachable".to_owned(),
+                ));
+            }
+
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let watcher = MemoryWatcher {
+                sender: sender.clone(),
+            };
+            let stream = MemoryWatchStream { receiver };
+            if let Some(start_revision) = start_revision {
+                if start_revision < state.compact_revision {
+                    let _ = sender.send(Ok(WatchResponse {
+                        canceled: true,
+                        compact_revision: state.compact_revision,
+                        revision: state.revision,
+                        events: vec![],
+                    }));
+                    return Ok((watcher, stream));
+                }
+                for (revision, event) in &state.history {
+                    let key = event.kv.as_ref().map(|kv| kv.key.as_slice());
+                    if *revision >= start_revision
+                        && key.map_or(false, |key| key.starts_with(prefix.as_bytes()))
+                    {
+                        let _ = sender.send(Ok(WatchResponse {
+                            revision: *revision,
+                            events: vec![event.clone()],
+                            ..Default::default()
+                        }));
+                    }
+                }
+            }
+            state.watches.push((prefix, sender));
+            Ok((watcher, stream))
+        }
+    }
+
+    pub(crate) struct MemoryRenewer {
+        state: Arc<Mutex<State>>,
+        lease_id: i64,
+    }
+
+    #[async_trait]
+    impl LeaseRenewer for MemoryRenewer {
+        async fn renew(&mut self) -> Result<Option<Duration>, Error> {
+            let state = self.state.lock().unwrap();
+            match state.leases.get(&self.lease_id) {
+                Some(ttl) => Ok(Some(Duration::from_secs(*ttl as u64))),
+                None => Err(Error::LostConnection(format!(
+                    "lease {} has expired",
+                    self.lease_id
+                ))),
+            }
+        }
+
+        async fn reconnect(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    pub(crate) struct MemoryWatcher {
+        sender: WatchSender,
+    }
+
+    #[async_trait]
+    impl Watcher for MemoryWatcher {
+        async fn cancel(&mut self) -> Result<(), Error> {
+            let _ = self.sender.send(Ok(WatchResponse {
+                canceled: true,
+                ..Default::default()
+            }));
+            Ok(())
+        }
+    }
+
+    pub(crate) struct MemoryWatchStream {
+        receiver: mpsc::UnboundedReceiver<Result<WatchResponse, Error>>,
+    }
+
+    #[async_trait]
+    impl WatchStream for MemoryWatchStream {
+        async fn message(&mut self) -> Result<Option<WatchResponse>, Error> {
+            self.receiver.recv().await.transpose()
+        }
+    }
+
+    fn event_types(responses: Vec<WatchResponse>) -> Vec<EventType> {
+        responses
+            .into_iter()
+            .flat_map(|resp| resp.events)
+            .map(|event| event.event_type)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn memory_etcd_gets_keys_by_prefix() -> Result<(), Error> {
+        let mut etcd = MemoryEtcd::default();
+        for key in &[
+            "pitaya/servers/room/a",
+            "pitaya/servers/room/b",
+            "pitaya/servers/roomy/c",
+        ] {
+            etcd.put(key.to_string(), b"value".to_vec(), None).await?;
+        }
+
+        let resp = etcd.get("pitaya/servers/room/".to_owned()).await?;
+        let keys: Vec<&str> = resp.kvs.iter().map(|kv| kv.key_str().unwrap()).collect();
+        assert_eq!(keys, vec!["pitaya/servers/room/a", "pitaya/servers/room/b"]);
+        assert_eq!(resp.revision, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_range_end_is_after_every_key_with_the_prefix() {
+        assert_eq!(
+            prefix_range_end("pitaya/servers/"),
+            b"pitaya/servers0".to_vec()
+        );
+        assert_eq!(prefix_range_end("a\u{7f}"), b"a\x80".to_vec());
+        assert_eq!(prefix_range_end(""), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn memory_etcd_revoking_lease_deletes_and_notifies() -> Result<(), Error> {
+        let mut etcd = MemoryEtcd::default();
+        let (_watcher, mut stream) = etcd.watch("pitaya/".to_owned(), None).await?;
+        let lease_id = etcd.lease_grant(60).await?;
+        etcd.put("pitaya/key".to_owned(), b"value".to_vec(), Some(lease_id))
+            .await?;
+        etcd.lease_revoke(lease_id).await?;
+
+        assert_eq!(etcd.value("pitaya/key"), None);
+        let responses = vec![
+            stream.message().await?.unwrap(),
+            stream.message().await?.unwrap(),
+        ];
+        assert_eq!(
+            event_types(responses),
+            vec![EventType::Put, EventType::Delete]
+        );
+
+        let mut renewer = etcd.lease_keep_alive(lease_id).await?;
+        assert!(matches!(
+            renewer.renew().await,
+            Err(Error::LostConnection(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_etcd_watches_from_past_revisions() -> Result<(), Error> {
+        let mut etcd = MemoryEtcd::default();
+        etcd.put("pitaya/key".to_owned(), b"value".to_vec(), None)
+            .await?;
+        etcd.delete("pitaya/key");
+
+        let (_watcher, mut stream) = etcd.watch("pitaya/".to_owned(), Some(1)).await?;
+        let responses = vec![
+            stream.message().await?.unwrap(),
+            stream.message().await?.unwrap(),
+        ];
+        assert_eq!(
+            event_types(responses),
+            vec![EventType::Put, EventType::Delete]
+        );
+
+        etcd.compact();
+        let (_watcher, mut stream) = etcd.watch("pitaya/".to_owned(), Some(1)).await?;
+        let resp = stream.message().await?.unwrap();
+        assert!(resp.canceled);
+        assert_eq!(resp.compact_revision, 3);
+        Ok(())
+    }
+}
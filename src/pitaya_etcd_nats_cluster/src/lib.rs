@@ -1,10 +1,17 @@
 mod constants;
 mod discovery;
+mod etcd_api;
+mod nats_options;
+mod rate_limiter;
 mod rpc_client;
 mod rpc_server;
+mod session_service;
 pub mod settings;
 mod tasks;
+mod topic_resolver;
 
 pub use discovery::EtcdLazy;
-pub use rpc_client::NatsRpcClient;
-pub use rpc_server::NatsRpcServer;
+pub use rpc_client::{NatsRpcClient, RetryPolicy};
+pub use rpc_server::{ConnectionState, NatsRpcServer, RpcTopic};
+pub use session_service::SessionService;
+pub use topic_resolver::{DefaultTopicResolver, TopicResolver};
@@ -0,0 +1,239 @@
+use crate::settings;
+use pitaya_core::cluster::{Error, ServerInfo};
+use slog::warn;
+use std::path::Path;
+
+// The authentication method used for connecting to NATS.
+#[derive(Debug, PartialEq)]
+enum Auth<'a> {
+    None,
+    UserPass(&'a str, &'a str),
+    Token(&'a str),
+}
+
+fn auth(settings: &settings::Nats) -> Auth {
+    if !settings.auth_token.is_empty() {
+        Auth::Token(&settings.auth_token)
+    } else if !settings.auth_user.is_empty() {
+        Auth::UserPass(&settings.auth_user, &settings.auth_pass)
+    } else {
+        Auth::None
+    }
+}
+
+// Builds the options used for connecting to NATS from the settings.
+pub(crate) fn connection_options(
+    logger: &slog::Logger,
+    settings: &settings::Nats,
+    server: &ServerInfo,
+) -> Result<nats::Options, Error> {
+    validate_tls(&settings.tls)?;
+
+    let options = match auth(settings) {
+        Auth::Token(token) => {
+            if !settings.auth_user.is_empty() {
+                warn!(
+                    logger,
+                    "both nats token and user were provided, using the token"
+                );
+            }
+            nats::Options::with_token(token)
+        }
+        Auth::UserPass(user, pass) => nats::Options::with_user_pass(user, pass),
+        Auth::None => nats::Options::new(),
+    };
+
+    let mut options = options
+        .with_name(&connection_name(settings, server))
+        .max_reconnects(max_reconnects(settings.max_reconnection_attempts))
+        .tls_required(settings.tls.required);
+
+    if !settings.tls.ca_cert.is_empty() {
+        options = options.add_root_certificate(&settings.tls.ca_cert);
+    }
+    if !settings.tls.client_cert.is_empty() {
+        options = options.client_cert(&settings.tls.client_cert, &settings.tls.client_key);
+    }
+
+    Ok(options)
+}
+
+// The name of the connection shown by NATS, which identifies the server, unless a name is
+// configured.
+fn connection_name(settings: &settings::Nats, server: &ServerInfo) -> String {
+    if settings.connection_name.is_empty() {
+        format!("pitaya-{}-{}", server.kind, server.id)
+    } else {
+        settings.connection_name.clone()
+    }
+}
+
+// Zero reconnection attempts means that the client will try to reconnect forever.
+fn max_reconnects(max_reconnection_attempts: u32) -> Option<usize> {
+    if max_reconnection_attempts == 0 {
+        None
+    } else {
+        Some(max_reconnection_attempts as usize)
+    }
+}
+
+// Makes sure that the configured certificate files exist, so that a misconfiguration
+// is reported when starting instead of failing when connecting.
+fn validate_tls(tls: &settings::NatsTls) -> Result<(), Error> {
+    if tls.client_cert.is_empty() != tls.client_key.is_empty() {
+        return Err(Error::InvalidSettings(
+            "nats tls client cert and key should be provided together".to_owned(),
+        ));
+    }
+
+    for path in &[&tls.ca_cert, &tls.client_cert, &tls.client_key] {
+        if !path.is_empty() && !Path::new(path).is_file() {
+            return Err(Error::InvalidSettings(format!(
+                "nats tls file {} does not exist",
+                path
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitaya_core::cluster::{ServerId, ServerKind};
+    use std::collections::HashMap;
+
+    fn server() -> ServerInfo {
+        ServerInfo {
+            id: ServerId::from("4e3b2c1a"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        }
+    }
+
+    fn temp_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, b"not really a certificate").unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn options_can_be_built_without_tls() {
+        assert!(connection_options(
+            &test_helpers::get_root_logger(),
+            &Default::default(),
+            &server()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn connection_name_identifies_the_server() {
+        assert_eq!(
+            connection_name(&Default::default(), &server()),
+            "pitaya-room-4e3b2c1a"
+        );
+
+        let settings = settings::Nats {
+            connection_name: "custom-name".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(connection_name(&settings, &server()), "custom-name");
+    }
+
+    #[test]
+    fn zero_reconnection_attempts_reconnect_forever() {
+        assert_eq!(max_reconnects(0), None);
+        assert_eq!(max_reconnects(1), Some(1));
+        assert_eq!(max_reconnects(5), Some(5));
+    }
+
+    #[test]
+    fn auth_is_disabled_by_default() {
+        assert_eq!(auth(&Default::default()), Auth::None);
+    }
+
+    #[test]
+    fn auth_can_use_user_and_password() {
+        let settings = settings::Nats {
+            auth_user: "user".to_owned(),
+            auth_pass: "secret".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(auth(&settings), Auth::UserPass("user", "secret"));
+    }
+
+    #[test]
+    fn auth_token_takes_precedence() {
+        let settings = settings::Nats {
+            auth_user: "user".to_owned(),
+            auth_pass: "secret".to_owned(),
+            auth_token: "token".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(auth(&settings), Auth::Token("token"));
+    }
+
+    #[test]
+    fn settings_do_not_show_secrets() {
+        let settings = settings::Nats {
+            auth_user: "user".to_owned(),
+            auth_pass: "secret-pass".to_owned(),
+            auth_token: "secret-token".to_owned(),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", settings);
+        assert!(debug.contains("user"));
+        assert!(!debug.contains("secret-pass"));
+        assert!(!debug.contains("secret-token"));
+    }
+
+    #[test]
+    fn options_can_be_built_with_tls() {
+        let settings = settings::Nats {
+            tls: settings::NatsTls {
+                required: true,
+                ca_cert: temp_file("pitaya-nats-ca.pem"),
+                client_cert: temp_file("pitaya-nats-cert.pem"),
+                client_key: temp_file("pitaya-nats-key.pem"),
+            },
+            ..Default::default()
+        };
+        assert!(validate_tls(&settings.tls).is_ok());
+        assert!(connection_options(&test_helpers::get_root_logger(), &settings, &server()).is_ok());
+    }
+
+    #[test]
+    fn missing_tls_files_are_rejected() {
+        let settings = settings::Nats {
+            tls: settings::NatsTls {
+                required: true,
+                ca_cert: "/this/file/does/not/exist.pem".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            connection_options(&test_helpers::get_root_logger(), &settings, &server()),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn client_cert_requires_key() {
+        let settings = settings::Nats {
+            tls: settings::NatsTls {
+                client_cert: temp_file("pitaya-nats-lonely-cert.pem"),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            connection_options(&test_helpers::get_root_logger(), &settings, &server()),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+}
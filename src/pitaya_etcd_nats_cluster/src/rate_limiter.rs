@@ -0,0 +1,144 @@
+use crate::settings::RouteRateLimit;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// A token bucket, implemented as the generic cell rate algorithm so that its whole state
+// is a single atomic: the time at which the bucket would be full again. Acquiring a token
+// moves that time forward by the interval between tokens, and fails if it would end up
+// more than the burst ahead of now. RPCs are never blocked waiting for a lock.
+pub(crate) struct RateLimiter {
+    start: Instant,
+    // Nanoseconds between tokens.
+    interval: u64,
+    // How far ahead of now, in nanoseconds, the bucket can be before acquiring fails.
+    tolerance: u64,
+    // Nanoseconds since `start` at which the bucket is full again.
+    full_at: AtomicU64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: f64, burst: u32) -> Self {
+        let interval = Duration::from_secs_f64(1.0 / rate).as_nanos() as u64;
+        Self {
+            start: Instant::now(),
+            interval,
+            tolerance: interval.saturating_mul(u64::from(burst)),
+            full_at: AtomicU64::new(0),
+        }
+    }
+
+    // Takes a token from the bucket, returning false if it is empty.
+    pub(crate) fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let now = now.saturating_duration_since(self.start).as_nanos() as u64;
+        let mut full_at = self.full_at.load(Ordering::Relaxed);
+        loop {
+            let new_full_at = full_at.max(now) + self.interval;
+            if new_full_at - now > self.tolerance {
+                return false;
+            }
+            match self.full_at.compare_exchange_weak(
+                full_at,
+                new_full_at,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => full_at = current,
+            }
+        }
+    }
+}
+
+// The rate limiters of the routes configured in the settings. They are created when the
+// server starts and never change, so finding the limiter of a route needs no lock.
+pub(crate) struct RouteRateLimiters {
+    limiters: Vec<(String, RateLimiter)>,
+}
+
+impl RouteRateLimiters {
+    pub(crate) fn new(limits: &[RouteRateLimit]) -> Self {
+        Self {
+            limiters: limits
+                .iter()
+                .map(|limit| {
+                    (
+                        limit.route.clone(),
+                        RateLimiter::new(limit.rate, limit.burst),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    // Returns the limiter of the first configured route that is the given route or one of
+    // its prefixes, like `room.room` for `room.room.join`.
+    pub(crate) fn limiter_for(&self, route: &str) -> Option<&RateLimiter> {
+        self.limiters
+            .iter()
+            .find(|(prefix, _)| {
+                route == prefix
+                    || (route.starts_with(prefix.as_str())
+                        && route[prefix.len()..].starts_with('.'))
+            })
+            .map(|(_, limiter)| limiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_are_allowed_up_to_the_limit() {
+        let limiter = RateLimiter::new(10.0, 3);
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(!limiter.try_acquire_at(now));
+    }
+
+    #[test]
+    fn tokens_are_refilled_at_the_rate() {
+        let limiter = RateLimiter::new(10.0, 1);
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at(now));
+        assert!(!limiter.try_acquire_at(now + Duration::from_millis(50)));
+        assert!(limiter.try_acquire_at(now + Duration::from_millis(100)));
+        assert!(!limiter.try_acquire_at(now + Duration::from_millis(150)));
+
+        // Tokens do not pile up above the burst while the limiter is idle.
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+    }
+
+    #[test]
+    fn limiters_are_found_by_route_prefix() {
+        let limiters = RouteRateLimiters::new(&[
+            RouteRateLimit {
+                route: "room.room.join".to_owned(),
+                rate: 1.0,
+                burst: 1,
+            },
+            RouteRateLimit {
+                route: "room.room".to_owned(),
+                rate: 1.0,
+                burst: 1,
+            },
+        ]);
+        let join = limiters.limiter_for("room.room.join").unwrap();
+        let room = limiters.limiter_for("room.room.leave").unwrap();
+        assert!(!std::ptr::eq(join, room));
+        assert!(std::ptr::eq(
+            limiters.limiter_for("room.room").unwrap(),
+            room
+        ));
+        assert!(limiters.limiter_for("room.roomy.join").is_none());
+        assert!(limiters.limiter_for("lobby.room.join").is_none());
+    }
+}
@@ -1,17 +1,56 @@
-use crate::settings;
+use crate::{nats_options, settings, DefaultTopicResolver, TopicResolver};
 use async_trait::async_trait;
-use nats::{self, asynk};
+use nats::asynk;
 use pitaya_core::{
-    cluster::{Error, RpcClient, ServerId, ServerInfo, ServerKind},
-    context, message, metrics, protos, utils,
+    cluster::{Discovery, Error, RpcClient, ServerId, ServerInfo, ServerKind},
+    compression, context, encoding, message, metrics, protos, utils, Route,
 };
 use prost::Message;
-use slog::{info, trace};
-use std::{io, sync::Arc, time::Instant};
-use tokio::{sync::RwLock, time::timeout};
+use slog::{debug, info, trace, warn};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Mutex, RwLock},
+    time::timeout,
+};
 
 const CLIENT_LATENCY_METRIC: &str = "rpc_client_latency";
 
+// Defines how many times `NatsRpcClient::call_with_retry` attempts an RPC and how long it
+// waits between attempts.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    // Time waited after a failed attempt before trying again.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+// Returns whether an error could have been caused by a transient transport or connection
+// failure, in which case the RPC can be attempted again.
+fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::Timeout
+        | Error::Nats(_)
+        | Error::NatsConnectionNotOpen
+        | Error::Connection(_)
+        | Error::LostConnection(_)
+        | Error::ClusterCommunication(_) => true,
+        _ => false,
+    }
+}
+
 pub struct NatsRpcClient {
     settings: settings::Nats,
     connection: Arc<RwLock<Option<asynk::Connection>>>,
@@ -19,6 +58,8 @@ pub struct NatsRpcClient {
     server_info: Arc<ServerInfo>,
     reporter: metrics::ThreadSafeReporter,
     runtime_handle: tokio::runtime::Handle,
+    topic_resolver: Arc<dyn TopicResolver>,
+    request_id_generator: fn() -> String,
 }
 
 impl NatsRpcClient {
@@ -36,68 +77,211 @@ impl NatsRpcClient {
             server_info,
             reporter,
             runtime_handle,
+            topic_resolver: Arc::new(DefaultTopicResolver),
+            request_id_generator: context::new_request_id,
         }
     }
 
-    async fn register_metrics(&self) {
-        self.reporter
-            .write()
-            .await
-            .register_histogram(metrics::Opts {
-                kind: metrics::MetricKind::Histogram,
-                namespace: String::from("pitaya"),
-                subsystem: String::from("rpc"),
-                name: String::from(CLIENT_LATENCY_METRIC),
-                help: String::from("histogram of client rpc latency in seconds"),
-                variable_labels: vec!["status".to_string()],
-                buckets: Some(metrics::exponential_buckets(0.0005, 2.0, 20)),
-            })
-            .expect("should not fail to register");
+    // Specifies the resolver for the topics where RPCs, kicks and pushes are sent.
+    pub fn with_topic_resolver(mut self, topic_resolver: Arc<dyn TopicResolver>) -> Self {
+        self.topic_resolver = topic_resolver;
+        self
     }
-}
 
-#[async_trait]
-impl RpcClient for NatsRpcClient {
-    async fn start(&self) -> Result<(), Error> {
-        if self.connection.read().await.is_some() {
-            return Err(Error::AlreadyConnected);
+    // Specifies how the ids of requests without one in their context are generated. Random
+    // ids are used by default.
+    pub fn with_request_id_generator(mut self, request_id_generator: fn() -> String) -> Self {
+        self.request_id_generator = request_id_generator;
+        self
+    }
+
+    // Calls an RPC like `call`, but attempts it again according to the given policy when it
+    // fails with a transport or connection error. Errors returned by the remote server in the
+    // response are never retried. If a discovery is given, the target is resolved again
+    // before each new attempt, falling back to another server of the same kind when the
+    // original one is gone.
+    pub async fn call_with_retry(
+        &self,
+        ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        mut target: Arc<ServerInfo>,
+        policy: &RetryPolicy,
+        discovery: Option<&Mutex<Box<dyn Discovery>>>,
+    ) -> Result<protos::Response, Error> {
+        let max_attempts = policy.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let err = match self
+                .call(ctx.clone(), rpc_type, msg.clone(), target.clone())
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+
+            let deadline_exceeded = ctx.remaining() == Some(Duration::from_secs(0));
+            if attempt >= max_attempts || !is_retryable(&err) || deadline_exceeded {
+                return Err(err);
+            }
+
+            warn!(
+                self.logger, "rpc failed, retrying";
+                "route" => &msg.route,
+                "server_id" => &target.id.0,
+                "attempt" => attempt,
+                "error" => %err,
+            );
+            tokio::time::delay_for(policy.backoff).await;
+            attempt += 1;
+
+            if let Some(discovery) = discovery {
+                match Self::resolve_target(discovery, &target).await {
+                    Some(server) => target = server,
+                    None => return Err(err),
+                }
+            }
         }
+    }
 
-        self.register_metrics().await;
+    // Calls an RPC like `call`, but blocks the current thread until it finishes, for
+    // callers that cannot be async. The RPC runs on the runtime given to `new`. Blocking a
+    // thread of an async runtime could deadlock it, so calling this from one fails with
+    // `Error::BlockingCallInRuntime`.
+    pub fn call_blocking(
+        &self,
+        ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        target: Arc<ServerInfo>,
+    ) -> Result<protos::Response, Error> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(Error::BlockingCallInRuntime);
+        }
+        self.runtime_handle
+            .block_on(self.call(ctx, rpc_type, msg, target))
+    }
 
-        info!(self.logger, "client connecting to nats"; "url" => &self.settings.url);
-        let nc = nats::Options::with_user_pass(&self.settings.auth_user, &self.settings.auth_pass)
-            .max_reconnects(Some(self.settings.max_reconnection_attempts as usize))
-            .connect_async(&self.settings.url)
+    // Flushes the NATS connection, failing if the server does not acknowledge it within the
+    // given timeout. This can be used to check if the connection is healthy.
+    pub async fn flush_timeout(&self, duration: Duration) -> Result<(), Error> {
+        let connection = self
+            .connection
+            .read()
             .await
-            .map_err(Error::Nats)?;
-
-        self.connection.write().await.replace(nc);
+            .as_ref()
+            .cloned()
+            .ok_or(Error::NatsConnectionNotOpen)?;
+        timeout(duration, connection.flush())
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(Error::Nats)
+    }
 
-        Ok(())
+    // Returns whether the NATS connection is healthy, using the request timeout as the
+    // limit for the flush.
+    pub async fn is_connected(&self) -> bool {
+        self.flush_timeout(self.settings.request_timeout)
+            .await
+            .is_ok()
     }
 
-    async fn shutdown(&self) -> Result<(), Error> {
-        if let Some(conn) = self.connection.write().await.take() {
-            let handle = self.runtime_handle.clone();
-            // need to spawn a thread so it does not block the current runtime thread
-            let th = std::thread::spawn(move || handle.block_on(conn.close()).map_err(Error::Nats));
-            return th
-                .join()
-                .unwrap_or_else(|_| Err(Error::Internal("error joining thread".into())));
+    async fn resolve_target(
+        discovery: &Mutex<Box<dyn Discovery>>,
+        target: &ServerInfo,
+    ) -> Option<Arc<ServerInfo>> {
+        let mut discovery = discovery.lock().await;
+        if let Ok(Some(server)) = discovery.server_by_id(&target.id, Some(&target.kind)).await {
+            return Some(server);
+        }
+        match discovery.servers_by_kind(&target.kind).await {
+            Ok(servers) => utils::random_server(&servers),
+            Err(_) => None,
         }
-        Ok(())
     }
 
-    async fn call(
+    // Calls an RPC like `call`, but lets NATS choose which server of the given kind answers
+    // it, instead of targeting a specific server. Only servers using
+    // `SubscriptionMode::QueueGroup` receive it.
+    pub async fn call_any_of_kind(
         &self,
         ctx: context::Context,
         rpc_type: protos::RpcType,
         msg: message::Message,
-        target: Arc<ServerInfo>,
+        server_kind: &ServerKind,
     ) -> Result<protos::Response, Error> {
-        trace!(self.logger, "NatsRpcClient::call");
+        trace!(self.logger, "NatsRpcClient::call_any_of_kind");
+        let topic = self.topic_resolver.server_kind_topic(server_kind);
+        self.call_topic(ctx, rpc_type, msg, topic).await
+    }
+
+    // Sends a notify like `notify`, but lets NATS choose which server of the given kind
+    // receives it. Only servers using `SubscriptionMode::QueueGroup` receive it.
+    pub async fn notify_any_of_kind(
+        &self,
+        ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        server_kind: &ServerKind,
+    ) -> Result<(), Error> {
+        trace!(self.logger, "NatsRpcClient::notify_any_of_kind");
+        let topic = self.topic_resolver.server_kind_topic(server_kind);
+        self.notify_topic(ctx, rpc_type, msg, topic).await
+    }
+
+    // Returns the id of the request made with the given context, generating one if the
+    // context does not have it yet.
+    fn request_id(&self, ctx: &mut context::Context) -> String {
+        if let Some(request_id) = ctx.request_id() {
+            return request_id.to_owned();
+        }
+        let request_id = (self.request_id_generator)();
+        ctx.set_request_id(&request_id);
+        request_id
+    }
+
+    // Builds the request sent to other servers, compressing the message data if the
+    // message is marked as compressed. Servers are asked to answer in the configured
+    // response format, unless it is protobuf, which they use by default.
+    fn build_request(
+        &self,
+        mut ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+    ) -> Result<protos::Request, Error> {
+        let compressed = msg.compressed;
+        if self.settings.response_format != encoding::Format::Protobuf {
+            ctx.set_response_format(self.settings.response_format);
+        }
+        let mut req = utils::build_request(ctx, rpc_type, msg, self.server_info.clone())
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        if compressed {
+            compression::compress_request(
+                &mut req,
+                self.settings.compression_codec,
+                self.settings.compression_min_size,
+            )
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        }
+        Ok(req)
+    }
+
+    // Sends an RPC to the given topic and waits for its response.
+    async fn call_topic(
+        &self,
+        mut ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        topic: String,
+    ) -> Result<protos::Response, Error> {
+        validate_route(&msg.route)?;
         let rpc_start = Instant::now();
+        let handler_label = route_handler_label(&msg.route);
+        // The RPC should not take longer than the deadline in the context, if there is one.
+        let request_timeout = match ctx.remaining() {
+            Some(remaining) if remaining < self.settings.request_timeout => remaining,
+            _ => self.settings.request_timeout,
+        };
         let connection = self
             .connection
             .read()
@@ -106,37 +290,45 @@ impl RpcClient for NatsRpcClient {
             .cloned()
             .ok_or(Error::NatsConnectionNotOpen)?;
 
-        let req = utils::build_request(ctx, rpc_type, msg, self.server_info.clone())
-            .map_err(|e| Error::Internal(e.to_string()))?;
-        let topic = utils::topic_for_server(&target);
+        let request_id = self.request_id(&mut ctx);
+        let req = self.build_request(ctx, rpc_type, msg)?;
         let buffer = utils::encode_proto(&req);
 
         trace!(
-            self.logger,
-            "sending nats request"; "topic" => &topic, "timeout" => self.settings.request_timeout.as_secs()
+            self.logger, "sending nats request";
+            "topic" => &topic,
+            "timeout" => ?request_timeout,
+            "request_id" => &request_id,
         );
 
-        let request_timeout = self.settings.request_timeout;
-
         let res: Result<protos::Response, Error> = {
             let message = timeout(request_timeout, connection.request(&topic, buffer))
                 .await
-                .map_err(|_| Error::Nats(io::ErrorKind::TimedOut.into()))?
+                .map_err(|_| Error::Timeout)?
                 .map_err(Error::Nats)?;
 
-            let msg: protos::Response =
-                Message::decode(message.data.as_ref()).map_err(Error::InvalidServerResponse)?;
+            let msg = self
+                .settings
+                .response_format
+                .codec()
+                .decode_response(&message.data)
+                .map_err(Error::InvalidResponseEncoding)?;
             Ok(msg)
         };
 
         match res {
             Err(err) => {
+                debug!(
+                    self.logger, "rpc failed";
+                    "request_id" => &request_id,
+                    "error" => %err,
+                );
                 metrics::record_histogram_duration(
                     self.logger.clone(),
                     self.reporter.clone(),
                     CLIENT_LATENCY_METRIC,
                     rpc_start,
-                    &["failed"],
+                    &["failed", &handler_label],
                 )
                 .await;
                 Err(err)
@@ -147,7 +339,7 @@ impl RpcClient for NatsRpcClient {
                     self.reporter.clone(),
                     CLIENT_LATENCY_METRIC,
                     rpc_start,
-                    &["ok"],
+                    &["ok", &handler_label],
                 )
                 .await;
                 Ok(r)
@@ -155,6 +347,142 @@ impl RpcClient for NatsRpcClient {
         }
     }
 
+    // Publishes a notify to the given topic, without waiting for an answer.
+    async fn notify_topic(
+        &self,
+        mut ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        topic: String,
+    ) -> Result<(), Error> {
+        validate_route(&msg.route)?;
+        let connection = self
+            .connection
+            .read()
+            .await
+            .as_ref()
+            .cloned()
+            .ok_or(Error::NatsConnectionNotOpen)?;
+
+        let request_id = self.request_id(&mut ctx);
+        let req = self.build_request(ctx, rpc_type, msg)?;
+        let buffer = utils::encode_proto(&req);
+
+        trace!(
+            self.logger, "sending nats notify";
+            "topic" => &topic,
+            "request_id" => &request_id,
+        );
+
+        // Notifies are published without a reply topic, so the server does not answer them.
+        connection
+            .publish(&topic, buffer)
+            .await
+            .map_err(Error::Nats)?;
+        timeout(self.settings.request_timeout, connection.flush())
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(Error::Nats)?;
+
+        Ok(())
+    }
+
+    async fn register_metrics(&self) {
+        self.reporter
+            .write()
+            .await
+            .register_histogram(metrics::Opts {
+                kind: metrics::MetricKind::Histogram,
+                namespace: self.settings.metrics_namespace.clone(),
+                subsystem: self.settings.metrics_subsystem.clone(),
+                name: String::from(CLIENT_LATENCY_METRIC),
+                help: String::from("histogram of client rpc latency in seconds"),
+                variable_labels: vec!["status".to_string(), "handler".to_string()],
+                buckets: Some(
+                    metrics::exponential_buckets(0.0005, 2.0, 20)
+                        .expect("should have valid buckets"),
+                ),
+            })
+            .or_else(metrics::allow_already_registered)
+            .unwrap_or_else(|e| warn!(self.logger, "failed to register metric"; "error" => %e));
+    }
+}
+
+// Parses a route before sending it to another server, so that malformed routes fail
+// early instead of on the receiving server.
+fn validate_route(route: &str) -> Result<Route, Error> {
+    Route::try_from_str(route.to_owned()).ok_or_else(|| Error::InvalidRoute(route.to_owned()))
+}
+
+// Returns the server kind and handler of a route, without the method. This is used as a
+// metric label, since the full route can have a high cardinality.
+pub(crate) fn route_handler_label(route: &str) -> String {
+    match Route::try_from_str(route.to_owned()) {
+        Some(route) => match route.server_kind() {
+            Some(server_kind) => format!("{}.{}", server_kind, route.handler()),
+            None => route.handler().to_owned(),
+        },
+        None => "invalid".to_owned(),
+    }
+}
+
+#[async_trait]
+impl RpcClient for NatsRpcClient {
+    async fn start(&self) -> Result<(), Error> {
+        self.settings.validate()?;
+        if self.connection.read().await.is_some() {
+            return Err(Error::AlreadyConnected);
+        }
+
+        self.register_metrics().await;
+
+        info!(self.logger, "client connecting to nats"; "url" => &self.settings.url);
+        let nc = nats_options::connection_options(&self.logger, &self.settings, &self.server_info)?
+            .connect_async(&self.settings.url)
+            .await
+            .map_err(Error::Nats)?;
+
+        self.connection.write().await.replace(nc);
+
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> Result<(), Error> {
+        if let Some(conn) = self.connection.write().await.take() {
+            let handle = self.runtime_handle.clone();
+            // need to spawn a thread so it does not block the current runtime thread
+            let th = std::thread::spawn(move || handle.block_on(conn.close()).map_err(Error::Nats));
+            return th
+                .join()
+                .unwrap_or_else(|_| Err(Error::Internal("error joining thread".into())));
+        }
+        Ok(())
+    }
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        target: Arc<ServerInfo>,
+    ) -> Result<protos::Response, Error> {
+        trace!(self.logger, "NatsRpcClient::call");
+        let topic = self.topic_resolver.server_topic(&target);
+        self.call_topic(ctx, rpc_type, msg, topic).await
+    }
+
+    async fn notify(
+        &self,
+        ctx: context::Context,
+        rpc_type: protos::RpcType,
+        msg: message::Message,
+        target: Arc<ServerInfo>,
+    ) -> Result<(), Error> {
+        trace!(self.logger, "NatsRpcClient::notify");
+        let topic = self.topic_resolver.server_topic(&target);
+        self.notify_topic(ctx, rpc_type, msg, topic).await
+    }
+
     async fn kick_user(
         &self,
         // NOTE: Ignore server_id, since it is not necessary to create the topic.
@@ -181,12 +509,14 @@ impl RpcClient for NatsRpcClient {
 
         let request_timeout = self.settings.request_timeout;
 
-        let topic = utils::user_kick_topic(&kick_msg.user_id, &server_kind);
+        let topic = self
+            .topic_resolver
+            .user_kick_topic(&kick_msg.user_id, &server_kind);
         let kick_buffer = utils::encode_proto(&kick_msg);
 
         let message = timeout(request_timeout, connection.request(&topic, kick_buffer))
             .await
-            .map_err(|_| Error::Nats(io::ErrorKind::TimedOut.into()))?
+            .map_err(|_| Error::Timeout)?
             .map_err(Error::Nats)?;
 
         let k: protos::KickAnswer =
@@ -215,7 +545,9 @@ impl RpcClient for NatsRpcClient {
             return Err(Error::EmptyServerKind);
         }
 
-        let topic = utils::user_messages_topic(&push_msg.uid, &server_kind);
+        let topic = self
+            .topic_resolver
+            .user_messages_topic(&push_msg.uid, &server_kind);
         let push_buffer = utils::encode_proto(&push_msg);
 
         connection
@@ -230,11 +562,98 @@ impl RpcClient for NatsRpcClient {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{constants, discovery::EtcdLazy};
-    use pitaya_core::cluster::Discovery;
+    use crate::{constants, discovery::EtcdLazy, NatsRpcServer};
+    use pitaya_core::cluster::RpcServer;
     use std::collections::HashMap;
     use std::error::Error as StdError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn validate_route_parses_components() {
+        let route = validate_route("room.room.join").unwrap();
+        assert_eq!(route.server_kind(), Some("room"));
+        assert_eq!(route.handler(), "room");
+        assert_eq!(route.method(), "join");
+
+        let route = validate_route("room.join").unwrap();
+        assert_eq!(route.server_kind(), None);
+        assert_eq!(route.handler(), "room");
+        assert_eq!(route.method(), "join");
+    }
+
+    #[test]
+    fn validate_route_rejects_malformed_routes() {
+        for route in &["", "room", "room.", ".join", "room..join", "a.b.c.d"] {
+            assert!(
+                matches!(validate_route(route), Err(Error::InvalidRoute(r)) if r == *route),
+                "route {:?} should be invalid",
+                route
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn call_rejects_malformed_route() {
+        let sv = new_server();
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            Default::default(),
+            sv.clone(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+
+        // The route is validated before the connection is used, so the client does not
+        // need to be started.
+        let res = client
+            .call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                message::Message {
+                    route: "room".to_owned(),
+                    ..Default::default()
+                },
+                sv,
+            )
+            .await;
+        assert!(matches!(res, Err(Error::InvalidRoute(_))));
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_generated_for_contexts_without_one() {
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            Default::default(),
+            new_server(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        )
+        .with_request_id_generator(|| "generated".to_owned());
+
+        let mut ctx = context::Context::empty();
+        assert_eq!(client.request_id(&mut ctx), "generated");
+        assert_eq!(ctx.request_id(), Some("generated"));
+
+        let mut ctx = context::Context::empty();
+        ctx.set_request_id("existing");
+        assert_eq!(client.request_id(&mut ctx), "existing");
+    }
+
+    #[test]
+    fn route_handler_label_drops_the_method() {
+        assert_eq!(route_handler_label("room.room.join"), "room.room");
+        assert_eq!(
+            route_handler_label("connector.entry.1234"),
+            "connector.entry"
+        );
+        assert_eq!(route_handler_label("room.join"), "room");
+        assert_eq!(route_handler_label("room..join"), "invalid");
+        assert_eq!(route_handler_label("room"), "invalid");
+        assert_eq!(route_handler_label("a.b.c.d"), "invalid");
+        assert_eq!(route_handler_label(""), "invalid");
+    }
 
     fn new_server() -> Arc<ServerInfo> {
         Arc::new(ServerInfo {
@@ -277,6 +696,30 @@ mod tests {
         client.shutdown().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn health_check_follows_connection() -> Result<(), Error> {
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            Default::default(),
+            new_server(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+        assert!(!client.is_connected().await);
+
+        client.start().await?;
+        assert!(client.is_connected().await);
+        client.flush_timeout(Duration::from_secs(1)).await?;
+
+        client.shutdown().await?;
+        assert!(!client.is_connected().await);
+        match client.flush_timeout(Duration::from_secs(1)).await {
+            Err(Error::NatsConnectionNotOpen) => {}
+            _ => panic!("expected connection to be closed"),
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn nats_request_timeout() -> Result<(), Error> {
         let client = NatsRpcClient::new(
@@ -319,9 +762,7 @@ mod tests {
         let err = response.unwrap_err();
 
         match err {
-            Error::Nats(nats_err) => {
-                assert_eq!(nats_err.kind(), std::io::ErrorKind::TimedOut);
-            }
+            Error::Timeout => {}
             _ => panic!("unexpected error"),
         };
 
@@ -339,6 +780,7 @@ mod tests {
                     prefix: "pitaya".to_owned(),
                     url: constants::LOCAL_ETCD_URL.to_owned(),
                     lease_ttl: Duration::from_secs(50),
+                    ..Default::default()
                 }),
             )
             .await
@@ -402,4 +844,205 @@ mod tests {
         client.shutdown().await?;
         Ok(())
     }
+
+    fn new_message() -> message::Message {
+        message::Message {
+            kind: message::Kind::Request,
+            id: 21,
+            data: b"sending some data".to_vec(),
+            route: "room.room.join".to_owned(),
+            compressed: false,
+            err: false,
+        }
+    }
+
+    #[test]
+    fn call_blocking_works_outside_the_runtime() -> Result<(), Box<dyn StdError>> {
+        let mut runtime = tokio::runtime::Runtime::new()?;
+        let sv = Arc::new(ServerInfo {
+            id: ServerId::from("blocking-call"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        });
+
+        let rpc_server = NatsRpcServer::new(
+            test_helpers::get_root_logger(),
+            sv.clone(),
+            Default::default(),
+            runtime.handle().clone(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+        let mut rpc_server_conn = runtime.block_on(rpc_server.start(broadcast::channel(1).0))?;
+        runtime.spawn(async move {
+            let rpc = rpc_server_conn.recv().await.unwrap();
+            assert!(rpc.respond(utils::encode_proto(&protos::Response {
+                data: b"ok".to_vec(),
+                error: None,
+            })));
+        });
+
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            Default::default(),
+            sv.clone(),
+            runtime.handle().clone(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+        runtime.block_on(client.start())?;
+
+        // The test thread does not run the runtime, so it can be blocked.
+        let res = client.call_blocking(
+            context::Context::empty(),
+            protos::RpcType::User,
+            new_message(),
+            sv,
+        )?;
+        assert_eq!(res.data, b"ok");
+
+        runtime.block_on(client.shutdown())?;
+        runtime.block_on(rpc_server.shutdown())?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn call_blocking_fails_inside_the_runtime() {
+        let sv = new_server();
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            Default::default(),
+            sv.clone(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+
+        let res = client.call_blocking(
+            context::Context::empty(),
+            protos::RpcType::User,
+            new_message(),
+            sv,
+        );
+        assert!(matches!(res, Err(Error::BlockingCallInRuntime)));
+    }
+
+    #[tokio::test]
+    async fn call_with_retry_succeeds_after_transient_failure() -> Result<(), Box<dyn StdError>> {
+        let sv = Arc::new(ServerInfo {
+            id: ServerId::from("retry-transient"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        });
+
+        // The server only starts listening after the first attempts have timed out.
+        let server_info = sv.clone();
+        let server_task = tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(300)).await;
+            let rpc_server = NatsRpcServer::new(
+                test_helpers::get_root_logger(),
+                server_info,
+                Default::default(),
+                tokio::runtime::Handle::current(),
+                Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+            );
+            let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await.unwrap();
+            let rpc = rpc_server_conn.recv().await.unwrap();
+            assert!(rpc.respond(utils::encode_proto(&protos::Response {
+                data: b"ok".to_vec(),
+                error: None,
+            })));
+            rpc_server
+        });
+
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            settings::Nats {
+                request_timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+            sv.clone(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+        client.start().await?;
+
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            backoff: Duration::from_millis(50),
+        };
+        let res = client
+            .call_with_retry(
+                context::Context::empty(),
+                protos::RpcType::User,
+                new_message(),
+                sv.clone(),
+                &policy,
+                None,
+            )
+            .await?;
+        assert!(res.error.is_none());
+        assert_eq!(res.data, b"ok");
+
+        client.shutdown().await?;
+        server_task.await?.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn call_with_retry_does_not_retry_application_errors() -> Result<(), Box<dyn StdError>> {
+        let sv = Arc::new(ServerInfo {
+            id: ServerId::from("retry-app-error"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        });
+
+        let rpc_server = NatsRpcServer::new(
+            test_helpers::get_root_logger(),
+            sv.clone(),
+            Default::default(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let received = Arc::new(AtomicUsize::new(0));
+        let received_by_server = received.clone();
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                received_by_server.fetch_add(1, Ordering::SeqCst);
+                assert!(rpc.respond(utils::build_error_response("PIT-400", "bad request")));
+            }
+        });
+
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            Default::default(),
+            sv.clone(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+        client.start().await?;
+
+        let res = client
+            .call_with_retry(
+                context::Context::empty(),
+                protos::RpcType::User,
+                new_message(),
+                sv.clone(),
+                &RetryPolicy::default(),
+                None,
+            )
+            .await?;
+        assert_eq!(res.error.map(|e| e.code), Some("PIT-400".to_owned()));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
 }
@@ -1,32 +1,711 @@
-use crate::settings;
+use crate::{
+    nats_options, rate_limiter::RouteRateLimiters, rpc_client::route_handler_label, settings,
+    DefaultTopicResolver, TopicResolver,
+};
 use async_trait::async_trait;
-use futures::{future, StreamExt};
-use nats::{self, asynk};
+use futures::{
+    future::{self, Either},
+    stream::BoxStream,
+    StreamExt,
+};
+use nats::asynk;
 use pitaya_core::{
     cluster::{Error, Rpc, RpcServer, ServerInfo},
+    compression, context, encoding,
     metrics::{self},
     protos, utils,
 };
+use prost::Message;
 use slog::{debug, error, info, o, trace, warn};
-use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot, RwLock};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, RwLock, Semaphore};
 
 const RPCS_IN_FLIGHT_METRIC: &str = "rpcs_in_flight";
+const NATS_RECONNECTS_METRIC: &str = "nats_reconnects";
+const RPC_QUEUE_DEPTH_METRIC: &str = "rpc_queue_depth";
+const RPC_DROPPED_METRIC: &str = "rpc_dropped";
+const RPC_EXPIRED_METRIC: &str = "rpc_expired";
+const RPC_REQUESTS_METRIC: &str = "rpc_requests_total";
+const RPC_ERRORS_METRIC: &str = "rpc_errors_total";
+const RPC_HANDLER_DURATION_METRIC: &str = "rpc_handler_duration";
 
 struct RpcServerState {
     connection: asynk::Connection,
-    close_sender: oneshot::Sender<()>,
+    // One for each topic the server is subscribed to.
+    close_senders: Vec<oneshot::Sender<()>>,
+    reject_queued_sender: oneshot::Sender<()>,
+    forward_queued_rpcs: tokio::task::JoinHandle<()>,
+}
+
+// A topic where the server receives RPCs. Besides its own topic, a server can receive the
+// RPCs of other topics, like a gateway that fronts several services subscribing to a
+// wildcard. NATS wildcards only match whole tokens separated by dots, which the topics of
+// `DefaultTopicResolver` do not have. When a queue group is given, each RPC is received
+// by only one of the servers in the group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcTopic {
+    pub topic: String,
+    pub queue_group: Option<String>,
+}
+
+impl RpcTopic {
+    pub fn new<T: ToString>(topic: T) -> Self {
+        Self {
+            topic: topic.to_string(),
+            queue_group: None,
+        }
+    }
+
+    pub fn with_queue_group<T: ToString>(mut self, queue_group: T) -> Self {
+        self.queue_group = Some(queue_group.to_string());
+        self
+    }
+
+    async fn subscribe(
+        &self,
+        connection: &asynk::Connection,
+    ) -> std::io::Result<asynk::Subscription> {
+        match &self.queue_group {
+            Some(queue_group) => connection.queue_subscribe(&self.topic, queue_group).await,
+            None => connection.subscribe(&self.topic).await,
+        }
+    }
+}
+
+// Represents an RPC that is still being processed. The in-flight count is decremented
+// when it is dropped.
+struct InFlightRpc(Arc<AtomicUsize>);
+
+impl InFlightRpc {
+    fn new(in_flight: Arc<AtomicUsize>) -> Self {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlightRpc {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// A permit for spawning a task that answers an RPC. It is given back to the semaphore
+// when dropped.
+struct ResponderPermit(Arc<Semaphore>);
+
+impl ResponderPermit {
+    fn try_acquire(semaphore: &Arc<Semaphore>) -> Option<Self> {
+        // The permit borrows the semaphore, so it cannot be moved into the task. It is
+        // forgotten here and given back manually instead.
+        semaphore.try_acquire().ok()?.forget();
+        Some(Self(semaphore.clone()))
+    }
+}
+
+impl Drop for ResponderPermit {
+    fn drop(&mut self) {
+        self.0.add_permits(1);
+    }
+}
+
+// Handles the messages received from NATS, forwarding them as RPCs.
+#[derive(Clone)]
+struct MessageHandler {
+    logger: slog::Logger,
+    sender: mpsc::Sender<Rpc>,
+    runtime_handle: tokio::runtime::Handle,
+    connection: asynk::Connection,
+    reporter: metrics::ThreadSafeReporter,
+    in_flight: Arc<AtomicUsize>,
+    queue_depth: Arc<AtomicUsize>,
+    responder_permits: Arc<Semaphore>,
+    overload_response: Vec<u8>,
+    max_request_size: usize,
+    response_format: encoding::Format,
+    queue_full_wait: Duration,
+    flush_response_handlers: Arc<Vec<String>>,
+    flush_response_timeout: Duration,
+    rate_limiters: Arc<RouteRateLimiters>,
+}
+
+impl MessageHandler {
+    fn on_nats_message(&self, mut message: asynk::Message) -> std::io::Result<()> {
+        let logger = &self.logger;
+        debug!(logger, "received nats message"; "message" => ?message);
+
+        let mut sender = self.sender.clone();
+
+        let (responder, response_receiver) = oneshot::channel();
+
+        // Messages without a reply topic are notifies, which are handled without answering.
+        let response_topic = message.reply.take();
+        if response_topic.is_none() {
+            debug!(logger, "received notify from nats message");
+        }
+
+        // The request is not decoded if it is too large, since that could use a lot of memory.
+        if message.data.len() > self.max_request_size {
+            warn!(logger, "request is too large, dropping it";
+                "size" => message.data.len(), "max_size" => self.max_request_size);
+            self.report_dropped("too_large");
+            if let Some(response_topic) = response_topic {
+                self.respond(
+                    response_topic,
+                    self.response_format,
+                    utils::build_error_response(
+                        pitaya_core::constants::CODE_PAYLOAD_TOO_LARGE,
+                        format!(
+                            "request has {} bytes, more than the maximum of {}",
+                            message.data.len(),
+                            self.max_request_size
+                        ),
+                    ),
+                );
+            }
+            return Ok(());
+        }
+
+        let (data, metadata, full_route, rpc_type) =
+            match parse_request(std::mem::take(&mut message.data)) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!(logger, "invalid compressed request, dropping it"; "error" => %e);
+                    self.report_dropped("invalid_compression");
+                    if let Some(response_topic) = response_topic {
+                        self.respond(
+                            response_topic,
+                            self.response_format,
+                            utils::build_error_response(
+                                pitaya_core::constants::CODE_BAD_FORMAT,
+                                format!("invalid compressed request: {}", e),
+                            ),
+                        );
+                    }
+                    return Ok(());
+                }
+            };
+        let route = route_handler_label(&full_route);
+        let deadline = metadata.deadline;
+        // Requests may ask for a format other than the one configured for the server.
+        let response_format = metadata.response_format.unwrap_or(self.response_format);
+        if let Some(deadline) = deadline {
+            if deadline <= SystemTime::now() {
+                warn!(logger, "rpc deadline already exceeded, dropping request");
+                self.report_dropped("deadline_exceeded");
+                self.report_expired(&route);
+                // The caller may still be waiting if its clock is behind ours, so it is
+                // told why the RPC was not processed.
+                if let Some(response_topic) = response_topic {
+                    self.respond(
+                        response_topic,
+                        response_format,
+                        utils::build_error_response(
+                            pitaya_core::constants::CODE_DEADLINE_EXCEEDED,
+                            "rpc deadline exceeded before it was processed",
+                        ),
+                    );
+                }
+                return Ok(());
+            }
+        }
+
+        if let Some(limiter) = self.rate_limiters.limiter_for(&full_route) {
+            if !limiter.try_acquire() {
+                warn!(logger, "rpc rate limit exceeded, dropping request"; "route" => &full_route);
+                self.report_dropped("rate_limited");
+                if let Some(response_topic) = response_topic {
+                    self.respond(
+                        response_topic,
+                        response_format,
+                        utils::build_error_response(
+                            pitaya_core::constants::CODE_TOO_MANY_REQUESTS,
+                            format!("rate limit of {} exceeded", full_route),
+                        ),
+                    );
+                }
+                return Ok(());
+            }
+        }
+
+        let response_topic = match response_topic {
+            Some(topic) => topic,
+            None => {
+                self.forward_notify(data, rpc_type);
+                return Ok(());
+            }
+        };
+
+        let permit = match ResponderPermit::try_acquire(&self.responder_permits) {
+            Some(permit) => permit,
+            None => {
+                warn!(logger, "too many rpcs being answered, dropping request");
+                self.report_dropped("too_many_responders");
+                self.respond_overloaded(response_topic, response_format);
+                return Ok(());
+            }
+        };
+
+        // When the queue is full, the RPC may wait a little for room in it instead of being
+        // rejected right away. The wait happens in the spawned task, so that NATS messages
+        // keep being handled in the meantime.
+        let enqueued_at = Instant::now();
+        // The RPC is counted before being sent, since it can be taken from the queue right
+        // away, and the count is rolled back if it could not be queued.
+        let queue_depth = self.queue_depth.fetch_add(1, Ordering::SeqCst) + 1;
+        let (waiting_rpc, queue_depth) = match sender.try_send(new_rpc(data, responder, rpc_type)) {
+            Ok(_) => (None, queue_depth),
+            Err(mpsc::error::TrySendError::Full(rpc))
+                if self.queue_full_wait > Duration::from_secs(0) =>
+            {
+                decrement_queue_depth(&self.queue_depth);
+                debug!(logger, "channel is full, waiting for room in it");
+                (Some(rpc), 0)
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                decrement_queue_depth(&self.queue_depth);
+                warn!(logger, "channel is full, dropping request");
+                self.report_dropped("overloaded");
+                self.respond_overloaded(response_topic, response_format);
+                return Ok(());
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                decrement_queue_depth(&self.queue_depth);
+                warn!(logger, "rpc channel stoped being listened");
+                self.report_dropped("channel_closed");
+                return Ok(());
+            }
+        };
+
+        // For the moment we are ignoring the handle returned by the task.
+        // Worst case scenario we will have to kill the task in the middle of its processing
+        // at the end of the program.
+
+        let _ = {
+            let logger = match metadata.trace_id {
+                Some(trace_id) => logger.new(o!("trace_id" => trace_id)),
+                None => logger.clone(),
+            };
+            let logger = match metadata.request_id {
+                Some(request_id) => logger.new(o!("request_id" => request_id)),
+                None => logger,
+            };
+            let conn = self.connection.clone();
+            let reporter = self.reporter.clone();
+            let queued_rpcs = self.queue_depth.clone();
+            let queue_full_wait = self.queue_full_wait;
+            let overload_response = self.overload_response.clone();
+            let in_flight_rpc = InFlightRpc::new(self.in_flight.clone());
+            let flush_timeout = if self.flush_response_handlers.contains(&route) {
+                Some(self.flush_response_timeout)
+            } else {
+                None
+            };
+            trace!(logger, "spawning response receiver task");
+            self.runtime_handle.spawn(async move {
+                let _permit = permit;
+                let _in_flight_rpc = in_flight_rpc;
+
+                let (queue_depth, enqueued_at) = match waiting_rpc {
+                    None => (queue_depth, enqueued_at),
+                    Some(rpc) => {
+                        // The RPC is counted before being sent, since it can be taken from
+                        // the queue right away.
+                        let queue_depth = queued_rpcs.fetch_add(1, Ordering::SeqCst) + 1;
+                        let reason =
+                            match tokio::time::timeout(queue_full_wait, sender.send(rpc)).await {
+                                Ok(Ok(_)) => None,
+                                Ok(Err(_)) => Some("channel_closed"),
+                                Err(_) => Some("overloaded"),
+                            };
+                        if let Some(reason) = reason {
+                            decrement_queue_depth(&queued_rpcs);
+                            warn!(
+                                logger, "rpc could not be queued, dropping request";
+                                "reason" => reason
+                            );
+                            if reason == "overloaded" {
+                                if let Err(err) = NatsRpcServer::respond(
+                                    &conn,
+                                    &response_topic,
+                                    response_format,
+                                    overload_response,
+                                )
+                                .await
+                                {
+                                    error!(logger, "failed to respond rpc"; "error" => %err);
+                                }
+                            }
+                            metrics::inc_counter(logger, reporter, RPC_DROPPED_METRIC, &[reason])
+                                .await;
+                            return;
+                        }
+                        (queue_depth, Instant::now())
+                    }
+                };
+                report_queue_depth(&logger, &reporter, queue_depth).await;
+
+                // Nobody waits for the response after the deadline, so stop waiting for it.
+                let response = match deadline {
+                    Some(deadline) => {
+                        let remaining = deadline
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default();
+                        match tokio::time::timeout(remaining, response_receiver).await {
+                            Ok(response) => response,
+                            Err(_) => {
+                                warn!(logger, "rpc deadline exceeded, not responding");
+                                report_result(&logger, &reporter, &route, true).await;
+                                return;
+                            }
+                        }
+                    }
+                    None => response_receiver.await,
+                };
+                // Measures only the time spent by the handler, not the time to publish the
+                // response, which allows telling slow handlers apart from a slow NATS.
+                metrics::record_histogram_duration(
+                    logger.clone(),
+                    reporter.clone(),
+                    RPC_HANDLER_DURATION_METRIC,
+                    enqueued_at,
+                    &[route.as_str()],
+                )
+                .await;
+
+                let failed = match response {
+                    Ok(response) => {
+                        debug!(logger, "responding rpc");
+                        let failed = response_has_error(&response);
+                        let mut result = NatsRpcServer::respond(
+                            &conn,
+                            &response_topic,
+                            response_format,
+                            response,
+                        )
+                        .await;
+                        if let (Ok(_), Some(flush_timeout)) = (&result, flush_timeout) {
+                            result = NatsRpcServer::flush(&conn, flush_timeout).await;
+                        }
+                        if let Err(err) = result {
+                            error!(logger, "failed to respond rpc"; "error" => %err);
+                            true
+                        } else {
+                            failed
+                        }
+                    }
+                    Err(e) => {
+                        // Errors happen here if the channel was closed before sending a message.
+                        error!(logger, "failed to receive response from RPC"; "error" => %e);
+                        true
+                    }
+                };
+                report_result(&logger, &reporter, &route, failed).await;
+            })
+        };
+
+        Ok(())
+    }
+
+    // Forwards a notify as an RPC whose response is discarded.
+    fn forward_notify(&self, data: Vec<u8>, rpc_type: Option<protos::RpcType>) {
+        let (responder, _) = oneshot::channel();
+        // Like requests, notifies are counted before being sent to the queue.
+        let queue_depth = self.queue_depth.fetch_add(1, Ordering::SeqCst) + 1;
+        match self
+            .sender
+            .clone()
+            .try_send(new_rpc(data, responder, rpc_type))
+        {
+            Ok(_) => {
+                let logger = self.logger.clone();
+                let reporter = self.reporter.clone();
+                self.runtime_handle.spawn(async move {
+                    report_queue_depth(&logger, &reporter, queue_depth).await;
+                });
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                decrement_queue_depth(&self.queue_depth);
+                warn!(self.logger, "channel is full, dropping notify");
+                self.report_dropped("overloaded");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                decrement_queue_depth(&self.queue_depth);
+                warn!(self.logger, "rpc channel stoped being listened");
+                self.report_dropped("channel_closed");
+            }
+        }
+    }
+
+    fn respond_overloaded(&self, response_topic: String, format: encoding::Format) {
+        self.respond(response_topic, format, self.overload_response.clone());
+    }
+
+    fn respond(&self, response_topic: String, format: encoding::Format, response: Vec<u8>) {
+        let logger = self.logger.clone();
+        let conn = self.connection.clone();
+        let _ = self.runtime_handle.spawn(async move {
+            if let Err(err) = NatsRpcServer::respond(&conn, &response_topic, format, response).await
+            {
+                error!(logger, "failed to respond rpc"; "error" => %err);
+            }
+        });
+    }
+
+    fn report_dropped(&self, reason: &'static str) {
+        let logger = self.logger.clone();
+        let reporter = self.reporter.clone();
+        self.runtime_handle.spawn(async move {
+            metrics::inc_counter(logger, reporter, RPC_DROPPED_METRIC, &[reason]).await;
+        });
+    }
+
+    fn report_expired(&self, route: &str) {
+        let logger = self.logger.clone();
+        let reporter = self.reporter.clone();
+        let route = route.to_owned();
+        self.runtime_handle.spawn(async move {
+            metrics::inc_counter(logger, reporter, RPC_EXPIRED_METRIC, &[&route]).await;
+        });
+    }
+}
+
+// Returns the data of a request, its metadata and its route. The data of compressed
+// requests is decompressed. Requests that cannot be decoded are returned as they are,
+// with empty metadata and route.
+fn parse_request(
+    data: Vec<u8>,
+) -> Result<
+    (
+        Vec<u8>,
+        context::RequestMetadata,
+        String,
+        Option<protos::RpcType>,
+    ),
+    compression::Error,
+> {
+    let mut req = match protos::Request::decode(data.as_slice()) {
+        Ok(req) => req,
+        Err(_) => return Ok((data, Default::default(), String::new(), None)),
+    };
+
+    let data = if compression::decompress_request(&mut req)? {
+        utils::encode_proto(&req)
+    } else {
+        data
+    };
+    let route = req
+        .msg
+        .as_ref()
+        .map(|msg| msg.route.clone())
+        .unwrap_or_default();
+    Ok((
+        data,
+        context::RequestMetadata::parse(&req.metadata),
+        route,
+        protos::RpcType::from_i32(req.r#type),
+    ))
+}
+
+fn new_rpc(
+    data: Vec<u8>,
+    responder: oneshot::Sender<Vec<u8>>,
+    rpc_type: Option<protos::RpcType>,
+) -> Rpc {
+    let rpc = Rpc::new(data, responder);
+    match rpc_type {
+        Some(rpc_type) => rpc.with_rpc_type(rpc_type),
+        None => rpc,
+    }
+}
+
+fn response_has_error(response: &[u8]) -> bool {
+    protos::Response::decode(response)
+        .map(|res| res.error.is_some())
+        .unwrap_or(false)
+}
+
+// Counts an answered RPC for its route, and also counts it as an error if it failed.
+async fn report_result(
+    logger: &slog::Logger,
+    reporter: &metrics::ThreadSafeReporter,
+    route: &str,
+    failed: bool,
+) {
+    metrics::inc_counter(
+        logger.clone(),
+        reporter.clone(),
+        RPC_REQUESTS_METRIC,
+        &[route],
+    )
+    .await;
+    if failed {
+        metrics::inc_counter(
+            logger.clone(),
+            reporter.clone(),
+            RPC_ERRORS_METRIC,
+            &[route],
+        )
+        .await;
+    }
+}
+
+// Uncounts an RPC that left the queue, returning how many are still in it. The count never
+// goes below zero, even if an RPC is taken from the queue before being counted.
+fn decrement_queue_depth(queue_depth: &AtomicUsize) -> usize {
+    let previous = queue_depth
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |depth| {
+            Some(depth.saturating_sub(1))
+        })
+        .unwrap_or_else(|depth| depth);
+    previous.saturating_sub(1)
+}
+
+async fn report_queue_depth(
+    logger: &slog::Logger,
+    reporter: &metrics::ThreadSafeReporter,
+    queue_depth: usize,
+) {
+    metrics::set_gauge(
+        logger.clone(),
+        reporter.clone(),
+        RPC_QUEUE_DEPTH_METRIC,
+        queue_depth as f64,
+        &[],
+    )
+    .await;
+}
+
+// Forwards the queued RPCs to the receiver returned by `start`, keeping track of how
+// many RPCs are still waiting in the queue. System RPCs are forwarded to their own sender
+// instead, if one is given. When the server shuts down, or a receiver is dropped, the RPCs
+// left in the queue are answered with an error instead of being lost.
+async fn forward_queued_rpcs(
+    logger: slog::Logger,
+    reporter: metrics::ThreadSafeReporter,
+    queue_depth: Arc<AtomicUsize>,
+    mut queued_receiver: mpsc::Receiver<Rpc>,
+    mut rpc_sender: mpsc::Sender<Rpc>,
+    mut sys_rpc_sender: Option<mpsc::Sender<Rpc>>,
+    mut reject_queued_receiver: oneshot::Receiver<()>,
+) {
+    loop {
+        let rpc = tokio::select! {
+            rpc = queued_receiver.recv() => match rpc {
+                Some(rpc) => rpc,
+                None => return,
+            },
+            _ = &mut reject_queued_receiver => break,
+        };
+        let depth = decrement_queue_depth(&queue_depth);
+        report_queue_depth(&logger, &reporter, depth).await;
+        let rpc_sender = match (rpc.rpc_type(), sys_rpc_sender.as_mut()) {
+            (Some(protos::RpcType::Sys), Some(sys_rpc_sender)) => sys_rpc_sender,
+            _ => &mut rpc_sender,
+        };
+        // The RPC is only taken by the send once there is room for it, so that it can still
+        // be rejected if the server shuts down while the handlers are busy.
+        let ready = tokio::select! {
+            ready = future::poll_fn(|cx| rpc_sender.poll_ready(cx)) => ready.is_ok(),
+            _ = &mut reject_queued_receiver => {
+                reject_shutting_down(rpc);
+                break;
+            }
+        };
+        let sent = if ready {
+            rpc_sender.try_send(rpc).map_err(|e| match e {
+                mpsc::error::TrySendError::Full(rpc) | mpsc::error::TrySendError::Closed(rpc) => {
+                    rpc
+                }
+            })
+        } else {
+            Err(rpc)
+        };
+        if let Err(rpc) = sent {
+            warn!(logger, "rpc channel stoped being listened");
+            reject_shutting_down(rpc);
+            break;
+        }
+    }
+
+    queued_receiver.close();
+    let mut rejected = 0;
+    while let Some(rpc) = queued_receiver.recv().await {
+        decrement_queue_depth(&queue_depth);
+        reject_shutting_down(rpc);
+        rejected += 1;
+    }
+    if rejected > 0 {
+        warn!(logger, "rejected queued rpcs on shutdown"; "rejected" => rejected);
+    }
+    report_queue_depth(&logger, &reporter, queue_depth.load(Ordering::SeqCst)).await;
 }
 
-impl RpcServerState {
-    async fn close(self) -> Result<(), Error> {
-        let _ = self.close_sender.send(());
-        self.connection.close().await.map_err(Error::Nats)
+fn reject_shutting_down(rpc: Rpc) {
+    // Notifies are not answered, so failing to respond is expected for them.
+    let _ = rpc.respond(utils::build_error_response(
+        pitaya_core::constants::CODE_SERVICE_UNAVAILABLE,
+        "server is shutting down",
+    ));
+}
+
+// Receives the messages of the server subscription until the server is closed. If NATS
+// drops the subscription, the server either subscribes again or signals the application
+// to die, according to the policy.
+async fn receive_rpcs(
+    topic: RpcTopic,
+    mut subscription: BoxStream<'static, asynk::Message>,
+    handler: MessageHandler,
+    mut close_receiver: oneshot::Receiver<()>,
+    policy: settings::SubscriptionLostPolicy,
+    app_die_sender: broadcast::Sender<()>,
+) {
+    let logger = handler.logger.clone();
+    loop {
+        let consume = subscription.for_each(|message| {
+            if let Err(e) = handler.on_nats_message(message) {
+                error!(logger, "error consuming message"; "error" => %e);
+            }
+            future::ready(())
+        });
+
+        match future::select(consume, close_receiver).await {
+            Either::Left((_, receiver)) => close_receiver = receiver,
+            Either::Right(_) => return,
+        }
+
+        if policy == settings::SubscriptionLostPolicy::Die {
+            error!(logger, "nats subscription lost, app will die"; "topic" => &topic.topic);
+            let _ = app_die_sender.send(());
+            return;
+        }
+
+        warn!(logger, "nats subscription lost, subscribing again"; "topic" => &topic.topic);
+        subscription = match topic.subscribe(&handler.connection).await {
+            Ok(subscription) => subscription.boxed(),
+            Err(e) => {
+                error!(logger, "failed to subscribe again, app will die"; "error" => %e);
+                let _ = app_die_sender.send(());
+                return;
+            }
+        };
     }
 }
 
 type NatsRpcServerState = Arc<RwLock<Option<RpcServerState>>>;
 
+// The state of the NATS connection of the RPC server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Disconnected,
+    // The server is connecting, or reconnecting after losing the connection.
+    Connecting,
+    Connected,
+}
+
 pub struct NatsRpcServer {
     settings: settings::Nats,
     connection: NatsRpcServerState,
@@ -34,6 +713,13 @@ pub struct NatsRpcServer {
     runtime_handle: tokio::runtime::Handle,
     logger: slog::Logger,
     reporter: metrics::ThreadSafeReporter,
+    in_flight: Arc<AtomicUsize>,
+    queue_depth: Arc<AtomicUsize>,
+    topic_resolver: Arc<dyn TopicResolver>,
+    additional_topics: Vec<RpcTopic>,
+    sys_rpc_sender: Option<mpsc::Sender<Rpc>>,
+    state_sender: Arc<watch::Sender<ConnectionState>>,
+    state_receiver: watch::Receiver<ConnectionState>,
 }
 
 impl NatsRpcServer {
@@ -44,6 +730,7 @@ impl NatsRpcServer {
         runtime_handle: tokio::runtime::Handle,
         reporter: metrics::ThreadSafeReporter,
     ) -> Self {
+        let (state_sender, state_receiver) = watch::channel(ConnectionState::Disconnected);
         Self {
             settings,
             this_server,
@@ -51,105 +738,150 @@ impl NatsRpcServer {
             connection: Arc::new(RwLock::new(None)),
             runtime_handle,
             reporter,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            queue_depth: Arc::new(AtomicUsize::new(0)),
+            topic_resolver: Arc::new(DefaultTopicResolver),
+            additional_topics: Vec::new(),
+            sys_rpc_sender: None,
+            state_sender: Arc::new(state_sender),
+            state_receiver,
+        }
+    }
+
+    // Specifies the resolver for the topic where the server receives RPCs.
+    pub fn with_topic_resolver(mut self, topic_resolver: Arc<dyn TopicResolver>) -> Self {
+        self.topic_resolver = topic_resolver;
+        self
+    }
+
+    // Specifies topics where the server receives RPCs besides its own topic. Responses
+    // are still sent to the reply topic of each RPC.
+    pub fn with_additional_topics(mut self, topics: Vec<RpcTopic>) -> Self {
+        self.additional_topics = topics;
+        self
+    }
+
+    // Sends system RPCs, like binds and kicks, to the given sender instead of the receiver
+    // returned by `start`, so that they can be handled apart from user RPCs.
+    pub fn with_sys_rpc_sender(mut self, sender: mpsc::Sender<Rpc>) -> Self {
+        self.sys_rpc_sender = Some(sender);
+        self
+    }
+
+    fn message_handler(
+        &self,
+        logger: slog::Logger,
+        connection: asynk::Connection,
+        sender: mpsc::Sender<Rpc>,
+    ) -> MessageHandler {
+        MessageHandler {
+            logger,
+            sender,
+            runtime_handle: self.runtime_handle.clone(),
+            connection,
+            reporter: self.reporter.clone(),
+            in_flight: self.in_flight.clone(),
+            queue_depth: self.queue_depth.clone(),
+            responder_permits: Arc::new(Semaphore::new(self.settings.max_rpcs_answering as usize)),
+            overload_response: utils::build_error_response(
+                &self.settings.overload_error.code,
+                &self.settings.overload_error.message,
+            ),
+            max_request_size: self.settings.max_request_size,
+            response_format: self.settings.response_format,
+            queue_full_wait: self.settings.queue_full_wait,
+            flush_response_handlers: Arc::new(self.settings.flush_response_handlers.clone()),
+            flush_response_timeout: self.settings.flush_response_timeout,
+            rate_limiters: Arc::new(RouteRateLimiters::new(&self.settings.rate_limits)),
         }
     }
 
-    fn on_nats_message(
-        mut message: asynk::Message,
-        logger: &slog::Logger,
-        sender: &mpsc::Sender<Rpc>,
+    // Returns a receiver of the state of the NATS connection, which is updated whenever
+    // the server connects, loses the connection, reconnects or shuts down.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state_receiver.clone()
+    }
+
+    fn set_connection_state(&self, state: ConnectionState) {
+        // Never fails, since the server holds a receiver.
+        let _ = self.state_sender.broadcast(state);
+    }
+
+    // Builds a callback that reports changes in the NATS connection, like disconnections
+    // and reconnections. The callback is called from a NATS thread, so reporting the
+    // metric is done on the tokio runtime.
+    fn connection_event_callback(
+        logger: slog::Logger,
+        reporter: metrics::ThreadSafeReporter,
         runtime_handle: tokio::runtime::Handle,
-        state: NatsRpcServerState,
-    ) -> std::io::Result<()> {
-        debug!(logger, "received nats message"; "message" => ?message);
+        state_sender: Arc<watch::Sender<ConnectionState>>,
+        event: &'static str,
+        state: ConnectionState,
+    ) -> impl Fn() + Send + Sync + 'static {
+        move || {
+            warn!(logger, "nats connection changed"; "event" => event);
+            let _ = state_sender.broadcast(state);
+            let logger = logger.clone();
+            let reporter = reporter.clone();
+            runtime_handle.spawn(async move {
+                metrics::inc_counter(logger, reporter, NATS_RECONNECTS_METRIC, &[event]).await;
+            });
+        }
+    }
 
-        let mut sender = sender.clone();
+    // Flushes the NATS connection, failing if the server does not acknowledge it within the
+    // given timeout. This can be used to check if the connection is healthy.
+    pub async fn flush_timeout(&self, duration: Duration) -> Result<(), Error> {
+        let connection = self
+            .connection
+            .read()
+            .await
+            .as_ref()
+            .map(|state| state.connection.clone())
+            .ok_or(Error::NatsConnectionNotOpen)?;
+        Self::flush(&connection, duration).await
+    }
 
-        let (responder, response_receiver) = oneshot::channel();
+    async fn flush(connection: &asynk::Connection, duration: Duration) -> Result<(), Error> {
+        tokio::time::timeout(duration, connection.flush())
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(Error::Nats)
+    }
 
-        let response_topic = match message.reply.take() {
-            Some(topic) => topic,
-            None => {
-                error!(logger, "received empty response topic from nats message");
-                return Ok(());
-            }
-        };
+    // Returns whether the NATS connection is healthy, using the request timeout as the
+    // limit for the flush.
+    pub async fn is_connected(&self) -> bool {
+        self.flush_timeout(self.settings.request_timeout)
+            .await
+            .is_ok()
+    }
 
-        match sender.try_send(Rpc::new(message.data, responder)) {
-            Ok(_) => {
-                // For the moment we are ignoring the handle returned by the task.
-                // Worst case scenario we will have to kill the task in the middle of its processing
-                // at the end of the program.
-
-                let _ = {
-                    let logger = logger.clone();
-                    // runtime.spawn(async move {
-                    trace!(logger, "spawning response receiver task");
-                    runtime_handle.spawn(async move {
-                        match response_receiver.await {
-                            Ok(response) => {
-                                let conn = match state.read().await.as_ref() {
-                                    Some(state) => state.connection.clone(),
-                                    _ => {
-                                        error!(logger, "connection not open, cannot answer");
-                                        return;
-                                    }
-                                };
-
-                                debug!(logger, "responding rpc");
-                                if let Err(err) = Self::respond(&conn, &response_topic, response).await
-                                {
-                                    error!(logger, "failed to respond rpc"; "error" => %err);
-                                }
-                            }
-                            Err(e) => {
-                                // Errors happen here if the channel was closed before sending a message.
-                                error!(logger, "failed to receive response from RPC"; "error" => %e);
-                            }
-                        }
-                    })
-                };
-            }
-            Err(mpsc::error::TrySendError::Full(_)) => {
-                let _ = {
-                    let logger = logger.clone();
-                    runtime_handle.spawn(async move {
-                        warn!(logger, "channel is full, dropping request");
-                        let conn = match state.read().await.as_ref() {
-                            Some(state) => state.connection.clone(),
-                            _ => {
-                                error!(logger, "connection not open, cannot answer");
-                                return;
-                            }
-                        };
-
-                        let response = utils::encode_proto(&protos::Response {
-                            error: Some(protos::Error {
-                                code: "PIT-503".to_string(),
-                                msg: "server is overloaded".to_string(),
-                                ..Default::default()
-                            }),
-                            ..Default::default()
-                        });
-                        if let Err(err) = Self::respond(&conn, &response_topic, response).await {
-                            error!(logger, "failed to respond rpc"; "error" => %err);
-                        }
-                    })
-                };
+    // Waits for the RPCs being processed to be answered, up to the drain timeout.
+    async fn drain_in_flight_rpcs(&self) {
+        let drain_start = std::time::Instant::now();
+        loop {
+            let in_flight = self.in_flight.load(Ordering::SeqCst);
+            if in_flight == 0 {
+                return;
             }
-            Err(mpsc::error::TrySendError::Closed(_)) => {
-                warn!(logger, "rpc channel stoped being listened");
+            if drain_start.elapsed() >= self.settings.shutdown_drain_timeout {
+                warn!(self.logger, "drain timed out, dropping in-flight rpcs"; "in_flight" => in_flight);
+                return;
             }
-        };
-
-        Ok(())
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
     }
 
+    // Sends a response, given encoded as protobuf by the handler, in the given format.
     async fn respond(
         connection: &asynk::Connection,
         reply_topic: &str,
+        format: encoding::Format,
         res: Vec<u8>,
     ) -> Result<(), Error> {
+        let res = encoding::encode_protobuf_response(res, format)
+            .map_err(Error::InvalidResponseEncoding)?;
         connection
             .publish(reply_topic, res)
             .await
@@ -157,26 +889,96 @@ impl NatsRpcServer {
     }
 
     async fn register_metrics(&self) {
-        self.reporter
-            .write()
-            .await
-            .register_gauge(metrics::Opts {
-                kind: metrics::MetricKind::Gauge,
-                namespace: String::from("pitaya"),
-                subsystem: String::from("rpc"),
-                name: String::from(RPCS_IN_FLIGHT_METRIC),
-                help: String::from("number of in-flight RPCs at the moment"),
-                variable_labels: vec![],
-                buckets: None,
-            })
-            .expect("should not failed to register");
+        // The kind, name, help and labels of every metric reported by the server.
+        let server_metrics: &[(metrics::MetricKind, &str, &str, &[&str])] = &[
+            (
+                metrics::MetricKind::Gauge,
+                RPCS_IN_FLIGHT_METRIC,
+                "number of in-flight RPCs at the moment",
+                &[],
+            ),
+            (
+                metrics::MetricKind::Gauge,
+                RPC_QUEUE_DEPTH_METRIC,
+                "number of RPCs waiting in the queue to be handled",
+                &[],
+            ),
+            (
+                metrics::MetricKind::Counter,
+                NATS_RECONNECTS_METRIC,
+                "number of times the nats connection was lost or reestablished",
+                &["event"],
+            ),
+            (
+                metrics::MetricKind::Counter,
+                RPC_DROPPED_METRIC,
+                "number of RPCs dropped by the server",
+                &["reason"],
+            ),
+            (
+                metrics::MetricKind::Counter,
+                RPC_EXPIRED_METRIC,
+                "number of RPCs whose deadline passed before they arrived",
+                &["route"],
+            ),
+            (
+                metrics::MetricKind::Counter,
+                RPC_REQUESTS_METRIC,
+                "number of RPCs answered by the server",
+                &["route"],
+            ),
+            (
+                metrics::MetricKind::Counter,
+                RPC_ERRORS_METRIC,
+                "number of RPCs answered by the server with an error",
+                &["route"],
+            ),
+            (
+                metrics::MetricKind::Histogram,
+                RPC_HANDLER_DURATION_METRIC,
+                "histogram of the time spent handling RPCs in seconds",
+                &["route"],
+            ),
+        ];
+
+        let mut reporter = self.reporter.write().await;
+        for (kind, name, help, labels) in server_metrics {
+            let opts = metrics::Opts {
+                kind: *kind,
+                namespace: self.settings.metrics_namespace.clone(),
+                subsystem: self.settings.metrics_subsystem.clone(),
+                name: name.to_string(),
+                help: help.to_string(),
+                variable_labels: labels.iter().map(|label| label.to_string()).collect(),
+                buckets: match kind {
+                    metrics::MetricKind::Histogram => Some(
+                        metrics::exponential_buckets(0.0005, 2.0, 20)
+                            .expect("should have valid buckets"),
+                    ),
+                    _ => None,
+                },
+            };
+            let registered = match kind {
+                metrics::MetricKind::Counter => reporter.register_counter(opts),
+                metrics::MetricKind::Gauge => reporter.register_gauge(opts),
+                metrics::MetricKind::Histogram => reporter.register_histogram(opts),
+            };
+            registered
+                .or_else(metrics::allow_already_registered)
+                .unwrap_or_else(|e| warn!(self.logger, "failed to register metric"; "error" => %e));
+        }
     }
 }
 
 #[async_trait]
 impl RpcServer for NatsRpcServer {
     // Starts the server.
-    async fn start(&self) -> Result<mpsc::Receiver<Rpc>, Error> {
+    async fn start(
+        &self,
+        app_die_sender: broadcast::Sender<()>,
+    ) -> Result<mpsc::Receiver<Rpc>, Error> {
+        self.settings.validate()?;
+
         // Register relevant metrics.
         self.register_metrics().await;
 
@@ -185,52 +987,99 @@ impl RpcServer for NatsRpcServer {
             return Err(Error::RpcServerAlreadyStarted);
         }
 
-        // TODO(lhahn): add callbacks here for sending metrics.
         info!(self.logger, "server connecting to nats"; "url" => &self.settings.url);
-        let nats_connection =
-            nats::Options::with_user_pass(&self.settings.auth_user, &self.settings.auth_pass)
-                .max_reconnects(Some(self.settings.max_reconnection_attempts as usize))
-                .connect_async(&self.settings.url)
-                .await
-                .map_err(Error::Nats)?;
+        let options =
+            nats_options::connection_options(&self.logger, &self.settings, &self.this_server)?
+                // NATS tries to reconnect after a disconnection, so the server is connecting again.
+                .disconnect_callback(Self::connection_event_callback(
+                    self.logger.clone(),
+                    self.reporter.clone(),
+                    self.runtime_handle.clone(),
+                    self.state_sender.clone(),
+                    "disconnected",
+                    ConnectionState::Connecting,
+                ))
+                .reconnect_callback(Self::connection_event_callback(
+                    self.logger.clone(),
+                    self.reporter.clone(),
+                    self.runtime_handle.clone(),
+                    self.state_sender.clone(),
+                    "reconnected",
+                    ConnectionState::Connected,
+                ));
 
-        let (rpc_sender, rpc_receiver) = mpsc::channel(self.settings.max_rpcs_queued as usize);
-        let (close_sender, close_receiver) = oneshot::channel();
+        self.set_connection_state(ConnectionState::Connecting);
+        let nats_connection = options
+            .connect_async(&self.settings.url)
+            .await
+            .map_err(|e| {
+                self.set_connection_state(ConnectionState::Disconnected);
+                Error::Nats(e)
+            })?;
+
+        // RPCs are queued in a bounded channel and then forwarded to the returned receiver,
+        // which allows us to know how many of them are waiting to be handled.
+        let (queued_sender, queued_receiver) =
+            mpsc::channel(self.settings.max_rpcs_queued as usize);
+        let (rpc_sender, rpc_receiver) = mpsc::channel(1);
 
-        let topic = utils::topic_for_server(&self.this_server);
+        let mut topics = vec![RpcTopic::new(
+            self.topic_resolver.server_topic(&self.this_server),
+        )];
+        if self.settings.subscription_mode == settings::SubscriptionMode::QueueGroup {
+            let kind = &self.this_server.kind;
+            topics.push(
+                RpcTopic::new(self.topic_resolver.server_kind_topic(kind)).with_queue_group(kind),
+            );
+        }
+        topics.extend(self.additional_topics.iter().cloned());
         let logger = self.logger.new(o!());
 
-        info!(self.logger, "rpc server subscribing"; "topic" => &topic);
+        let handler = self.message_handler(logger, nats_connection.clone(), queued_sender);
 
-        let sender = rpc_sender;
-        let runtime_handle = self.runtime_handle.clone();
-        let connection = self.connection.clone();
+        let mut subscriptions = Vec::new();
+        for topic in topics {
+            info!(self.logger, "rpc server subscribing";
+                "topic" => &topic.topic, "queue_group" => ?topic.queue_group);
+            let subscription = topic.subscribe(&nats_connection).await.map_err(|e| {
+                self.set_connection_state(ConnectionState::Disconnected);
+                Error::Nats(e)
+            })?;
+            subscriptions.push((topic, subscription));
+        }
 
-        let subscription = nats_connection
-            .subscribe(&topic)
-            .await
-            .map_err(Error::Nats)?;
-
-        self.runtime_handle.spawn(future::select(
-            subscription.for_each(move |message| {
-                if let Err(e) = Self::on_nats_message(
-                    message,
-                    &logger,
-                    &sender,
-                    runtime_handle.clone(),
-                    connection.clone(),
-                ) {
-                    error!(logger, "error consuming message"; "error" => %e);
-                }
-                future::ready(())
-            }),
-            close_receiver,
+        let (reject_queued_sender, reject_queued_receiver) = oneshot::channel();
+        let forward_queued_rpcs = self.runtime_handle.spawn(forward_queued_rpcs(
+            self.logger.new(o!("task" => "forward_queued_rpcs")),
+            self.reporter.clone(),
+            self.queue_depth.clone(),
+            queued_receiver,
+            rpc_sender,
+            self.sys_rpc_sender.clone(),
+            reject_queued_receiver,
         ));
 
+        let mut close_senders = Vec::new();
+        for (topic, subscription) in subscriptions {
+            let (close_sender, close_receiver) = oneshot::channel();
+            self.runtime_handle.spawn(receive_rpcs(
+                topic,
+                subscription.boxed(),
+                handler.clone(),
+                close_receiver,
+                self.settings.subscription_lost_policy,
+                app_die_sender.clone(),
+            ));
+            close_senders.push(close_sender);
+        }
+
         self.connection.write().await.replace(RpcServerState {
-            close_sender,
+            close_senders,
+            reject_queued_sender,
+            forward_queued_rpcs,
             connection: nats_connection,
         });
+        self.set_connection_state(ConnectionState::Connected);
 
         Ok(rpc_receiver)
     }
@@ -238,84 +1087,1435 @@ impl RpcServer for NatsRpcServer {
     // Shuts down the server.
     async fn shutdown(&self) -> Result<(), Error> {
         if let Some(state) = self.connection.write().await.take() {
+            // Stop receiving new RPCs, but allow the ones being processed to be answered.
+            for close_sender in state.close_senders {
+                let _ = close_sender.send(());
+            }
+            // The RPCs that were not taken by the handlers yet are rejected, and their
+            // responses are sent while draining.
+            let _ = state.reject_queued_sender.send(());
+            if let Err(e) = state.forward_queued_rpcs.await {
+                error!(self.logger, "forward queued rpcs task failed"; "error" => %e);
+            }
+            self.drain_in_flight_rpcs().await;
+
             let handle = self.runtime_handle.clone();
+            let connection = state.connection;
             // need to spawn a thread so it does not block the current runtime thread
-            let th = std::thread::spawn(move || handle.block_on(state.close()));
-            return th
+            let th = std::thread::spawn(move || {
+                handle.block_on(async move { connection.close().await.map_err(Error::Nats) })
+            });
+            let result = th
                 .join()
                 .unwrap_or_else(|_| Err(Error::Internal("error joining thread".into())));
+            self.set_connection_state(ConnectionState::Disconnected);
+            return result;
         }
         Ok(())
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::NatsRpcClient;
+    use crate::{constants, NatsRpcClient};
     use pitaya_core::{
         cluster::{RpcClient, ServerId, ServerKind},
         context, message,
     };
     use std::collections::HashMap;
     use std::error::Error as StdError;
+    use std::sync::Mutex;
 
-    #[tokio::test]
-    async fn server_starts_and_stops() -> Result<(), Box<dyn StdError>> {
-        let sv = Arc::new(ServerInfo {
-            id: ServerId::from("my-id"),
-            kind: ServerKind::from("room"),
+    // A reporter that records the counters that were incremented.
+    #[derive(Default)]
+    pub(crate) struct RecordingReporter {
+        pub(crate) registered: Arc<Mutex<Vec<String>>>,
+        pub(crate) qualified_names: Arc<Mutex<Vec<String>>>,
+        pub(crate) counters: Arc<Mutex<Vec<(String, Vec<String>)>>>,
+        pub(crate) gauges: Arc<Mutex<Vec<(String, f64)>>>,
+        pub(crate) histograms: Arc<Mutex<Vec<(String, f64, Vec<String>)>>>,
+    }
+
+    impl RecordingReporter {
+        // Rejects metrics that were already registered, like most reporters do.
+        fn record_registration(&self, opts: metrics::Opts) -> Result<(), metrics::Error> {
+            let mut registered = self.registered.lock().unwrap();
+            if registered.contains(&opts.name) {
+                return Err(metrics::Error::MetricAlreadyRegistered(opts.name));
+            }
+            self.qualified_names.lock().unwrap().push(format!(
+                "{}_{}_{}",
+                opts.namespace, opts.subsystem, opts.name
+            ));
+            registered.push(opts.name);
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl metrics::Reporter for RecordingReporter {
+        fn register_counter(&mut self, opts: metrics::Opts) -> Result<(), metrics::Error> {
+            self.record_registration(opts)
+        }
+
+        fn register_histogram(&mut self, opts: metrics::Opts) -> Result<(), metrics::Error> {
+            self.record_registration(opts)
+        }
+
+        fn register_gauge(&mut self, opts: metrics::Opts) -> Result<(), metrics::Error> {
+            self.record_registration(opts)
+        }
+
+        async fn start(&mut self) -> Result<(), metrics::Error> {
+            Ok(())
+        }
+
+        async fn shutdown(&mut self) -> Result<(), metrics::Error> {
+            Ok(())
+        }
+
+        fn inc_counter(&self, name: &str, labels: &[&str]) -> Result<(), metrics::Error> {
+            self.counters.lock().unwrap().push((
+                name.to_owned(),
+                labels.iter().map(|l| l.to_string()).collect(),
+            ));
+            Ok(())
+        }
+
+        fn observe_hist(
+            &self,
+            name: &str,
+            value: f64,
+            labels: &[&str],
+        ) -> Result<(), metrics::Error> {
+            self.histograms.lock().unwrap().push((
+                name.to_owned(),
+                value,
+                labels.iter().map(|l| l.to_string()).collect(),
+            ));
+            Ok(())
+        }
+
+        fn set_gauge(
+            &self,
+            name: &str,
+            value: f64,
+            _labels: &[&str],
+        ) -> Result<(), metrics::Error> {
+            self.gauges.lock().unwrap().push((name.to_owned(), value));
+            Ok(())
+        }
+
+        fn add_gauge(
+            &self,
+            _name: &str,
+            _value: f64,
+            _labels: &[&str],
+        ) -> Result<(), metrics::Error> {
+            Ok(())
+        }
+    }
+
+    fn new_room_server(id: &str) -> Arc<ServerInfo> {
+        Arc::new(ServerInfo {
+            id: ServerId::from(id),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        })
+    }
+
+    fn new_test_server(sv: Arc<ServerInfo>, settings: settings::Nats) -> NatsRpcServer {
+        new_test_server_with_reporter(sv, settings, metrics::DummyReporter {})
+    }
+
+    fn new_test_server_with_reporter(
+        sv: Arc<ServerInfo>,
+        settings: settings::Nats,
+        reporter: impl metrics::Reporter + Send + Sync + 'static,
+    ) -> NatsRpcServer {
+        NatsRpcServer::new(
+            test_helpers::get_root_logger(),
+            sv,
+            settings,
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(reporter))),
+        )
+    }
+
+    // Returns a client that is already started.
+    async fn new_test_client(
+        sv: Arc<ServerInfo>,
+        settings: settings::Nats,
+    ) -> Result<NatsRpcClient, Box<dyn StdError>> {
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            settings,
+            sv,
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        );
+        client.start().await?;
+        Ok(client)
+    }
+
+    fn join_message() -> message::Message {
+        message::Message {
+            kind: message::Kind::Request,
+            id: 12,
+            data: b"sending some data".to_vec(),
+            route: "room.room.join".to_owned(),
+            compressed: false,
+            err: false,
+        }
+    }
+
+    // Calls the join route of the server from another task.
+    fn spawn_join_call(
+        client: &Arc<NatsRpcClient>,
+        sv: &Arc<ServerInfo>,
+    ) -> tokio::task::JoinHandle<Result<protos::Response, Error>> {
+        let client = client.clone();
+        let sv = sv.clone();
+        tokio::spawn(async move {
+            client
+                .call(
+                    context::Context::empty(),
+                    protos::RpcType::User,
+                    join_message(),
+                    sv,
+                )
+                .await
+        })
+    }
+
+    // Answers every RPC received by the server with the given data.
+    fn spawn_responder(
+        mut rpcs: mpsc::Receiver<Rpc>,
+        data: &'static [u8],
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Some(rpc) = rpcs.recv().await {
+                let res = utils::encode_proto(&protos::Response {
+                    data: data.to_vec(),
+                    error: None,
+                });
+                if !rpc.respond(res) {
+                    panic!("failed to respond rpc");
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn metrics_can_be_registered_twice() {
+        let recording = RecordingReporter::default();
+        let registered = recording.registered.clone();
+        let rpc_server =
+            new_test_server_with_reporter(new_room_server("my-id"), Default::default(), recording);
+
+        rpc_server.register_metrics().await;
+        let count = registered.lock().unwrap().len();
+
+        // Registering again, like when the server is restarted, should not panic.
+        rpc_server.register_metrics().await;
+        assert_eq!(registered.lock().unwrap().len(), count);
+    }
+
+    #[tokio::test]
+    async fn metrics_use_configured_namespace() {
+        let recording = RecordingReporter::default();
+        let qualified_names = recording.qualified_names.clone();
+        let rpc_server = new_test_server_with_reporter(
+            new_room_server("my-id"),
+            settings::Nats {
+                metrics_namespace: "lobby".to_owned(),
+                metrics_subsystem: "cluster".to_owned(),
+                ..Default::default()
+            },
+            recording,
+        );
+
+        rpc_server.register_metrics().await;
+        assert!(qualified_names
+            .lock()
+            .unwrap()
+            .contains(&format!("lobby_cluster_{}", RPC_DROPPED_METRIC)));
+    }
+
+    #[tokio::test]
+    async fn connection_events_increment_counter() {
+        let recording = RecordingReporter::default();
+        let counters = recording.counters.clone();
+        let reporter: metrics::ThreadSafeReporter = Arc::new(RwLock::new(Box::new(recording)));
+        let (state_sender, state) = watch::channel(ConnectionState::Connecting);
+        let state_sender = Arc::new(state_sender);
+
+        let callback = NatsRpcServer::connection_event_callback(
+            test_helpers::get_root_logger(),
+            reporter,
+            tokio::runtime::Handle::current(),
+            state_sender,
+            "reconnected",
+            ConnectionState::Connected,
+        );
+        // NATS calls the callback from its own thread.
+        std::thread::spawn(move || callback())
+            .join()
+            .expect("callback should not panic");
+        assert_eq!(*state.borrow(), ConnectionState::Connected);
+
+        for _ in 0..20 {
+            if !counters.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert_eq!(
+            *counters.lock().unwrap(),
+            vec![(
+                NATS_RECONNECTS_METRIC.to_owned(),
+                vec!["reconnected".to_owned()]
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn overloaded_server_answers_configured_error() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("overloaded-id");
+
+        let rpc_server = new_test_server(
+            sv.clone(),
+            settings::Nats {
+                max_rpcs_queued: 1,
+                overload_error: settings::ErrorResponse {
+                    code: "CUSTOM-503".to_owned(),
+                    message: "try again later".to_owned(),
+                },
+                ..Default::default()
+            },
+        );
+        // The receiver is never read, so the queue gets full after a few RPCs.
+        let rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let client = Arc::new(
+            new_test_client(
+                sv.clone(),
+                settings::Nats {
+                    request_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        );
+
+        // Besides the queued one, an RPC waits in the receiver and another one
+        // is being forwarded to it.
+        let mut queued = Vec::new();
+        for _ in 0..3 {
+            queued.push(spawn_join_call(&client, &sv));
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+
+        let res = client
+            .call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                join_message(),
+                sv.clone(),
+            )
+            .await?;
+        let err = res.error.expect("response should be an error");
+        assert_eq!(err.code, "CUSTOM-503");
+        assert_eq!(err.msg, "try again later");
+
+        // The queued RPCs are never answered.
+        for call in queued {
+            assert!(call.await?.is_err());
+        }
+        // Dropping the receiver discards the queued RPCs, so shutdown does not wait for them.
+        drop(rpc_server_conn);
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_rejected() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("small-requests-id");
+
+        let rpc_server = new_test_server(
+            sv.clone(),
+            settings::Nats {
+                max_request_size: 256,
+                ..Default::default()
+            },
+        );
+        let _rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let client = new_test_client(
+            sv.clone(),
+            settings::Nats {
+                request_timeout: Duration::from_secs(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let res = client
+            .call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                message::Message {
+                    kind: message::Kind::Request,
+                    id: 12,
+                    data: vec![7; 1024],
+                    route: "room.room.join".to_owned(),
+                    compressed: false,
+                    err: false,
+                },
+                sv.clone(),
+            )
+            .await?;
+        let err = res.error.expect("response should be an error");
+        assert_eq!(err.code, pitaya_core::constants::CODE_PAYLOAD_TOO_LARGE);
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rpcs_are_rejected_when_too_many_are_being_answered() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("busy-id");
+
+        let rpc_server = new_test_server(
+            sv.clone(),
+            settings::Nats {
+                max_rpcs_answering: 1,
+                ..Default::default()
+            },
+        );
+        // The RPCs are never answered, so the only permit is never given back.
+        let rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let client = Arc::new(
+            new_test_client(
+                sv.clone(),
+                settings::Nats {
+                    request_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        );
+
+        let first_call = spawn_join_call(&client, &sv);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+
+        let res = client
+            .call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                join_message(),
+                sv.clone(),
+            )
+            .await?;
+        let err = res.error.expect("response should be an error");
+        assert_eq!(err.code, constants::DEFAULT_NATS_OVERLOAD_ERROR_CODE);
+
+        assert!(first_call.await?.is_err());
+        drop(rpc_server_conn);
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reply_less_messages_are_forwarded_as_notifies() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("reply-less-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let settings = settings::Nats::default();
+        let connection = nats::Options::new().connect_async(&settings.url).await?;
+        connection
+            .publish(&utils::topic_for_server(&sv), b"no one to answer")
+            .await?;
+
+        let rpc = tokio::time::timeout(Duration::from_secs(1), rpc_server_conn.recv())
+            .await?
+            .expect("notify should be forwarded");
+        assert_eq!(rpc.request(), b"no one to answer");
+        // Nobody waits for the response of a notify.
+        assert!(!rpc.respond(b"ignored".to_vec()));
+
+        drop(rpc_server_conn);
+        connection.close().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_rpcs_are_answered_without_being_processed() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("expired-rpcs-id");
+
+        let recording = RecordingReporter::default();
+        let counters = recording.counters.clone();
+        let rpc_server = new_test_server_with_reporter(sv.clone(), Default::default(), recording);
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        // The client would not send an RPC past its deadline, so it is sent directly.
+        let mut ctx = context::Context::empty();
+        ctx.set_deadline(SystemTime::now() - Duration::from_secs(5));
+        let req = utils::build_request(
+            ctx,
+            protos::RpcType::User,
+            message::Message {
+                route: "room.room.join".to_owned(),
+                ..Default::default()
+            },
+            sv.clone(),
+        )?;
+        let settings = settings::Nats::default();
+        let connection = nats::Options::new().connect_async(&settings.url).await?;
+        let message = tokio::time::timeout(
+            Duration::from_secs(1),
+            connection.request(&utils::topic_for_server(&sv), utils::encode_proto(&req)),
+        )
+        .await??;
+
+        let res = protos::Response::decode(message.data.as_slice())?;
+        let err = res.error.expect("response should be an error");
+        assert_eq!(err.code, pitaya_core::constants::CODE_DEADLINE_EXCEEDED);
+        assert!(rpc_server_conn.try_recv().is_err());
+
+        let expired = (RPC_EXPIRED_METRIC.to_owned(), vec!["room.room".to_owned()]);
+        for _ in 0..20 {
+            if counters.lock().unwrap().contains(&expired) {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert!(counters.lock().unwrap().contains(&expired));
+
+        drop(rpc_server_conn);
+        connection.close().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rpcs_above_the_rate_limit_of_their_route_are_rejected() -> Result<(), Box<dyn StdError>>
+    {
+        let sv = new_room_server("rate-limited-id");
+
+        let rpc_server = new_test_server(
+            sv.clone(),
+            settings::Nats {
+                rate_limits: vec![settings::RouteRateLimit {
+                    route: "room.room.join".to_owned(),
+                    rate: 0.1,
+                    burst: 1,
+                }],
+                ..Default::default()
+            },
+        );
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+        let responder = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                rpc.respond(utils::encode_proto(&protos::Response::default()));
+            }
+        });
+
+        let client = new_test_client(
+            sv.clone(),
+            settings::Nats {
+                request_timeout: Duration::from_secs(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let call = |route: &str| {
+            client.call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                message::Message {
+                    route: route.to_owned(),
+                    ..Default::default()
+                },
+                sv.clone(),
+            )
+        };
+
+        assert!(call("room.room.join").await?.error.is_none());
+        let err = call("room.room.join")
+            .await?
+            .error
+            .expect("response should be an error");
+        assert_eq!(err.code, pitaya_core::constants::CODE_TOO_MANY_REQUESTS);
+        // Other routes are not limited.
+        assert!(call("room.room.leave").await?.error.is_none());
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        responder.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_state_is_published() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("connection-state-id");
+
+        let rpc_server = new_test_server(sv, Default::default());
+        let state = rpc_server.connection_state();
+        assert_eq!(*state.borrow(), ConnectionState::Disconnected);
+
+        let _rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+        assert_eq!(*state.borrow(), ConnectionState::Connected);
+
+        rpc_server.shutdown().await?;
+        assert_eq!(*state.borrow(), ConnectionState::Disconnected);
+        Ok(())
+    }
+
+    // Uses topics with dots, which can be matched by NATS wildcards.
+    struct DottedTopicResolver;
+
+    impl TopicResolver for DottedTopicResolver {
+        fn server_topic(&self, server: &ServerInfo) -> String {
+            format!("servers.{}.{}", server.kind, server.id)
+        }
+
+        fn server_kind_topic(&self, server_kind: &ServerKind) -> String {
+            format!("servers.{}", server_kind)
+        }
+
+        fn user_kick_topic(&self, user_id: &str, server_kind: &ServerKind) -> String {
+            DefaultTopicResolver.user_kick_topic(user_id, server_kind)
+        }
+
+        fn user_messages_topic(&self, user_id: &str, server_kind: &ServerKind) -> String {
+            DefaultTopicResolver.user_messages_topic(user_id, server_kind)
+        }
+    }
+
+    #[tokio::test]
+    async fn server_receives_rpcs_of_additional_topics() -> Result<(), Box<dyn StdError>> {
+        let gateway = Arc::new(ServerInfo {
+            id: ServerId::from("gateway-id"),
+            kind: ServerKind::from("gateway"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        });
+        // There is no room server, the gateway receives its RPCs instead.
+        let room = Arc::new(ServerInfo {
+            id: ServerId::from("fronted-room-id"),
+            kind: ServerKind::from("fronted-room"),
             metadata: HashMap::new(),
             frontend: false,
             hostname: "".to_owned(),
         });
 
-        let rpc_server = NatsRpcServer::new(
-            test_helpers::get_root_logger(),
+        let rpc_server = new_test_server(gateway, Default::default())
+            .with_additional_topics(vec![RpcTopic::new("servers.fronted-room.*")]);
+        let rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let handle = spawn_responder(rpc_server_conn, b"answered by gateway");
+
+        let client = NatsRpcClient::new(
+            test_helpers::get_root_logger(),
+            Default::default(),
+            room.clone(),
+            tokio::runtime::Handle::current(),
+            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+        )
+        .with_topic_resolver(Arc::new(DottedTopicResolver));
+        client.start().await?;
+
+        let res = client
+            .call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                message::Message {
+                    route: "fronted-room.room.join".to_owned(),
+                    ..join_message()
+                },
+                room,
+            )
+            .await?;
+        assert_eq!(String::from_utf8_lossy(&res.data), "answered by gateway");
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_group_splits_rpcs_between_servers() -> Result<(), Box<dyn StdError>> {
+        let settings = settings::Nats {
+            subscription_mode: settings::SubscriptionMode::QueueGroup,
+            ..Default::default()
+        };
+
+        let mut servers = Vec::new();
+        let mut handles = Vec::new();
+        let answered = Arc::new(Mutex::new(Vec::new()));
+        for id in &["queue-room-1", "queue-room-2"] {
+            let sv = Arc::new(ServerInfo {
+                id: ServerId::from(*id),
+                kind: ServerKind::from("queue-room"),
+                metadata: HashMap::new(),
+                frontend: false,
+                hostname: "".to_owned(),
+            });
+            let rpc_server = new_test_server(sv, settings.clone());
+            let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+            let answered = answered.clone();
+            handles.push(tokio::spawn(async move {
+                while let Some(rpc) = rpc_server_conn.recv().await {
+                    answered.lock().unwrap().push(id.to_string());
+                    let res = utils::encode_proto(&protos::Response {
+                        data: b"answered".to_vec(),
+                        error: None,
+                    });
+                    if !rpc.respond(res) {
+                        panic!("failed to respond rpc");
+                    }
+                }
+            }));
+            servers.push(rpc_server);
+        }
+
+        let client = new_test_client(servers[0].this_server.clone(), settings).await?;
+
+        let msg = message::Message {
+            route: "queue-room.room.join".to_owned(),
+            ..join_message()
+        };
+
+        // RPCs to a specific server are still answered by it.
+        for _ in 0..5 {
+            client
+                .call(
+                    context::Context::empty(),
+                    protos::RpcType::User,
+                    msg.clone(),
+                    servers[1].this_server.clone(),
+                )
+                .await?;
+        }
+        assert_eq!(*answered.lock().unwrap(), vec!["queue-room-2"; 5]);
+        answered.lock().unwrap().clear();
+
+        // RPCs to any server of the kind are shared by NATS.
+        let kind = ServerKind::from("queue-room");
+        for _ in 0..20 {
+            client
+                .call_any_of_kind(
+                    context::Context::empty(),
+                    protos::RpcType::User,
+                    msg.clone(),
+                    &kind,
+                )
+                .await?;
+        }
+
+        {
+            let answered = answered.lock().unwrap();
+            assert_eq!(answered.len(), 20);
+            for id in &["queue-room-1", "queue-room-2"] {
+                assert!(answered.iter().any(|answered_by| answered_by == id));
+            }
+        }
+
+        client.shutdown().await?;
+        for rpc_server in servers {
+            rpc_server.shutdown().await?;
+        }
+        for handle in handles {
+            handle.await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sys_rpcs_are_delivered_to_their_own_receiver() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("sys-rpcs-id");
+
+        let (sys_rpc_sender, mut sys_rpc_receiver) = mpsc::channel(10);
+        let rpc_server =
+            new_test_server(sv.clone(), Default::default()).with_sys_rpc_sender(sys_rpc_sender);
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        for rpc_type in &[protos::RpcType::Sys, protos::RpcType::User] {
+            client
+                .notify(
+                    context::Context::empty(),
+                    *rpc_type,
+                    message::Message {
+                        kind: message::Kind::Notify,
+                        ..join_message()
+                    },
+                    sv.clone(),
+                )
+                .await?;
+        }
+
+        let sys_rpc = tokio::time::timeout(Duration::from_secs(1), sys_rpc_receiver.recv())
+            .await?
+            .expect("sys rpc should be forwarded");
+        assert_eq!(sys_rpc.rpc_type(), Some(protos::RpcType::Sys));
+
+        let user_rpc = tokio::time::timeout(Duration::from_secs(1), rpc_server_conn.recv())
+            .await?
+            .expect("user rpc should be forwarded");
+        assert_eq!(user_rpc.rpc_type(), Some(protos::RpcType::User));
+        assert!(sys_rpc_receiver.try_recv().is_err());
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flushed_responses_are_delivered_before_close() -> Result<(), Box<dyn StdError>> {
+        let settings = settings::Nats::default();
+        let subscriber = nats::Options::new().connect_async(&settings.url).await?;
+        let mut subscription = subscriber.subscribe("flushed-response-topic").await?;
+        subscriber.flush().await?;
+
+        let connection = nats::Options::new().connect_async(&settings.url).await?;
+        NatsRpcServer::respond(
+            &connection,
+            "flushed-response-topic",
+            encoding::Format::Protobuf,
+            b"flushed response".to_vec(),
+        )
+        .await?;
+        NatsRpcServer::flush(&connection, Duration::from_secs(1)).await?;
+        connection.close().await?;
+
+        let message = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+            .await?
+            .expect("response should be delivered");
+        assert_eq!(message.data, b"flushed response");
+
+        subscriber.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_notify_is_delivered_to_server() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("notify-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        client
+            .notify(
+                context::Context::empty(),
+                protos::RpcType::User,
+                message::Message {
+                    kind: message::Kind::Notify,
+                    id: 0,
+                    data: b"fire and forget".to_vec(),
+                    route: "room.room.leave".to_owned(),
+                    compressed: false,
+                    err: false,
+                },
+                sv.clone(),
+            )
+            .await?;
+
+        let rpc = tokio::time::timeout(Duration::from_secs(1), rpc_server_conn.recv())
+            .await?
+            .expect("notify should be delivered");
+        let req = protos::Request::decode(rpc.request())?;
+        let msg = req.msg.expect("request should have a message");
+        assert_eq!(msg.route, "room.room.leave");
+        assert_eq!(msg.data, b"fire and forget");
+
+        drop(rpc_server_conn);
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responses_use_the_format_asked_by_the_client() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("response-format-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let handle = spawn_responder(rpc_server_conn, b"joined");
+
+        for format in &[encoding::Format::Protobuf, encoding::Format::Json] {
+            let client = new_test_client(
+                sv.clone(),
+                settings::Nats {
+                    response_format: *format,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+            let res = client
+                .call(
+                    context::Context::empty(),
+                    protos::RpcType::User,
+                    join_message(),
+                    sv.clone(),
+                )
+                .await?;
+            assert_eq!(res.data, b"joined");
+            assert!(res.error.is_none());
+
+            client.shutdown().await?;
+        }
+
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_handlers_increase_handler_duration() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("handler-duration-id");
+
+        let recording = RecordingReporter::default();
+        let histograms = recording.histograms.clone();
+
+        let rpc_server = new_test_server_with_reporter(sv.clone(), Default::default(), recording);
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        // The room handler is slow and the lobby handler answers right away.
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                let req = protos::Request::decode(rpc.request()).unwrap();
+                if req.msg.unwrap().route == "room.room.join" {
+                    tokio::time::delay_for(Duration::from_millis(300)).await;
+                }
+                let res = utils::encode_proto(&protos::Response {
+                    data: b"joined".to_vec(),
+                    error: None,
+                });
+                if !rpc.respond(res) {
+                    panic!("failed to respond rpc");
+                }
+            }
+        });
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        for route in &["room.room.join", "room.lobby.join"] {
+            client
+                .call(
+                    context::Context::empty(),
+                    protos::RpcType::User,
+                    message::Message {
+                        route: route.to_string(),
+                        ..join_message()
+                    },
+                    sv.clone(),
+                )
+                .await?;
+        }
+
+        let duration = |route: &str| -> Option<f64> {
+            histograms
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(name, _, labels)| {
+                    name == RPC_HANDLER_DURATION_METRIC && labels == &[route.to_owned()]
+                })
+                .map(|(_, value, _)| *value)
+        };
+        let slow = duration("room.room").expect("slow handler should be measured");
+        let fast = duration("room.lobby").expect("fast handler should be measured");
+        assert!(slow >= 0.3);
+        assert!(fast < slow);
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answered_rpcs_increment_route_counters() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("route-counters-id");
+
+        let recording = RecordingReporter::default();
+        let counters = recording.counters.clone();
+
+        let rpc_server = new_test_server_with_reporter(sv.clone(), Default::default(), recording);
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        // The join route succeeds and the leave route fails.
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                let req = protos::Request::decode(rpc.request()).unwrap();
+                let res = if req.msg.unwrap().route == "room.room.join" {
+                    utils::encode_proto(&protos::Response {
+                        data: b"joined".to_vec(),
+                        error: None,
+                    })
+                } else {
+                    utils::build_error_response("PIT-500", "failed to leave")
+                };
+                if !rpc.respond(res) {
+                    panic!("failed to respond rpc");
+                }
+            }
+        });
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        for route in &["room.room.join", "room.room.leave"] {
+            client
+                .call(
+                    context::Context::empty(),
+                    protos::RpcType::User,
+                    message::Message {
+                        route: route.to_string(),
+                        ..join_message()
+                    },
+                    sv.clone(),
+                )
+                .await?;
+        }
+
+        let route_counters = || -> Vec<(String, Vec<String>)> {
+            counters
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(name, _)| name == RPC_REQUESTS_METRIC || name == RPC_ERRORS_METRIC)
+                .cloned()
+                .collect()
+        };
+        // The counters are incremented after the response is sent.
+        for _ in 0..20 {
+            if route_counters().len() == 3 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+
+        let mut found = route_counters();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (RPC_ERRORS_METRIC.to_owned(), vec!["room.room".to_owned()]),
+                (RPC_REQUESTS_METRIC.to_owned(), vec!["room.room".to_owned()]),
+                (RPC_REQUESTS_METRIC.to_owned(), vec!["room.room".to_owned()]),
+            ]
+        );
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_rpcs_are_decompressed() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("compressed-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        let data = b"a large payload that repeats itself. ".repeat(1000);
+        client
+            .notify(
+                context::Context::empty(),
+                protos::RpcType::User,
+                message::Message {
+                    kind: message::Kind::Notify,
+                    id: 0,
+                    data: data.clone(),
+                    route: "room.room.join".to_owned(),
+                    compressed: true,
+                    err: false,
+                },
+                sv.clone(),
+            )
+            .await?;
+
+        let rpc = tokio::time::timeout(Duration::from_secs(1), rpc_server_conn.recv())
+            .await?
+            .expect("notify should be delivered");
+        let req = protos::Request::decode(rpc.request())?;
+        assert_eq!(req.msg.expect("request should have a message").data, data);
+
+        drop(rpc_server_conn);
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bursts_wait_for_room_in_the_queue() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("burst-id");
+
+        let rpc_server = new_test_server(
             sv.clone(),
-            Default::default(),
-            tokio::runtime::Handle::current(),
-            Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
+            settings::Nats {
+                max_rpcs_queued: 1,
+                queue_full_wait: Duration::from_secs(2),
+                ..Default::default()
+            },
         );
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        // The RPCs are handled slowly, so the queue is full during the burst.
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+                assert!(rpc.respond(utils::encode_proto(&protos::Response {
+                    data: b"handled".to_vec(),
+                    error: None,
+                })));
+            }
+        });
 
-        let mut rpc_server_conn = rpc_server.start().await?;
+        let client = Arc::new(new_test_client(sv.clone(), Default::default()).await?);
 
-        let handle = {
-            tokio::spawn(async move {
-                while let Some(rpc) = rpc_server_conn.recv().await {
+        let calls: Vec<_> = (0..5).map(|_| spawn_join_call(&client, &sv)).collect();
+
+        for call in calls {
+            let res = call.await??;
+            assert!(res.error.is_none(), "rpc was rejected: {:?}", res.error);
+            assert_eq!(res.data, b"handled");
+        }
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_depth_gauge_follows_queued_rpcs() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("queue-depth-id");
+
+        let recording = RecordingReporter::default();
+        let registered = recording.registered.clone();
+        let gauges = recording.gauges.clone();
+
+        let rpc_server = new_test_server_with_reporter(
+            sv.clone(),
+            settings::Nats {
+                max_rpcs_queued: 2,
+                ..Default::default()
+            },
+            recording,
+        );
+        // The receiver is never read, so RPCs pile up in the queue.
+        let rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+        assert!(registered
+            .lock()
+            .unwrap()
+            .contains(&RPC_QUEUE_DEPTH_METRIC.to_owned()));
+
+        let client = Arc::new(
+            new_test_client(
+                sv.clone(),
+                settings::Nats {
+                    request_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        );
+
+        let mut calls = Vec::new();
+        for _ in 0..4 {
+            calls.push(spawn_join_call(&client, &sv));
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+
+        // Two RPCs were forwarded to the receiver, the other two are still queued.
+        assert_eq!(rpc_server.queue_depth.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            gauges.lock().unwrap().last(),
+            Some(&(RPC_QUEUE_DEPTH_METRIC.to_owned(), 2.0))
+        );
+
+        for call in calls {
+            assert!(call.await?.is_err());
+        }
+        // Dropping the receiver discards the queued RPCs, so shutdown does not wait for them.
+        drop(rpc_server_conn);
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_depth_gauge_stays_within_bounds_while_draining() -> Result<(), Box<dyn StdError>>
+    {
+        let sv = new_room_server("queue-depth-drain-id");
+
+        let recording = RecordingReporter::default();
+        let gauges = recording.gauges.clone();
+        let rpc_server = new_test_server_with_reporter(
+            sv.clone(),
+            settings::Nats {
+                max_rpcs_queued: 2,
+                ..Default::default()
+            },
+            recording,
+        );
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        // The RPCs are taken from the queue as fast as they arrive.
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                let res = utils::encode_proto(&protos::Response {
+                    data: b"answered".to_vec(),
+                    error: None,
+                });
+                let _ = rpc.respond(res);
+            }
+        });
+
+        let client = Arc::new(new_test_client(sv.clone(), Default::default()).await?);
+
+        let calls: Vec<_> = (0..50).map(|_| spawn_join_call(&client, &sv)).collect();
+        for call in calls {
+            // Some calls are rejected because the queue is full, which is expected.
+            let _ = call.await?;
+        }
+
+        assert_eq!(rpc_server.queue_depth.load(Ordering::SeqCst), 0);
+        for (name, value) in gauges.lock().unwrap().iter() {
+            if name == RPC_QUEUE_DEPTH_METRIC {
+                assert!(*value <= 2.0, "queue depth gauge reported {}", value);
+            }
+        }
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_times_out_at_context_deadline() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("deadline-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                // Answer only after the deadline.
+                tokio::time::delay_for(Duration::from_millis(500)).await;
+                let res = utils::encode_proto(&protos::Response {
+                    data: b"TOO LATE".to_vec(),
+                    error: None,
+                });
+                assert!(!rpc.respond(res));
+            }
+        });
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        let mut ctx = context::Context::empty();
+        ctx.set_timeout(Duration::from_millis(200));
+        let res = client
+            .call(ctx, protos::RpcType::User, join_message(), sv.clone())
+            .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trace_id_is_propagated_to_server() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("trace-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        // The server answers with the trace id it received.
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                let req = protos::Request::decode(rpc.request()).unwrap();
+                let metadata = context::RequestMetadata::parse(&req.metadata);
+                let res = utils::encode_proto(&protos::Response {
+                    data: metadata.trace_id.unwrap_or_default().into_bytes(),
+                    error: None,
+                });
+                assert!(rpc.respond(res));
+            }
+        });
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        let mut ctx = context::Context::empty();
+        ctx.set_trace_id("0123456789abcdef");
+        let res = client
+            .call(ctx, protos::RpcType::User, join_message(), sv.clone())
+            .await?;
+        assert_eq!(String::from_utf8_lossy(&res.data), "0123456789abcdef");
+
+        client.shutdown().await?;
+        rpc_server.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_check_follows_connection() -> Result<(), Box<dyn StdError>> {
+        let rpc_server = new_test_server(new_room_server("health-check-id"), Default::default());
+        assert!(!rpc_server.is_connected().await);
+
+        let rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+        assert!(rpc_server.is_connected().await);
+        rpc_server.flush_timeout(Duration::from_secs(1)).await?;
+
+        drop(rpc_server_conn);
+        rpc_server.shutdown().await?;
+        assert!(!rpc_server.is_connected().await);
+        match rpc_server.flush_timeout(Duration::from_secs(1)).await {
+            Err(Error::NatsConnectionNotOpen) => {}
+            _ => panic!("expected connection to be closed"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_in_flight_rpcs() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("draining-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                tokio::spawn(async move {
+                    // Simulate a slow handler.
+                    tokio::time::delay_for(Duration::from_millis(500)).await;
                     let res = utils::encode_proto(&protos::Response {
-                        data: b"HEY, THIS IS THE SERVER".to_vec(),
+                        data: b"SLOW RESPONSE".to_vec(),
                         error: None,
                     });
-                    if !rpc.respond(res) {
-                        panic!("failed to respond rpc");
-                    }
+                    let _ = rpc.respond(res);
+                });
+            }
+        });
+
+        let client = Arc::new(new_test_client(sv.clone(), Default::default()).await?);
+
+        let in_flight_call = spawn_join_call(&client, &sv);
+
+        // Start shutting down while the RPC is being processed.
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(rpc_server.in_flight.load(Ordering::SeqCst), 1);
+        rpc_server.shutdown().await?;
+
+        let res = in_flight_call.await??;
+        assert_eq!(String::from_utf8_lossy(&res.data), "SLOW RESPONSE");
+        assert_eq!(rpc_server.in_flight.load(Ordering::SeqCst), 0);
+
+        client.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_rejects_queued_rpcs() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("rejecting-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+        let mut rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        // A single slow handler, so that the other RPCs wait in the queue.
+        let handle = tokio::spawn(async move {
+            while let Some(rpc) = rpc_server_conn.recv().await {
+                tokio::time::delay_for(Duration::from_millis(200)).await;
+                let res = utils::encode_proto(&protos::Response {
+                    data: b"SLOW RESPONSE".to_vec(),
+                    error: None,
+                });
+                let _ = rpc.respond(res);
+            }
+        });
+
+        let client = Arc::new(new_test_client(sv.clone(), Default::default()).await?);
+
+        let calls: Vec<_> = (0..5).map(|_| spawn_join_call(&client, &sv)).collect();
+
+        for _ in 0..20 {
+            if rpc_server.in_flight.load(Ordering::SeqCst) == 5 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(rpc_server.in_flight.load(Ordering::SeqCst), 5);
+        rpc_server.shutdown().await?;
+
+        // Every RPC is answered, either by the handler or with an error.
+        let mut rejected = 0;
+        for call in calls {
+            let res = call.await??;
+            match res.error {
+                Some(error) => {
+                    assert_eq!(error.code, pitaya_core::constants::CODE_SERVICE_UNAVAILABLE);
+                    rejected += 1;
                 }
-            })
-        };
+                None => assert_eq!(String::from_utf8_lossy(&res.data), "SLOW RESPONSE"),
+            }
+        }
+        assert!(rejected > 0 && rejected < 5);
+        assert_eq!(rpc_server.queue_depth.load(Ordering::SeqCst), 0);
+
+        client.shutdown().await?;
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_starts_and_stops() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("my-id");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+
+        let rpc_server_conn = rpc_server.start(broadcast::channel(1).0).await?;
+
+        let handle = spawn_responder(rpc_server_conn, b"HEY, THIS IS THE SERVER");
 
         {
-            let client = NatsRpcClient::new(
-                test_helpers::get_root_logger(),
-                Default::default(),
-                sv.clone(),
-                tokio::runtime::Handle::current(),
-                Arc::new(RwLock::new(Box::new(metrics::DummyReporter {}))),
-            );
-            client.start().await?;
+            let client = new_test_client(sv.clone(), Default::default()).await?;
 
             let res = client
                 .call(
                     context::Context::empty(),
                     protos::RpcType::User,
-                    message::Message {
-                        kind: message::Kind::Request,
-                        id: 12,
-                        data: b"sending some data".to_vec(),
-                        route: "room.room.join".to_owned(),
-                        compressed: false,
-                        err: false,
-                    },
+                    join_message(),
                     sv.clone(),
                 )
                 .await?;
@@ -329,14 +2529,7 @@ mod tests {
                 .call(
                     context::Context::empty(),
                     protos::RpcType::User,
-                    message::Message {
-                        kind: message::Kind::Request,
-                        id: 12,
-                        data: b"sending some data".to_vec(),
-                        route: "room.room.join".to_owned(),
-                        compressed: false,
-                        err: false,
-                    },
+                    join_message(),
                     sv.clone(),
                 )
                 .await?;
@@ -353,4 +2546,97 @@ mod tests {
         handle.await?;
         Ok(())
     }
+
+    async fn connect_handler(
+        rpc_server: &NatsRpcServer,
+        sender: mpsc::Sender<Rpc>,
+    ) -> Result<MessageHandler, Box<dyn StdError>> {
+        let connection = nats_options::connection_options(
+            &rpc_server.logger,
+            &rpc_server.settings,
+            &rpc_server.this_server,
+        )?
+        .connect_async(&rpc_server.settings.url)
+        .await?;
+        Ok(rpc_server.message_handler(rpc_server.logger.clone(), connection, sender))
+    }
+
+    #[tokio::test]
+    async fn subscription_lost_signals_app_die() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("subscription-die");
+
+        let rpc_server = new_test_server(
+            sv.clone(),
+            settings::Nats {
+                subscription_lost_policy: settings::SubscriptionLostPolicy::Die,
+                ..Default::default()
+            },
+        );
+
+        let (sender, _receiver) = mpsc::channel(10);
+        let handler = connect_handler(&rpc_server, sender).await?;
+        let (_close_sender, close_receiver) = oneshot::channel();
+        let (app_die_sender, mut app_die_receiver) = broadcast::channel(1);
+
+        // An empty stream simulates NATS dropping the subscription right away.
+        let receive = tokio::spawn(receive_rpcs(
+            RpcTopic::new(rpc_server.topic_resolver.server_topic(&sv)),
+            futures::stream::empty().boxed(),
+            handler,
+            close_receiver,
+            settings::SubscriptionLostPolicy::Die,
+            app_die_sender,
+        ));
+
+        tokio::time::timeout(Duration::from_secs(1), app_die_receiver.recv()).await??;
+        receive.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_lost_resubscribes() -> Result<(), Box<dyn StdError>> {
+        let sv = new_room_server("subscription-resubscribe");
+
+        let rpc_server = new_test_server(sv.clone(), Default::default());
+
+        let (sender, receiver) = mpsc::channel(10);
+        let handler = connect_handler(&rpc_server, sender).await?;
+        let (close_sender, close_receiver) = oneshot::channel();
+        let (app_die_sender, mut app_die_receiver) = broadcast::channel(1);
+
+        // An empty stream simulates NATS dropping the subscription right away.
+        let receive = tokio::spawn(receive_rpcs(
+            RpcTopic::new(rpc_server.topic_resolver.server_topic(&sv)),
+            futures::stream::empty().boxed(),
+            handler,
+            close_receiver,
+            settings::SubscriptionLostPolicy::Resubscribe,
+            app_die_sender,
+        ));
+
+        let handle = spawn_responder(receiver, b"STILL RECEIVING");
+
+        let client = new_test_client(sv.clone(), Default::default()).await?;
+
+        // Give the server some time to subscribe again.
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+
+        let res = client
+            .call(
+                context::Context::empty(),
+                protos::RpcType::User,
+                join_message(),
+                sv.clone(),
+            )
+            .await?;
+
+        assert_eq!(String::from_utf8_lossy(&res.data), "STILL RECEIVING");
+        assert!(app_die_receiver.try_recv().is_err());
+
+        client.shutdown().await?;
+        let _ = close_sender.send(());
+        receive.await?;
+        handle.await?;
+        Ok(())
+    }
 }
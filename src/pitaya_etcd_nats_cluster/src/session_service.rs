@@ -0,0 +1,150 @@
+use crate::etcd_api::EtcdApi;
+use pitaya_core::cluster::{Error, ServerId, ServerInfo};
+use slog::{debug, o};
+use std::sync::Arc;
+
+// Keeps track of the frontend server each session is bound to, so that backend servers can
+// send pushes and kicks to the frontend that owns a session. Bindings are stored in etcd
+// under the lease of the frontend, so they are removed when it dies.
+//
+// It is created from a discovery with `EtcdLazy::session_service`, and bindings can only
+// be made after the discovery is started, since that is when the lease is granted.
+pub struct SessionService<C: EtcdApi = etcd_client::Client> {
+    client: C,
+    prefix: String,
+    this_server: Arc<ServerInfo>,
+    lease_id: Option<i64>,
+    logger: slog::Logger,
+}
+
+impl<C: EtcdApi> SessionService<C> {
+    pub(crate) fn new(
+        logger: slog::Logger,
+        client: C,
+        prefix: String,
+        this_server: Arc<ServerInfo>,
+        lease_id: Option<i64>,
+    ) -> Self {
+        Self {
+            logger: logger.new(o!("service" => "session")),
+            client,
+            prefix,
+            this_server,
+            lease_id,
+        }
+    }
+
+    // Binds the session of the given user to this server. A session bound to another
+    // server is bound to this one instead.
+    pub async fn bind(&mut self, uid: &str) -> Result<(), Error> {
+        let lease_id = self.lease_id.ok_or_else(|| {
+            Error::Internal("the discovery should be started before binding sessions".to_owned())
+        })?;
+        self.client
+            .put(
+                session_key(&self.prefix, uid),
+                self.this_server.id.0.as_bytes().to_vec(),
+                Some(lease_id),
+            )
+            .await?;
+        debug!(self.logger, "bound session"; "uid" => uid);
+        Ok(())
+    }
+
+    // Removes the binding of the session of the given user, returning whether it existed.
+    pub async fn unbind(&mut self, uid: &str) -> Result<bool, Error> {
+        let removed = self.client.delete(session_key(&self.prefix, uid)).await?;
+        debug!(self.logger, "unbound session"; "uid" => uid, "removed" => removed);
+        Ok(removed)
+    }
+
+    // Returns the id of the frontend server the session of the given user is bound to.
+    pub async fn frontend_of(&mut self, uid: &str) -> Result<Option<ServerId>, Error> {
+        let key = session_key(&self.prefix, uid);
+        // Keys are read by prefix, so the keys of other users that start with this uid
+        // are ignored.
+        let response = self.client.get(key.clone()).await?;
+        for kv in response.kvs {
+            if kv.key_str().map(|k| k == key).unwrap_or(false) {
+                let id = kv
+                    .value_str()
+                    .map_err(|e| Error::Internal(format!("invalid session binding: {}", e)))?;
+                return Ok(Some(ServerId::from(id)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Returns the key under which the binding of a session is stored in etcd.
+fn session_key(prefix: &str, uid: &str) -> String {
+    format!("{}/sessions/{}", prefix, uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{discovery::tests::new_memory_sd, etcd_api::tests::MemoryEtcd, settings};
+    use pitaya_core::cluster::Discovery;
+    use std::error::Error as StdError;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn session_keys_have_the_expected_format() {
+        assert_eq!(session_key("pitaya", "user-1"), "pitaya/sessions/user-1");
+    }
+
+    #[tokio::test]
+    async fn sessions_can_be_bound_and_unbound() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, settings::Etcd::default());
+        sd.start(broadcast::channel(10).0).await?;
+        let mut sessions = sd.session_service();
+
+        assert_eq!(sessions.frontend_of("user-1").await?, None);
+
+        sessions.bind("user-1").await?;
+        sessions.bind("user-10").await?;
+        let this_server = sessions.this_server.id.clone();
+        assert_eq!(sessions.frontend_of("user-1").await?, Some(this_server));
+        assert_eq!(
+            etcd.lease_of("pitaya/sessions/user-1"),
+            sd.current_lease_id()
+        );
+
+        assert!(sessions.unbind("user-1").await?);
+        assert!(!sessions.unbind("user-1").await?);
+        assert_eq!(sessions.frontend_of("user-1").await?, None);
+        assert!(sessions.frontend_of("user-10").await?.is_some());
+
+        sd.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bindings_are_removed_when_the_frontend_dies() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let mut sd = new_memory_sd(&etcd, settings::Etcd::default());
+        sd.start(broadcast::channel(10).0).await?;
+        let mut sessions = sd.session_service();
+        sessions.bind("user-1").await?;
+
+        // The lease expires when the frontend stops renewing it.
+        let lease_id = sd.current_lease_id().unwrap();
+        etcd.clone().lease_revoke(lease_id).await?;
+        assert_eq!(sessions.frontend_of("user-1").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn binding_requires_a_started_discovery() -> Result<(), Box<dyn StdError>> {
+        let etcd = MemoryEtcd::default();
+        let sd = new_memory_sd(&etcd, settings::Etcd::default());
+        let mut sessions = sd.session_service();
+        assert!(matches!(
+            sessions.bind("user-1").await,
+            Err(Error::Internal(_))
+        ));
+        Ok(())
+    }
+}
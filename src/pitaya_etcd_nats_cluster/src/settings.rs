@@ -1,12 +1,18 @@
 use crate::constants;
-use serde::{Deserialize, Serialize};
+use config::{Config, Environment};
+use pitaya_core::{cluster::Error, compression, encoding};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::time::Duration;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Nats {
     // The url where Nats is located.
     pub url: String,
 
+    // The name of the connections to NATS, shown by tools like `nats-top`. If empty, it is
+    // built from the kind and id of the server, like `pitaya-room-<id>`.
+    pub connection_name: String,
+
     // How long to wait until the connection request times out.
     #[serde(with = "humantime_serde")]
     pub connection_timeout: Duration,
@@ -16,34 +22,293 @@ pub struct Nats {
     pub request_timeout: Duration,
 
     // The maximum amount of times the nats client will attempt to reconnect.
+    // Zero means that the client will attempt to reconnect forever.
     pub max_reconnection_attempts: u32,
 
     // The maximum amount of RPCs queued that a nats server will have.
     // If this amount is passed, RPCs will fail.
     pub max_rpcs_queued: u32,
 
+    // How long an RPC waits for room in the queue when it is full, before failing. This
+    // allows short bursts to be absorbed. Zero means that RPCs fail right away.
+    #[serde(with = "humantime_serde")]
+    pub queue_full_wait: Duration,
+
+    // The maximum amount of RPCs that a nats server will be answering at the same time,
+    // counting the ones that are queued. If this amount is passed, RPCs will fail.
+    pub max_rpcs_answering: u32,
+
     // The NATS connection username.
     pub auth_user: String,
 
     // The NATS connection password.
     pub auth_pass: String,
+
+    // The NATS connection token. If set, it is used instead of the user and password.
+    pub auth_token: String,
+
+    // The error answered to RPCs when more than `max_rpcs_queued` are queued.
+    pub overload_error: ErrorResponse,
+
+    // How long to wait on shutdown for the RPCs being processed to be answered.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_drain_timeout: Duration,
+
+    // Handlers, like `room.payment`, whose responses are flushed right after being
+    // published, so that they are not lost if the server stops. Flushing waits for NATS
+    // to acknowledge, which adds latency to these responses.
+    pub flush_response_handlers: Vec<String>,
+
+    // How long to wait for NATS to acknowledge a flushed response.
+    #[serde(with = "humantime_serde")]
+    pub flush_response_timeout: Duration,
+
+    // TLS settings for the NATS connection.
+    pub tls: NatsTls,
+
+    // What the RPC server does when NATS drops its subscription.
+    pub subscription_lost_policy: SubscriptionLostPolicy,
+
+    // Whether the RPC server also receives the RPCs sent to any server of its kind.
+    pub subscription_mode: SubscriptionMode,
+
+    // The namespace of the metrics reported by the RPC client and server.
+    pub metrics_namespace: String,
+
+    // The subsystem of the metrics reported by the RPC client and server.
+    pub metrics_subsystem: String,
+
+    // The codec used to compress the data of messages marked as compressed.
+    pub compression_codec: compression::Codec,
+
+    // Messages with less data than this, in bytes, are not compressed.
+    pub compression_min_size: usize,
+
+    // Requests with more data than this, in bytes, are rejected without being decoded.
+    pub max_request_size: usize,
+
+    // The format of the RPC responses. The client asks the servers it calls to answer
+    // in this format, and the server uses it for requests that do not ask for one.
+    pub response_format: encoding::Format,
+
+    // Limits on the rate of RPCs received by the server for expensive routes. RPCs above
+    // the limit are answered with PIT-429. Only the first limit that matches a route
+    // applies to it.
+    pub rate_limits: Vec<RouteRateLimit>,
 }
 
 impl Default for Nats {
     fn default() -> Self {
         Self {
             url: constants::LOCAL_NATS_URL.to_owned(),
+            connection_name: String::new(),
             connection_timeout: constants::DEFAULT_NATS_CONN_TIMEOUT,
             request_timeout: constants::DEFAULT_NATS_REQUEST_TIMEOUT,
             max_reconnection_attempts: constants::DEFAULT_NATS_MAX_RECONN_ATTEMPTS,
             max_rpcs_queued: constants::DEFAULT_NATS_MAX_RPCS_QUEUED,
+            queue_full_wait: constants::DEFAULT_NATS_QUEUE_FULL_WAIT,
+            max_rpcs_answering: constants::DEFAULT_NATS_MAX_RPCS_ANSWERING,
             auth_user: constants::DEFAULT_NATS_AUTH_USER.to_owned(),
             auth_pass: constants::DEFAULT_NATS_AUTH_PASS.to_owned(),
+            auth_token: constants::DEFAULT_NATS_AUTH_TOKEN.to_owned(),
+            overload_error: ErrorResponse {
+                code: constants::DEFAULT_NATS_OVERLOAD_ERROR_CODE.to_owned(),
+                message: constants::DEFAULT_NATS_OVERLOAD_ERROR_MESSAGE.to_owned(),
+            },
+            shutdown_drain_timeout: constants::DEFAULT_NATS_SHUTDOWN_DRAIN_TIMEOUT,
+            flush_response_handlers: Vec::new(),
+            flush_response_timeout: constants::DEFAULT_NATS_FLUSH_RESPONSE_TIMEOUT,
+            tls: Default::default(),
+            subscription_lost_policy: Default::default(),
+            subscription_mode: Default::default(),
+            metrics_namespace: constants::DEFAULT_NATS_METRICS_NAMESPACE.to_owned(),
+            metrics_subsystem: constants::DEFAULT_NATS_METRICS_SUBSYSTEM.to_owned(),
+            compression_codec: Default::default(),
+            compression_min_size: constants::DEFAULT_NATS_COMPRESSION_MIN_SIZE,
+            max_request_size: constants::DEFAULT_NATS_MAX_REQUEST_SIZE,
+            response_format: Default::default(),
+            rate_limits: Vec::new(),
+        }
+    }
+}
+
+impl Nats {
+    // Loads the settings from environment variables prefixed with `PITAYA_NATS_`, like
+    // `PITAYA_NATS_URL` or `PITAYA_NATS_TLS__REQUIRED` for nested settings. Settings
+    // without a variable keep their default values.
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_env_with(Self::default())
+    }
+
+    // Loads the settings like `from_env`, but the values of `explicit` that differ from
+    // the defaults take precedence over the environment.
+    pub fn from_env_with(explicit: Self) -> Result<Self, Error> {
+        from_env(explicit, &Self::default(), constants::NATS_ENV_PREFIX)
+    }
+
+    // Checks the settings, so that invalid values fail when the RPC client or server is
+    // started, instead of causing errors later on.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.url.is_empty() {
+            return Err(Error::InvalidSettings(
+                "nats url should not be empty".to_owned(),
+            ));
         }
+        if self.connection_timeout.as_nanos() == 0 {
+            return Err(Error::InvalidSettings(
+                "nats connection timeout should be greater than zero".to_owned(),
+            ));
+        }
+        if self.request_timeout.as_nanos() == 0 {
+            return Err(Error::InvalidSettings(
+                "nats request timeout should be greater than zero".to_owned(),
+            ));
+        }
+        if self.max_rpcs_queued == 0 {
+            return Err(Error::InvalidSettings(
+                "nats max rpcs queued should be at least one".to_owned(),
+            ));
+        }
+        if self.max_rpcs_answering == 0 {
+            return Err(Error::InvalidSettings(
+                "nats max rpcs answering should be at least one".to_owned(),
+            ));
+        }
+        if self.max_request_size == 0 {
+            return Err(Error::InvalidSettings(
+                "nats max request size should be at least one byte".to_owned(),
+            ));
+        }
+        if self.tls.client_cert.is_empty() != self.tls.client_key.is_empty() {
+            return Err(Error::InvalidSettings(
+                "nats tls client cert and client key should be set together".to_owned(),
+            ));
+        }
+        for limit in &self.rate_limits {
+            if limit.route.is_empty() {
+                return Err(Error::InvalidSettings(
+                    "nats rate limit route should not be empty".to_owned(),
+                ));
+            }
+            if !limit.rate.is_finite() || limit.rate <= 0.0 {
+                return Err(Error::InvalidSettings(format!(
+                    "nats rate limit rate of {} should be greater than zero, got {}",
+                    limit.route, limit.rate
+                )));
+            }
+            if limit.burst == 0 {
+                return Err(Error::InvalidSettings(format!(
+                    "nats rate limit burst of {} should be at least one",
+                    limit.route
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Debug is implemented manually so that the credentials never end up in the logs.
+impl std::fmt::Debug for Nats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nats")
+            .field("url", &self.url)
+            .field("connection_name", &self.connection_name)
+            .field("connection_timeout", &self.connection_timeout)
+            .field("request_timeout", &self.request_timeout)
+            .field("max_reconnection_attempts", &self.max_reconnection_attempts)
+            .field("max_rpcs_queued", &self.max_rpcs_queued)
+            .field("queue_full_wait", &self.queue_full_wait)
+            .field("max_rpcs_answering", &self.max_rpcs_answering)
+            .field("auth_user", &self.auth_user)
+            .field("auth_pass", &"<redacted>")
+            .field("auth_token", &"<redacted>")
+            .field("overload_error", &self.overload_error)
+            .field("shutdown_drain_timeout", &self.shutdown_drain_timeout)
+            .field("flush_response_handlers", &self.flush_response_handlers)
+            .field("flush_response_timeout", &self.flush_response_timeout)
+            .field("tls", &self.tls)
+            .field("subscription_lost_policy", &self.subscription_lost_policy)
+            .field("subscription_mode", &self.subscription_mode)
+            .field("metrics_namespace", &self.metrics_namespace)
+            .field("metrics_subsystem", &self.metrics_subsystem)
+            .field("compression_codec", &self.compression_codec)
+            .field("compression_min_size", &self.compression_min_size)
+            .field("max_request_size", &self.max_request_size)
+            .field("response_format", &self.response_format)
+            .field("rate_limits", &self.rate_limits)
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct NatsTls {
+    // Whether the NATS server is required to use TLS.
+    pub required: bool,
+
+    // Path to the root certificate used to verify the NATS server.
+    // If empty, the system root certificates are used.
+    pub ca_cert: String,
+
+    // Path to the client certificate, for servers that verify clients.
+    pub client_cert: String,
+
+    // Path to the client private key.
+    pub client_key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionLostPolicy {
+    // Subscribe again to the server topic.
+    Resubscribe,
+    // Signal the application to die, so that the process can be restarted.
+    Die,
+}
+
+impl Default for SubscriptionLostPolicy {
+    fn default() -> Self {
+        SubscriptionLostPolicy::Resubscribe
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionMode {
+    // Servers only receive the RPCs sent to their own topic.
+    PerServer,
+    // Servers also join a queue group on the topic of their kind, where NATS delivers each
+    // RPC sent with `NatsRpcClient::call_any_of_kind` to a single server of that kind.
+    QueueGroup,
+}
+
+impl Default for SubscriptionMode {
+    fn default() -> Self {
+        SubscriptionMode::PerServer
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RouteRateLimit {
+    // A route, like `room.room.join`, or a prefix of routes, like `room.room`.
+    pub route: String,
+
+    // How many RPCs per second are allowed, on average.
+    pub rate: f64,
+
+    // How many RPCs can be received at once before the rate applies.
+    pub burst: u32,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorResponse {
+    // The error code sent in the response.
+    pub code: String,
+
+    // The error message sent in the response.
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Etcd {
     // The URL where the ETCD instance is located at.
     pub url: String,
@@ -59,6 +324,128 @@ pub struct Etcd {
     // not running anymore.
     #[serde(with = "humantime_serde")]
     pub lease_ttl: Duration,
+
+    // How many times a failed lease renewal is retried before the server
+    // gives up and shuts down.
+    pub keep_alive_max_retries: u32,
+
+    // How long to wait before retrying a failed lease renewal.
+    // The wait time doubles after every retry.
+    #[serde(with = "humantime_serde")]
+    pub keep_alive_retry_backoff: Duration,
+
+    // How many times to try reopening the keep alive stream when etcd closes it, before
+    // the server gives up and shuts down. The retries use `keep_alive_retry_backoff`.
+    pub keep_alive_max_reconnections: u32,
+
+    // Which fraction of the lease TTL to wait before renewing the lease. It should be
+    // greater than zero and at most one. Lower values renew the lease more often, but
+    // leave more time for retries before it expires.
+    pub keep_alive_renewal_fraction: f64,
+
+    // Up to which fraction of the renewal interval is randomly subtracted from it, so that
+    // servers with the same TTL do not all renew their leases at the same time. It should
+    // be at least zero and less than one. The lease is never renewed later because of it.
+    pub keep_alive_jitter: f64,
+
+    // For how long a server id that was not found in etcd is remembered as missing.
+    // Lookups for that id during this period will not hit etcd. Zero disables it.
+    #[serde(with = "humantime_serde")]
+    pub server_not_found_ttl: Duration,
+
+    // The etcd username. Authentication is disabled if it is empty.
+    pub auth_user: String,
+
+    // The etcd password.
+    pub auth_pass: String,
+
+    // How long to wait for the background tasks of the service discovery to finish when
+    // it is shut down. Tasks that take longer are aborted.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+
+    // The namespace of the metrics reported by the service discovery.
+    pub metrics_namespace: String,
+
+    // The subsystem of the metrics reported by the service discovery.
+    pub metrics_subsystem: String,
+
+    // How many times to try reopening the watch when it breaks, before the server gives
+    // up and shuts down. The watch resumes from the last change it observed.
+    pub watch_max_reconnections: u32,
+
+    // How long to wait before retrying to reopen the watch.
+    // The wait time doubles after every retry.
+    #[serde(with = "humantime_serde")]
+    pub watch_retry_backoff: Duration,
+
+    // The server kinds whose changes are watched. Servers of other kinds are still fetched
+    // from etcd when looked up, but they are not kept up to date afterwards. All kinds are
+    // watched if it is empty.
+    pub watched_server_kinds: Vec<String>,
+
+    // The server kinds that exist in the cluster. Looking up servers of other kinds fails
+    // with `Error::UnknownServerKind`, so that a mistyped kind is noticed right away
+    // instead of finding no servers. Any kind is accepted if it is empty.
+    pub known_server_kinds: Vec<String>,
+
+    // How many servers are fetched from etcd per request when filling the cache, which
+    // bounds the memory used while caching a kind with many servers. All pages are read at
+    // the revision of the first one. If zero, all servers are fetched in a single request.
+    pub cache_page_size: u32,
+
+    // Whether to delete the key of a previous instance of this server, with the same id,
+    // before registering it. The key of a server that crashed is only removed when its
+    // lease expires, so a server restarted quickly could otherwise race its stale key.
+    pub remove_stale_registration: bool,
+}
+
+impl Etcd {
+    // Loads the settings from environment variables prefixed with `PITAYA_ETCD_`, like
+    // `PITAYA_ETCD_PREFIX`. Settings without a variable keep their default values.
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_env_with(Self::default())
+    }
+
+    // Loads the settings like `from_env`, but the values of `explicit` that differ from
+    // the defaults take precedence over the environment.
+    pub fn from_env_with(explicit: Self) -> Result<Self, Error> {
+        from_env(explicit, &Self::default(), constants::ETCD_ENV_PREFIX)
+    }
+}
+
+// Debug is implemented manually so that the credentials never end up in the logs.
+impl std::fmt::Debug for Etcd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Etcd")
+            .field("url", &self.url)
+            .field("prefix", &self.prefix)
+            .field("lease_ttl", &self.lease_ttl)
+            .field("keep_alive_max_retries", &self.keep_alive_max_retries)
+            .field("keep_alive_retry_backoff", &self.keep_alive_retry_backoff)
+            .field(
+                "keep_alive_max_reconnections",
+                &self.keep_alive_max_reconnections,
+            )
+            .field(
+                "keep_alive_renewal_fraction",
+                &self.keep_alive_renewal_fraction,
+            )
+            .field("keep_alive_jitter", &self.keep_alive_jitter)
+            .field("server_not_found_ttl", &self.server_not_found_ttl)
+            .field("auth_user", &self.auth_user)
+            .field("auth_pass", &"<redacted>")
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("metrics_namespace", &self.metrics_namespace)
+            .field("metrics_subsystem", &self.metrics_subsystem)
+            .field("watch_max_reconnections", &self.watch_max_reconnections)
+            .field("watch_retry_backoff", &self.watch_retry_backoff)
+            .field("watched_server_kinds", &self.watched_server_kinds)
+            .field("known_server_kinds", &self.known_server_kinds)
+            .field("cache_page_size", &self.cache_page_size)
+            .field("remove_stale_registration", &self.remove_stale_registration)
+            .finish()
+    }
 }
 
 impl Default for Etcd {
@@ -67,6 +454,239 @@ impl Default for Etcd {
             url: constants::LOCAL_ETCD_URL.to_owned(),
             prefix: constants::DEFAULT_ETCD_PREFIX.to_owned(),
             lease_ttl: constants::DEFAULT_ETCD_LEASE_TTL,
+            keep_alive_max_retries: constants::DEFAULT_ETCD_KEEP_ALIVE_MAX_RETRIES,
+            keep_alive_retry_backoff: constants::DEFAULT_ETCD_KEEP_ALIVE_RETRY_BACKOFF,
+            keep_alive_max_reconnections: constants::DEFAULT_ETCD_KEEP_ALIVE_MAX_RECONNECTIONS,
+            keep_alive_renewal_fraction: constants::DEFAULT_ETCD_KEEP_ALIVE_RENEWAL_FRACTION,
+            keep_alive_jitter: constants::DEFAULT_ETCD_KEEP_ALIVE_JITTER,
+            server_not_found_ttl: constants::DEFAULT_ETCD_SERVER_NOT_FOUND_TTL,
+            auth_user: constants::DEFAULT_ETCD_AUTH_USER.to_owned(),
+            auth_pass: constants::DEFAULT_ETCD_AUTH_PASS.to_owned(),
+            shutdown_timeout: constants::DEFAULT_ETCD_SHUTDOWN_TIMEOUT,
+            metrics_namespace: constants::DEFAULT_ETCD_METRICS_NAMESPACE.to_owned(),
+            metrics_subsystem: constants::DEFAULT_ETCD_METRICS_SUBSYSTEM.to_owned(),
+            watch_max_reconnections: constants::DEFAULT_ETCD_WATCH_MAX_RECONNECTIONS,
+            watch_retry_backoff: constants::DEFAULT_ETCD_WATCH_RETRY_BACKOFF,
+            watched_server_kinds: Vec::new(),
+            known_server_kinds: Vec::new(),
+            cache_page_size: constants::DEFAULT_ETCD_CACHE_PAGE_SIZE,
+            remove_stale_registration: false,
+        }
+    }
+}
+
+// Overrides the defaults with the environment variables that have the prefix, and then
+// with the explicit settings that differ from the defaults. Nested settings are separated
+// by a double underscore in the variables. An explicit value that is the same as the
+// default cannot be told apart from an unset one, so the environment wins over it.
+fn from_env<T: Serialize + DeserializeOwned>(
+    explicit: T,
+    defaults: &T,
+    prefix: &str,
+) -> Result<T, Error> {
+    let invalid = |e: config::ConfigError| Error::InvalidSettings(e.to_string());
+    let invalid_json = |e: serde_json::Error| Error::InvalidSettings(e.to_string());
+    let mut config = Config::try_from(defaults).map_err(invalid)?;
+    config
+        .merge(
+            Environment::with_prefix(prefix)
+                .separator("__")
+                .ignore_empty(true),
+        )
+        .map_err(invalid)?;
+    let from_env: T = config.try_into().map_err(invalid)?;
+
+    let mut settings = serde_json::to_value(&from_env).map_err(invalid_json)?;
+    overlay_explicit(
+        &mut settings,
+        &serde_json::to_value(defaults).map_err(invalid_json)?,
+        serde_json::to_value(&explicit).map_err(invalid_json)?,
+    );
+    serde_json::from_value(settings).map_err(invalid_json)
+}
+
+// Replaces the values of `settings` with the ones of `explicit` that are not the same as
+// in `defaults`, recursing into nested settings.
+fn overlay_explicit(
+    settings: &mut serde_json::Value,
+    defaults: &serde_json::Value,
+    explicit: serde_json::Value,
+) {
+    match (settings, explicit) {
+        (serde_json::Value::Object(settings), serde_json::Value::Object(explicit)) => {
+            for (key, value) in explicit {
+                let default = defaults.get(&key).unwrap_or(&serde_json::Value::Null);
+                match settings.get_mut(&key) {
+                    Some(setting) => overlay_explicit(setting, default, value),
+                    None => {
+                        settings.insert(key, value);
+                    }
+                }
+            }
+        }
+        (setting, explicit) => {
+            if explicit != *defaults {
+                *setting = explicit;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn default_nats_settings_are_valid() {
+        assert!(Nats::default().validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_nats_settings_are_rejected() {
+        let invalid = vec![
+            (
+                Nats {
+                    url: String::new(),
+                    ..Default::default()
+                },
+                "url",
+            ),
+            (
+                Nats {
+                    connection_timeout: Duration::from_secs(0),
+                    ..Default::default()
+                },
+                "connection timeout",
+            ),
+            (
+                Nats {
+                    request_timeout: Duration::from_secs(0),
+                    ..Default::default()
+                },
+                "request timeout",
+            ),
+            (
+                Nats {
+                    max_rpcs_queued: 0,
+                    ..Default::default()
+                },
+                "max rpcs queued",
+            ),
+            (
+                Nats {
+                    max_rpcs_answering: 0,
+                    ..Default::default()
+                },
+                "max rpcs answering",
+            ),
+            (
+                Nats {
+                    max_request_size: 0,
+                    ..Default::default()
+                },
+                "max request size",
+            ),
+            (
+                Nats {
+                    tls: NatsTls {
+                        client_cert: "client.pem".to_owned(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                "client key",
+            ),
+            (
+                Nats {
+                    rate_limits: vec![RouteRateLimit {
+                        route: "room.room.join".to_owned(),
+                        rate: 0.0,
+                        burst: 1,
+                    }],
+                    ..Default::default()
+                },
+                "rate limit rate",
+            ),
+            (
+                Nats {
+                    rate_limits: vec![RouteRateLimit {
+                        route: "room.room.join".to_owned(),
+                        rate: 10.0,
+                        burst: 0,
+                    }],
+                    ..Default::default()
+                },
+                "rate limit burst",
+            ),
+        ];
+        for (settings, field) in invalid {
+            let res = settings.validate();
+            assert!(
+                matches!(res, Err(Error::InvalidSettings(msg)) if msg.contains(field)),
+                "settings with invalid {} should be rejected",
+                field
+            );
         }
     }
+
+    // Each test uses its own variables, since the environment is shared by the tests that
+    // run in parallel.
+
+    #[test]
+    fn nats_settings_are_loaded_from_env() -> Result<(), Error> {
+        env::set_var("PITAYA_NATS_URL", "nats://nats.svc:4222");
+        env::set_var("PITAYA_NATS_REQUEST_TIMEOUT", "3s");
+        env::set_var("PITAYA_NATS_MAX_RPCS_QUEUED", "42");
+        env::set_var("PITAYA_NATS_TLS__REQUIRED", "true");
+        let settings = Nats::from_env();
+        env::remove_var("PITAYA_NATS_URL");
+        env::remove_var("PITAYA_NATS_REQUEST_TIMEOUT");
+        env::remove_var("PITAYA_NATS_MAX_RPCS_QUEUED");
+        env::remove_var("PITAYA_NATS_TLS__REQUIRED");
+
+        let settings = settings?;
+        assert_eq!(settings.url, "nats://nats.svc:4222");
+        assert_eq!(settings.request_timeout, Duration::from_secs(3));
+        assert_eq!(settings.max_rpcs_queued, 42);
+        assert!(settings.tls.required);
+        // Settings without a variable keep their default values.
+        assert_eq!(
+            settings.connection_timeout,
+            constants::DEFAULT_NATS_CONN_TIMEOUT
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn etcd_settings_are_loaded_from_env() -> Result<(), Error> {
+        env::set_var("PITAYA_ETCD_PREFIX", "my-game");
+        let settings = Etcd::from_env();
+        env::remove_var("PITAYA_ETCD_PREFIX");
+
+        let settings = settings?;
+        assert_eq!(settings.prefix, "my-game");
+        assert_eq!(settings.url, constants::LOCAL_ETCD_URL);
+
+        // Values set explicitly take precedence over the environment, which still
+        // overrides the defaults of the other settings.
+        env::set_var("PITAYA_ETCD_PREFIX", "my-game");
+        env::set_var("PITAYA_ETCD_URL", "etcd.svc:2379");
+        let settings = Etcd::from_env_with(Etcd {
+            prefix: "explicit".to_owned(),
+            ..Default::default()
+        });
+        env::remove_var("PITAYA_ETCD_URL");
+        env::remove_var("PITAYA_ETCD_PREFIX");
+
+        let settings = settings?;
+        assert_eq!(settings.prefix, "explicit");
+        assert_eq!(settings.url, "etcd.svc:2379");
+
+        env::set_var("PITAYA_ETCD_LEASE_TTL", "not a duration");
+        let settings = Etcd::from_env();
+        env::remove_var("PITAYA_ETCD_LEASE_TTL");
+        assert!(matches!(settings, Err(Error::InvalidSettings(_))));
+        Ok(())
+    }
 }
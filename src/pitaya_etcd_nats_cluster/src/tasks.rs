@@ -1,57 +1,220 @@
-use crate::discovery::ServersCache;
-use pitaya_core::cluster::{ServerId, ServerInfo, ServerKind};
+use crate::{
+    constants,
+    discovery::{self, ServersCache},
+    etcd_api::{self, EtcdApi, WatchStream, Watcher},
+    settings,
+};
+use async_trait::async_trait;
+use pitaya_core::cluster::{Error, ServerId, ServerInfo, ServerKind};
 use slog::{debug, error, info, warn};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::sync::{broadcast, oneshot};
 
-pub(super) async fn lease_keep_alive(
+// A LeaseRenewer is responsible for renewing the lease of the current server.
+#[async_trait]
+pub trait LeaseRenewer: Send + 'static {
+    // Renews the lease, returning its new TTL. `None` means that the keep alive
+    // stream was closed.
+    async fn renew(&mut self) -> Result<Option<Duration>, Error>;
+
+    // Opens a new keep alive stream for the lease, replacing a closed one.
+    async fn reconnect(&mut self) -> Result<(), Error>;
+}
+
+pub struct EtcdLeaseRenewer {
+    client: etcd_client::LeaseClient,
+    lease_id: i64,
+    keeper: etcd_client::LeaseKeeper,
+    stream: etcd_client::LeaseKeepAliveStream,
+}
+
+impl EtcdLeaseRenewer {
+    pub(crate) fn new(
+        client: etcd_client::LeaseClient,
+        lease_id: i64,
+        keeper: etcd_client::LeaseKeeper,
+        stream: etcd_client::LeaseKeepAliveStream,
+    ) -> Self {
+        Self {
+            client,
+            lease_id,
+            keeper,
+            stream,
+        }
+    }
+}
+
+#[async_trait]
+impl LeaseRenewer for EtcdLeaseRenewer {
+    async fn renew(&mut self) -> Result<Option<Duration>, Error> {
+        let lease_id = self.lease_id;
+        self.keeper.keep_alive().await.map_err(|e| {
+            Error::ClusterCommunication(format!(
+                "failed to send keep alive request for lease {}: {}",
+                lease_id, e
+            ))
+        })?;
+        match self.stream.message().await.map_err(|e| {
+            Error::ClusterCommunication(format!(
+                "failed to get keep alive response for lease {}: {}",
+                lease_id, e
+            ))
+        })? {
+            Some(response) if response.ttl() <= 0 => Err(Error::LostConnection(format!(
+                "lease {} has expired",
+                lease_id
+            ))),
+            Some(response) => Ok(Some(Duration::from_secs(response.ttl() as u64))),
+            None => Ok(None),
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        let (keeper, stream) = self
+            .client
+            .keep_alive(self.lease_id)
+            .await
+            .map_err(|e| Error::ClusterCommunication(e.to_string()))?;
+        self.keeper = keeper;
+        self.stream = stream;
+        Ok(())
+    }
+}
+
+// Renews the lease, retrying failures to communicate with etcd with an exponential backoff
+// until `max_retries` is reached. Other failures, like an expired lease, cannot be fixed by
+// retrying, so they are returned right away.
+async fn renew_with_retry<R: LeaseRenewer>(
+    logger: &slog::Logger,
+    renewer: &mut R,
+    max_retries: u32,
+    initial_backoff: Duration,
+) -> Result<Option<Duration>, Error> {
+    let mut backoff = initial_backoff;
+    let mut retries = 0;
+    loop {
+        match renewer.renew().await {
+            Ok(ttl) => return Ok(ttl),
+            Err(e) if retries < max_retries && matches!(e, Error::ClusterCommunication(_)) => {
+                retries += 1;
+                warn!(
+                    logger, "failed keep alive request, retrying";
+                    "error" => %e, "retry" => retries, "backoff" => ?backoff
+                );
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Returns how long to wait before renewing a lease with the given TTL. Up to `jitter` of
+// the interval is randomly subtracted from it, which never makes the lease be renewed
+// later than `renewal_fraction` of the TTL. The interval is never shorter than
+// `MIN_ETCD_KEEP_ALIVE_INTERVAL`, so that short TTLs do not make the task renew the lease
+// in a busy loop.
+fn keep_alive_interval(lease_ttl: Duration, renewal_fraction: f64, jitter: f64) -> Duration {
+    let interval = lease_ttl.mul_f64(renewal_fraction);
+    std::cmp::max(
+        interval.mul_f64(1.0 - jitter * rand::random::<f64>()),
+        constants::MIN_ETCD_KEEP_ALIVE_INTERVAL,
+    )
+}
+
+// Opens a new keep alive stream, retrying with an exponential backoff until
+// `max_attempts` is reached.
+async fn reconnect_with_retry<R: LeaseRenewer>(
+    logger: &slog::Logger,
+    renewer: &mut R,
+    max_attempts: u32,
+    initial_backoff: Duration,
+) -> Result<(), Error> {
+    let mut backoff = initial_backoff;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match renewer.reconnect().await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempts < max_attempts => {
+                warn!(
+                    logger, "failed to reopen keep alive stream, retrying";
+                    "error" => %e, "attempt" => attempts, "backoff" => ?backoff
+                );
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Keeps the lease alive until a stop message is received. Returns whether the lease was
+// lost, which is the only reason for the task to finish before being stopped.
+pub(super) async fn lease_keep_alive<R: LeaseRenewer>(
     logger: slog::Logger,
-    mut lease_ttl: Duration,
-    mut keeper: etcd_client::LeaseKeeper,
-    mut stream: etcd_client::LeaseKeepAliveStream,
+    settings: Arc<settings::Etcd>,
+    mut renewer: R,
     mut stop_chan: oneshot::Receiver<()>,
     app_die_chan: broadcast::Sender<()>,
-) {
+) -> bool {
     use tokio::time::timeout;
 
     info!(logger, "keep alive task started");
+    let mut lease_ttl = settings.lease_ttl;
+    // Whether the lease should be renewed without waiting, after reopening the stream.
+    let mut renew_now = false;
     loop {
-        let seconds_to_wait = lease_ttl.as_secs() as f32 - (lease_ttl.as_secs() as f32 / 3.0);
-        assert!(seconds_to_wait > 0.0);
-
-        debug!(logger, "waiting for {:.2} seconds", seconds_to_wait);
+        let interval = if renew_now {
+            Duration::from_secs(0)
+        } else {
+            keep_alive_interval(
+                lease_ttl,
+                settings.keep_alive_renewal_fraction,
+                settings.keep_alive_jitter,
+            )
+        };
+        renew_now = false;
+        debug!(
+            logger,
+            "waiting for {:?} before renewing the lease", interval
+        );
 
-        match timeout(Duration::from_secs(seconds_to_wait as u64), &mut stop_chan).await {
+        match timeout(interval, &mut stop_chan).await {
             Err(_) => {
-                // TODO(lhahn): currently, the ttl will fail as soon as it loses connection to etcd.
-                // Figure out if a more robust retrying scheme is necessary here.
-                if let Err(e) = keeper.keep_alive().await {
-                    error!(logger, "failed keep alive request: {}", e);
-                    if app_die_chan.send(()).is_err() {
-                        error!(logger, "failed to send die message");
-                    }
-                    return;
-                }
-                match stream.message().await {
-                    Err(err) => {
-                        error!(logger, "failed to get keep alive response"; "error" => %err);
-                        return;
+                match renew_with_retry(
+                    &logger,
+                    &mut renewer,
+                    settings.keep_alive_max_retries,
+                    settings.keep_alive_retry_backoff,
+                )
+                .await
+                {
+                    Ok(Some(ttl)) => {
+                        debug!(logger, "lease renewed with new ttl of {:?}", ttl);
+                        lease_ttl = ttl;
                     }
-                    Ok(msg) => {
-                        if let Some(response) = msg {
-                            debug!(
-                                logger,
-                                "lease renewed with new ttl of {} seconds",
-                                response.ttl()
-                            );
-                            assert!(response.ttl() > 0);
-                            lease_ttl = Duration::from_secs(response.ttl() as u64);
-                        } else {
-                            // TODO(lhahn): what to do here?
-                            warn!(logger, "received empty lease keep alive response");
-                            return;
+                    Ok(None) => {
+                        warn!(logger, "keep alive stream was closed, reopening it");
+                        if let Err(e) = reconnect_with_retry(
+                            &logger,
+                            &mut renewer,
+                            settings.keep_alive_max_reconnections,
+                            settings.keep_alive_retry_backoff,
+                        )
+                        .await
+                        {
+                            error!(logger, "failed to reopen keep alive stream: {}", e);
+                            signal_app_die(&logger, &app_die_chan);
+                            return true;
                         }
+                        renew_now = true;
+                    }
+                    Err(e) => {
+                        error!(logger, "failed keep alive request"; "error" => %e);
+                        signal_app_die(&logger, &app_die_chan);
+                        return true;
                     }
                 }
             }
@@ -60,107 +223,259 @@ pub(super) async fn lease_keep_alive(
                     logger,
                     "received stop message, exiting lease keep alive task"
                 );
-                return;
+                return false;
+            }
+        }
+    }
+}
+
+// An etcd watch of the servers, of all kinds or of a single one. It remembers the last
+// revision it observed, so that it can be opened again without missing any changes.
+pub(super) struct ServersWatch<C: EtcdApi> {
+    client: C,
+    prefix: String,
+    server_kind: Option<ServerKind>,
+    watcher: C::Watcher,
+    stream: C::WatchStream,
+    // The revision after the last one observed, from where the watch is reopened.
+    next_revision: Option<i64>,
+}
+
+impl<C: EtcdApi> ServersWatch<C> {
+    pub(super) async fn open(
+        mut client: C,
+        prefix: String,
+        server_kind: Option<ServerKind>,
+    ) -> Result<Self, Error> {
+        let key = discovery::servers_key(&prefix, server_kind.as_ref());
+        let (watcher, stream) = client.watch(key, None).await?;
+        Ok(Self {
+            client,
+            prefix,
+            server_kind,
+            watcher,
+            stream,
+            next_revision: None,
+        })
+    }
+
+    fn key(&self) -> String {
+        discovery::servers_key(&self.prefix, self.server_kind.as_ref())
+    }
+
+    async fn reopen(&mut self) -> Result<(), Error> {
+        let (watcher, stream) = self.client.watch(self.key(), self.next_revision).await?;
+        self.watcher = watcher;
+        self.stream = stream;
+        Ok(())
+    }
+
+    // Replaces the watched servers in the cache with the ones currently in etcd, and
+    // watches the changes made after them.
+    async fn refetch(
+        &mut self,
+        logger: &slog::Logger,
+        servers_cache: &RwLock<ServersCache>,
+    ) -> Result<(), Error> {
+        let resp = self.client.get(self.key()).await?;
+        let servers = resp
+            .kvs
+            .iter()
+            .filter_map(|kv| parse_server(logger, kv))
+            .collect();
+        servers_cache
+            .write()
+            .unwrap()
+            .replace(self.server_kind.as_ref(), servers);
+        self.next_revision = Some(resp.revision + 1);
+        Ok(())
+    }
+}
+
+fn parse_server(logger: &slog::Logger, kv: &etcd_api::KeyValue) -> Option<Arc<ServerInfo>> {
+    let value_str = kv.value_str().ok()?;
+    match serde_json::from_str::<ServerInfo>(value_str) {
+        Ok(s) => Some(Arc::new(s)),
+        Err(e) => {
+            error!(logger, "server is not valid json: {}", e);
+            None
+        }
+    }
+}
+
+// Reopens the watch, retrying with an exponential backoff until `max_attempts` is reached.
+// If the revision to resume from was compacted, the watched servers are fetched again.
+async fn reopen_with_retry<C: EtcdApi>(
+    logger: &slog::Logger,
+    watch: &mut ServersWatch<C>,
+    servers_cache: &RwLock<ServersCache>,
+    compacted: bool,
+    max_attempts: u32,
+    initial_backoff: Duration,
+) -> Result<(), Error> {
+    let mut backoff = initial_backoff;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let result = if compacted {
+            match watch.refetch(logger, servers_cache).await {
+                Ok(()) => watch.reopen().await,
+                Err(e) => Err(e),
+            }
+        } else {
+            watch.reopen().await
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempts < max_attempts => {
+                warn!(
+                    logger, "failed to reopen watch, retrying";
+                    "error" => %e, "attempt" => attempts, "backoff" => ?backoff
+                );
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
             }
+            Err(e) => return Err(e),
         }
     }
 }
 
-pub(super) async fn watch_task(
+pub(super) async fn watch_task<C: EtcdApi>(
     logger: slog::Logger,
+    settings: Arc<settings::Etcd>,
+    mut watch: ServersWatch<C>,
     servers_cache: Arc<RwLock<ServersCache>>,
-    prefix: String,
-    mut stream: etcd_client::WatchStream,
+    mut stop_chan: oneshot::Receiver<()>,
     app_die_sender: broadcast::Sender<()>,
 ) {
     loop {
         debug!(logger, "watching for etcd changes...");
 
-        match stream.message().await {
-            Ok(Some(watch_response)) => {
-                if watch_response.canceled() {
-                    info!(logger, "watch was cancelled, exiting task");
-                    return;
+        let message = tokio::select! {
+            _ = &mut stop_chan => {
+                info!(logger, "received stop message, exiting watch task");
+                if let Err(e) = watch.watcher.cancel().await {
+                    warn!(logger, "failed to cancel watch"; "error" => %e);
                 }
+                return;
+            }
+            message = watch.stream.message() => message,
+        };
 
-                for event in watch_response.events() {
-                    let kv = match event.kv() {
-                        Some(kv) => kv,
-                        None => {
-                            warn!(logger, "did not get kv for watch event");
-                            continue;
-                        }
-                    };
-
-                    let key_str = match kv.key_str() {
-                        Ok(v) => v,
-                        Err(_) => {
-                            warn!(logger, "invalid key string for watch event");
-                            continue;
-                        }
-                    };
-
-                    match event.event_type() {
-                        etcd_client::EventType::Put => {
-                            if parse_server_kind_and_id(&prefix, key_str).is_none() {
-                                continue;
-                            };
-
-                            let value_str = match kv.value_str() {
-                                Ok(v) => v,
-                                Err(_) => continue,
-                            };
-
-                            let server = match serde_json::from_str::<ServerInfo>(value_str) {
-                                Ok(s) => Arc::new(s),
-                                Err(e) => {
-                                    error!(logger, "server is not valid json: {}", e);
-                                    continue;
-                                }
-                            };
-
-                            servers_cache.write().unwrap().insert(server);
-                        }
-                        etcd_client::EventType::Delete => {
-                            let (server_kind, server_id) =
-                                match parse_server_kind_and_id(&prefix, key_str) {
-                                    Some(a) => a,
-                                    None => {
-                                        warn!(logger, "could not parse key on deleted server");
-                                        continue;
-                                    }
-                                };
-
-                            servers_cache
-                                .write()
-                                .unwrap()
-                                .remove(&server_kind, &server_id);
-                        }
-                    }
+        // Whether the revision to resume from was compacted, so that the changes since
+        // then cannot be watched anymore.
+        let compacted = match message {
+            Ok(Some(watch_response)) if watch_response.canceled => {
+                if watch_response.compact_revision > 0 {
+                    warn!(
+                        logger, "watch revision was compacted, fetching servers again";
+                        "compact_revision" => watch_response.compact_revision
+                    );
+                    true
+                } else {
+                    warn!(logger, "watch was cancelled by etcd, reopening it");
+                    false
+                }
+            }
+            Ok(Some(watch_response)) => {
+                for event in &watch_response.events {
+                    handle_watch_event(&logger, &servers_cache, &watch.prefix, event);
                 }
+                watch.next_revision = Some(watch_response.revision + 1);
+                continue;
             }
             Ok(None) => {
-                // TODO(lhahn): if it got a None, is it safe to assume the watch was closed?
-                info!(logger, "watch was closed, exiting");
-                return;
+                warn!(logger, "watch was closed, reopening it");
+                false
             }
             Err(e) => {
-                // FIXME, TODO(lhahn): should we send an event to kill the pod here?
-                // panic!("failed to get watch message: {}", e);
-                error!(logger, "watch error"; "error" => %e);
-                if app_die_sender.send(()).is_err() {
-                    warn!(logger, "receiver side not listening");
-                }
+                warn!(logger, "watch error, reopening it"; "error" => %e);
+                false
+            }
+        };
+
+        if let Err(e) = reopen_with_retry(
+            &logger,
+            &mut watch,
+            &servers_cache,
+            compacted,
+            settings.watch_max_reconnections,
+            settings.watch_retry_backoff,
+        )
+        .await
+        {
+            error!(logger, "failed to reopen watch"; "error" => %e);
+            signal_app_die(&logger, &app_die_sender);
+            return;
+        }
+        info!(logger, "watch reopened"; "revision" => watch.next_revision);
+    }
+}
+
+fn handle_watch_event(
+    logger: &slog::Logger,
+    servers_cache: &RwLock<ServersCache>,
+    prefix: &str,
+    event: &etcd_api::WatchEvent,
+) {
+    let kv = match &event.kv {
+        Some(kv) => kv,
+        None => {
+            warn!(logger, "did not get kv for watch event");
+            return;
+        }
+    };
+
+    let key_str = match kv.key_str() {
+        Ok(v) => v,
+        Err(_) => {
+            warn!(logger, "invalid key string for watch event");
+            return;
+        }
+    };
+
+    match event.event_type {
+        etcd_api::EventType::Put => {
+            if parse_server_kind_and_id(prefix, key_str).is_none() {
                 return;
+            };
+
+            if let Some(server) = parse_server(logger, kv) {
+                servers_cache.write().unwrap().insert(server);
             }
         }
+        etcd_api::EventType::Delete => {
+            let (server_kind, server_id) = match parse_server_kind_and_id(prefix, key_str) {
+                Some(a) => a,
+                None => {
+                    warn!(logger, "could not parse key on deleted server");
+                    return;
+                }
+            };
+
+            servers_cache
+                .write()
+                .unwrap()
+                .remove(&server_kind, &server_id);
+        }
+    }
+}
+
+// Tells the application that the discovery cannot work anymore, through the channel given
+// to `Discovery::start`, which is the only one used by the discovery tasks. If nobody is
+// listening, the application is already going away, so that is not an error.
+fn signal_app_die(logger: &slog::Logger, app_die_sender: &broadcast::Sender<()>) {
+    match app_die_sender.send(()) {
+        Ok(receivers) => info!(logger, "sent die message"; "receivers" => receivers),
+        Err(_) => warn!(logger, "no one is listening for the die message"),
     }
 }
 
 fn parse_server_kind_and_id(prefix: &str, string: &str) -> Option<(ServerKind, ServerId)> {
-    let components: Vec<&str> = string.split('/').collect();
+    let key = string.strip_prefix(&discovery::servers_key(prefix, None))?;
+    let components: Vec<&str> = key.split('/').collect();
     match components[..] {
-        [key_prefix, "servers", server_kind, server_id] if key_prefix == prefix => {
+        [server_kind, server_id] if !server_kind.is_empty() && !server_id.is_empty() => {
             Some((ServerKind::from(server_kind), ServerId::from(server_id)))
         }
         _ => None,
@@ -170,6 +485,9 @@ fn parse_server_kind_and_id(prefix: &str, string: &str) -> Option<(ServerKind, S
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+    use tokio::time::Instant;
 
     #[test]
     fn works() {
@@ -182,5 +500,419 @@ mod tests {
             ))
         );
         assert_eq!(parse_server_kind_and_id("pit", s), None);
+        assert_eq!(
+            parse_server_kind_and_id("pitaya", "pitaya/servers/room/"),
+            None
+        );
+        assert_eq!(
+            parse_server_kind_and_id("pitaya", "pitaya/servers/room/a/b"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_keys_with_nested_prefix() {
+        assert_eq!(
+            parse_server_kind_and_id("games/pitaya", "games/pitaya/servers/room/server-id"),
+            Some((ServerKind::from("room"), ServerId::from("server-id")))
+        );
+    }
+
+    #[test]
+    fn keep_alive_interval_is_a_fraction_of_the_ttl() {
+        let fraction = constants::DEFAULT_ETCD_KEEP_ALIVE_RENEWAL_FRACTION;
+        let interval = keep_alive_interval(Duration::from_secs(1), fraction, 0.0);
+        assert!(interval > Duration::from_millis(600) && interval < Duration::from_millis(700));
+
+        let interval = keep_alive_interval(Duration::from_secs(2), fraction, 0.0);
+        assert!(interval > Duration::from_millis(1300) && interval < Duration::from_millis(1400));
+
+        let interval = keep_alive_interval(Duration::from_secs(10), fraction, 0.0);
+        assert!(interval > Duration::from_millis(6600) && interval < Duration::from_millis(6700));
+
+        assert_eq!(
+            keep_alive_interval(Duration::from_secs(10), 0.5, 0.0),
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn jittered_keep_alive_interval_stays_within_bounds() {
+        let fraction = constants::DEFAULT_ETCD_KEEP_ALIVE_RENEWAL_FRACTION;
+        for secs in &[1, 2, 5, 10, 30, 60, 300] {
+            let lease_ttl = Duration::from_secs(*secs);
+            let max_interval = lease_ttl.mul_f64(fraction);
+            let min_interval = max_interval.mul_f64(0.5);
+            for _ in 0..100 {
+                let interval = keep_alive_interval(lease_ttl, fraction, 0.5);
+                assert!(interval <= max_interval && interval < lease_ttl);
+                assert!(interval >= min_interval);
+            }
+        }
+    }
+
+    #[test]
+    fn keep_alive_interval_is_clamped_for_short_ttls() {
+        assert_eq!(
+            keep_alive_interval(Duration::from_secs(1), 0.01, 0.0),
+            constants::MIN_ETCD_KEEP_ALIVE_INTERVAL
+        );
+        assert_eq!(
+            keep_alive_interval(Duration::from_secs(0), 0.5, 0.0),
+            constants::MIN_ETCD_KEEP_ALIVE_INTERVAL
+        );
+    }
+
+    struct FlakyRenewer {
+        failures: u32,
+        expired: bool,
+        calls: Arc<AtomicU32>,
+        calls_at: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl FlakyRenewer {
+        fn new(failures: u32) -> Self {
+            Self {
+                failures,
+                expired: false,
+                calls: Arc::new(AtomicU32::new(0)),
+                calls_at: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+
+        // A renewer whose lease has already expired.
+        fn expired() -> Self {
+            Self {
+                expired: true,
+                ..Self::new(0)
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LeaseRenewer for FlakyRenewer {
+        async fn renew(&mut self) -> Result<Option<Duration>, Error> {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            self.calls_at.lock().unwrap().push(Instant::now());
+            if self.expired {
+                Err(Error::LostConnection("lease has expired".to_owned()))
+            } else if calls <= self.failures {
+                Err(Error::ClusterCommunication(
+                    "etcd is unavailable".to_owned(),
+                ))
+            } else {
+                Ok(Some(Duration::from_secs(60)))
+            }
+        }
+
+        async fn reconnect(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    // A renewer whose keep alive stream is closed once, and that can only be reopened
+    // after `reconnect_failures` failed attempts.
+    struct ClosingRenewer {
+        closed: bool,
+        reconnect_failures: u32,
+        reconnects: Arc<AtomicU32>,
+        renewals: Arc<AtomicU32>,
+    }
+
+    impl ClosingRenewer {
+        fn new(reconnect_failures: u32) -> Self {
+            Self {
+                closed: false,
+                reconnect_failures,
+                reconnects: Arc::new(AtomicU32::new(0)),
+                renewals: Arc::new(AtomicU32::new(0)),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LeaseRenewer for ClosingRenewer {
+        async fn renew(&mut self) -> Result<Option<Duration>, Error> {
+            if !self.closed {
+                self.closed = true;
+                return Ok(None);
+            }
+            self.renewals.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(Duration::from_secs(60)))
+        }
+
+        async fn reconnect(&mut self) -> Result<(), Error> {
+            let reconnects = self.reconnects.fetch_add(1, Ordering::SeqCst) + 1;
+            if reconnects <= self.reconnect_failures {
+                Err(Error::ClusterCommunication(
+                    "etcd is unavailable".to_owned(),
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    // Advances the paused clock a millisecond at a time until `done` returns true. The
+    // spawned tasks are given a chance to run after every step, so that their timers fire
+    // exactly when they expire.
+    async fn advance_until(done: impl Fn() -> bool) {
+        for _ in 0..10_000 {
+            if done() {
+                return;
+            }
+            tokio::time::advance(Duration::from_millis(1)).await;
+            for _ in 0..3 {
+                tokio::task::yield_now().await;
+            }
+        }
+        panic!("condition was not met in time");
+    }
+
+    // Renews the lease in a spawned task, so that the test can advance the paused clock
+    // while it waits between retries.
+    fn spawn_renew_with_retry(
+        mut renewer: FlakyRenewer,
+        max_retries: u32,
+    ) -> tokio::task::JoinHandle<Result<Option<Duration>, Error>> {
+        tokio::spawn(async move {
+            renew_with_retry(
+                &test_helpers::get_root_logger(),
+                &mut renewer,
+                max_retries,
+                Duration::from_millis(100),
+            )
+            .await
+        })
+    }
+
+    #[tokio::test]
+    async fn renew_retries_transient_failures_with_exponential_backoff() {
+        tokio::time::pause();
+        let renewer = FlakyRenewer::new(3);
+        let calls_at = renewer.calls_at.clone();
+        let handle = spawn_renew_with_retry(renewer, 3);
+
+        advance_until(|| calls_at.lock().unwrap().len() == 4).await;
+        let ttl = handle
+            .await
+            .expect("task should not panic")
+            .expect("should succeed after retrying");
+        assert_eq!(ttl, Some(Duration::from_secs(60)));
+
+        let calls_at = calls_at.lock().unwrap();
+        let backoffs: Vec<_> = calls_at.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(
+            backoffs,
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(400)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn renew_gives_up_after_max_retries() {
+        tokio::time::pause();
+        let renewer = FlakyRenewer::new(5);
+        let calls = renewer.calls.clone();
+        let handle = spawn_renew_with_retry(renewer, 3);
+
+        advance_until(|| calls.load(Ordering::SeqCst) == 4).await;
+        let res = handle.await.expect("task should not panic");
+        assert!(matches!(res, Err(Error::ClusterCommunication(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn renew_does_not_retry_an_expired_lease() {
+        let mut renewer = FlakyRenewer::expired();
+        let res = renew_with_retry(
+            &test_helpers::get_root_logger(),
+            &mut renewer,
+            3,
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(matches!(res, Err(Error::LostConnection(_))));
+        assert_eq!(renewer.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keep_alive_does_not_die_on_transient_failures() {
+        tokio::time::pause();
+        let renewer = FlakyRenewer::new(2);
+        let calls = renewer.calls.clone();
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let (app_die_sender, mut app_die_receiver) = broadcast::channel(1);
+
+        let handle = tokio::spawn(lease_keep_alive(
+            test_helpers::get_root_logger(),
+            Arc::new(settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_retry_backoff: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            renewer,
+            stop_receiver,
+            app_die_sender,
+        ));
+
+        advance_until(|| calls.load(Ordering::SeqCst) >= 3).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        stop_sender.send(()).expect("task should be running");
+        let lease_lost = handle.await.expect("task should not panic");
+        assert!(!lease_lost);
+        assert!(app_die_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn lost_lease_signals_all_app_die_subscribers() {
+        let (_stop_sender, stop_receiver) = oneshot::channel();
+        let (app_die_sender, first_receiver) = broadcast::channel(1);
+        let second_receiver = app_die_sender.subscribe();
+
+        let handle = tokio::spawn(lease_keep_alive(
+            test_helpers::get_root_logger(),
+            Arc::new(settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_max_retries: 0,
+                ..Default::default()
+            }),
+            FlakyRenewer::new(u32::MAX),
+            stop_receiver,
+            app_die_sender,
+        ));
+
+        for receiver in [first_receiver, second_receiver].iter_mut() {
+            tokio::time::timeout(Duration::from_secs(2), receiver.recv())
+                .await
+                .expect("should receive the die signal in time")
+                .expect("die signal should be sent");
+        }
+        handle.await.expect("task should not panic");
+    }
+
+    #[tokio::test]
+    async fn lost_lease_without_app_die_subscribers_ends_task() {
+        let (_stop_sender, stop_receiver) = oneshot::channel();
+        let (app_die_sender, app_die_receiver) = broadcast::channel(1);
+        drop(app_die_receiver);
+
+        let handle = tokio::spawn(lease_keep_alive(
+            test_helpers::get_root_logger(),
+            Arc::new(settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_max_retries: 0,
+                ..Default::default()
+            }),
+            FlakyRenewer::new(u32::MAX),
+            stop_receiver,
+            app_die_sender,
+        ));
+
+        let lease_lost = tokio::time::timeout(Duration::from_secs(2), handle)
+            .await
+            .expect("task should finish in time")
+            .expect("task should not panic");
+        assert!(lease_lost);
+    }
+
+    #[tokio::test]
+    async fn closed_keep_alive_stream_is_reopened() {
+        let renewer = ClosingRenewer::new(1);
+        let reconnects = renewer.reconnects.clone();
+        let renewals = renewer.renewals.clone();
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let (app_die_sender, mut app_die_receiver) = broadcast::channel(1);
+
+        let handle = tokio::spawn(lease_keep_alive(
+            test_helpers::get_root_logger(),
+            Arc::new(settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_retry_backoff: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            renewer,
+            stop_receiver,
+            app_die_sender,
+        ));
+
+        for _ in 0..100 {
+            if renewals.load(Ordering::SeqCst) >= 1 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert_eq!(reconnects.load(Ordering::SeqCst), 2);
+        assert_eq!(renewals.load(Ordering::SeqCst), 1);
+
+        stop_sender.send(()).expect("task should be running");
+        handle.await.expect("task should not panic");
+        assert!(app_die_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn keep_alive_stream_that_cannot_be_reopened_signals_app_die() {
+        let renewer = ClosingRenewer::new(u32::MAX);
+        let reconnects = renewer.reconnects.clone();
+        let (_stop_sender, stop_receiver) = oneshot::channel();
+        let (app_die_sender, mut app_die_receiver) = broadcast::channel(1);
+
+        let handle = tokio::spawn(lease_keep_alive(
+            test_helpers::get_root_logger(),
+            Arc::new(settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_max_reconnections: 2,
+                keep_alive_retry_backoff: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            renewer,
+            stop_receiver,
+            app_die_sender,
+        ));
+
+        tokio::time::timeout(Duration::from_secs(2), app_die_receiver.recv())
+            .await
+            .expect("should receive the die signal in time")
+            .expect("die signal should be sent");
+        handle.await.expect("task should not panic");
+        assert_eq!(reconnects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn keep_alive_failure_logs_error_and_lease_id() {
+        let (logger, logs) = test_helpers::get_capturing_logger();
+        let (_stop_sender, stop_receiver) = oneshot::channel();
+        let (app_die_sender, mut app_die_receiver) = broadcast::channel(1);
+
+        let handle = tokio::spawn(lease_keep_alive(
+            logger.new(slog::o!("lease_id" => 42)),
+            Arc::new(settings::Etcd {
+                lease_ttl: Duration::from_secs(1),
+                keep_alive_max_retries: 0,
+                ..Default::default()
+            }),
+            FlakyRenewer::new(u32::MAX),
+            stop_receiver,
+            app_die_sender,
+        ));
+
+        tokio::time::timeout(Duration::from_secs(2), app_die_receiver.recv())
+            .await
+            .expect("should receive the die signal in time")
+            .expect("die signal should be sent");
+        handle.await.expect("task should not panic");
+
+        assert!(
+            logs.contains(
+                "failed keep alive request error=failed to communicate with cluster: \
+                 etcd is unavailable lease_id=42"
+            ),
+            "unexpected logs: {:?}",
+            logs.lines()
+        );
     }
 }
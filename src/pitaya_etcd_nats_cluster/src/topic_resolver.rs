@@ -0,0 +1,80 @@
+use pitaya_core::{
+    cluster::{ServerInfo, ServerKind},
+    utils,
+};
+
+// Defines the NATS topics used for sending messages to servers and users.
+//
+// The default implementation matches the topics used by Pitaya in Go. A custom
+// implementation can be used for communicating with deployments that use other topics.
+pub trait TopicResolver: Send + Sync + 'static {
+    // The topic where the server receives RPCs.
+    fn server_topic(&self, server: &ServerInfo) -> String;
+
+    // The topic shared by all servers of a kind, where they receive RPCs in a queue group
+    // when `SubscriptionMode::QueueGroup` is used.
+    fn server_kind_topic(&self, server_kind: &ServerKind) -> String;
+
+    // The topic where a user connected to a server of the given kind is kicked.
+    fn user_kick_topic(&self, user_id: &str, server_kind: &ServerKind) -> String;
+
+    // The topic where pushes are sent to a user connected to a server of the given kind.
+    fn user_messages_topic(&self, user_id: &str, server_kind: &ServerKind) -> String;
+}
+
+// The topics used by Pitaya.
+pub struct DefaultTopicResolver;
+
+impl TopicResolver for DefaultTopicResolver {
+    fn server_topic(&self, server: &ServerInfo) -> String {
+        utils::topic_for_server(server)
+    }
+
+    fn server_kind_topic(&self, server_kind: &ServerKind) -> String {
+        utils::topic_for_server_kind(server_kind)
+    }
+
+    fn user_kick_topic(&self, user_id: &str, server_kind: &ServerKind) -> String {
+        utils::user_kick_topic(user_id, server_kind)
+    }
+
+    fn user_messages_topic(&self, user_id: &str, server_kind: &ServerKind) -> String {
+        utils::user_messages_topic(user_id, server_kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitaya_core::cluster::ServerId;
+    use std::collections::HashMap;
+
+    #[test]
+    fn default_resolver_matches_go_pitaya() {
+        let server = ServerInfo {
+            id: ServerId::from("4e3b2c1a"),
+            kind: ServerKind::from("room"),
+            metadata: HashMap::new(),
+            frontend: false,
+            hostname: "".to_owned(),
+        };
+        let resolver = DefaultTopicResolver;
+
+        assert_eq!(
+            resolver.server_topic(&server),
+            "pitaya/servers/room/4e3b2c1a"
+        );
+        assert_eq!(
+            resolver.server_kind_topic(&ServerKind::from("room")),
+            "pitaya/servers/room"
+        );
+        assert_eq!(
+            resolver.user_kick_topic("user-1", &ServerKind::from("connector")),
+            "pitaya/connector/user/user-1/kick"
+        );
+        assert_eq!(
+            resolver.user_messages_topic("user-1", &ServerKind::from("connector")),
+            "pitaya/connector/user/user-1/push"
+        );
+    }
+}
@@ -1,14 +1,43 @@
 use async_trait::async_trait;
 use hyper::{
     header,
+    server::conn::Http,
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server,
 };
 use pitaya_core::metrics::{BucketOpts, Error, Opts, Reporter};
 use prometheus::{Encoder, TextEncoder};
-use slog::{error, info};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::{sync::oneshot, task::JoinHandle};
+use slog::{debug, error, info};
+use std::{collections::HashMap, fs::File, io::BufReader, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        NoClientAuth, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+// TLS settings of the metrics endpoint.
+#[derive(Debug, Clone)]
+pub struct MetricsTls {
+    // Path to the PEM encoded certificate chain of the endpoint.
+    pub cert: String,
+
+    // Path to the PEM encoded private key of the endpoint, in PKCS#8 or RSA format.
+    pub key: String,
+}
+
+// The token that scrapes have to send as a bearer token. Debug does not show it, so that
+// it never ends up in the logs.
+#[derive(Clone)]
+struct AuthToken(Arc<String>);
+
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
 
 //
 // Reporter implementation.
@@ -19,6 +48,10 @@ pub struct PrometheusReporter {
     server_handle: Option<JoinHandle<()>>,
     logger: slog::Logger,
     addr: SocketAddr,
+    // The address the endpoint is listening on, while the reporter is started.
+    local_addr: Option<SocketAddr>,
+    tls: Option<MetricsTls>,
+    auth_token: Option<AuthToken>,
     shutdown_sender: Option<oneshot::Sender<()>>,
     histograms: HashMap<String, prometheus::HistogramVec>,
     counters: HashMap<String, prometheus::CounterVec>,
@@ -43,6 +76,9 @@ impl PrometheusReporter {
             server_handle: None,
             logger,
             addr,
+            local_addr: None,
+            tls: None,
+            auth_token: None,
             shutdown_sender: None,
             histograms: HashMap::new(),
             counters: HashMap::new(),
@@ -51,20 +87,63 @@ impl PrometheusReporter {
             namespace: prefix,
         })
     }
+
+    // Serves the metrics endpoint over TLS. The certificate and key are loaded when the
+    // reporter is started.
+    pub fn with_tls(mut self, tls: MetricsTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Requires scrapes to send the given token in an `Authorization: Bearer` header.
+    // Scrapes without it are rejected with 401.
+    pub fn with_auth_token(mut self, auth_token: String) -> Self {
+        self.auth_token = Some(AuthToken(Arc::new(auth_token)));
+        self
+    }
+
+    // Returns the address the metrics endpoint is listening on, which is known once the
+    // reporter is started, even when it was created with port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
 }
 
 #[async_trait]
 impl Reporter for PrometheusReporter {
     async fn start(&mut self) -> Result<(), Error> {
+        let tls_acceptor = match &self.tls {
+            Some(tls) => Some(tls_acceptor(tls)?),
+            None => None,
+        };
+        // The address is bound before returning, so the endpoint accepts scrapes as soon as
+        // the reporter is started.
+        let listener = std::net::TcpListener::bind(self.addr)
+            .map_err(|e| Error::FailedToStartServer(e.to_string()))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| Error::FailedToStartServer(e.to_string()))?;
         let (tx, rx) = oneshot::channel();
 
-        let handle = tokio::spawn(start_server(
-            self.registry.clone(),
-            self.logger.clone(),
-            self.addr,
-            rx,
-        ));
+        let handle = match tls_acceptor {
+            Some(tls_acceptor) => tokio::spawn(start_tls_server(
+                self.registry.clone(),
+                self.auth_token.clone(),
+                self.logger.clone(),
+                listener,
+                tls_acceptor,
+                rx,
+            )),
+            None => tokio::spawn(start_server(
+                self.registry.clone(),
+                self.auth_token.clone(),
+                self.logger.clone(),
+                listener,
+                rx,
+            )),
+        };
 
+        self.local_addr.replace(local_addr);
         self.server_handle.replace(handle);
         self.shutdown_sender.replace(tx);
 
@@ -87,11 +166,15 @@ impl Reporter for PrometheusReporter {
         {
             error!(self.logger, "metrics server panicked");
         }
+        self.local_addr = None;
         info!(self.logger, "prometheus metrics server was shut down");
         Ok(())
     }
 
     fn register_counter(&mut self, opts: Opts) -> Result<(), Error> {
+        if self.counters.contains_key(&opts.name) {
+            return Err(Error::MetricAlreadyRegistered(opts.name));
+        }
         let name = opts.name.clone();
         let prometheus_opts = prometheus::Opts {
             namespace: opts.namespace,
@@ -106,13 +189,16 @@ impl Reporter for PrometheusReporter {
             .map_err(|e| Error::InvalidMetric(e.to_string()))?;
         self.registry
             .register(Box::new(collector.clone()))
-            .map_err(|e| Error::InvalidMetric(e.to_string()))?;
+            .map_err(to_register_error)?;
         self.counters.insert(name, collector);
 
         Ok(())
     }
 
     fn register_histogram(&mut self, opts: Opts) -> Result<(), Error> {
+        if self.histograms.contains_key(&opts.name) {
+            return Err(Error::MetricAlreadyRegistered(opts.name));
+        }
         let name = opts.name.clone();
         let prometheus_opts = prometheus::HistogramOpts {
             common_opts: prometheus::Opts {
@@ -130,7 +216,7 @@ impl Reporter for PrometheusReporter {
             .map_err(|e| Error::InvalidMetric(e.to_string()))?;
         self.registry
             .register(Box::new(collector.clone()))
-            .map_err(|e| Error::InvalidMetric(e.to_string()))?;
+            .map_err(to_register_error)?;
 
         self.histograms.insert(name, collector);
 
@@ -138,6 +224,9 @@ impl Reporter for PrometheusReporter {
     }
 
     fn register_gauge(&mut self, opts: Opts) -> Result<(), Error> {
+        if self.gauges.contains_key(&opts.name) {
+            return Err(Error::MetricAlreadyRegistered(opts.name));
+        }
         let name = opts.name.clone();
         let prometheus_opts = prometheus::Opts {
             namespace: opts.namespace,
@@ -152,7 +241,7 @@ impl Reporter for PrometheusReporter {
             .map_err(|e| Error::InvalidMetric(e.to_string()))?;
         self.registry
             .register(Box::new(collector.clone()))
-            .map_err(|e| Error::InvalidMetric(e.to_string()))?;
+            .map_err(to_register_error)?;
 
         self.gauges.insert(name, collector);
 
@@ -210,20 +299,28 @@ impl Reporter for PrometheusReporter {
 
 async fn start_server(
     registry: Arc<prometheus::Registry>,
+    auth_token: Option<AuthToken>,
     logger: slog::Logger,
-    addr: SocketAddr,
+    listener: std::net::TcpListener,
     shutdown_signal: oneshot::Receiver<()>,
 ) {
     let make_svc = make_service_fn(|_conn| {
         let registry = registry.clone();
+        let auth_token = auth_token.clone();
         async move {
             Ok::<_, hyper::http::Error>(service_fn(move |req| {
-                metrics_handler(registry.clone(), req)
+                metrics_handler(registry.clone(), auth_token.clone(), req)
             }))
         }
     });
-    let server = Server::bind(&addr).serve(make_svc);
-    info!(logger, "started metrics server"; "addr" => %addr);
+    let server = match Server::from_tcp(listener) {
+        Ok(builder) => builder.serve(make_svc),
+        Err(err) => {
+            error!(logger, "server error"; "error" => %err);
+            return;
+        }
+    };
+    info!(logger, "started metrics server"; "addr" => %server.local_addr());
     let graceful = server.with_graceful_shutdown(async move {
         let _ = shutdown_signal.await;
     });
@@ -232,10 +329,125 @@ async fn start_server(
     }
 }
 
+// Serves the metrics endpoint over TLS. Each connection is handled in its own task, so
+// that a slow handshake does not hold back the other scrapes.
+async fn start_tls_server(
+    registry: Arc<prometheus::Registry>,
+    auth_token: Option<AuthToken>,
+    logger: slog::Logger,
+    listener: std::net::TcpListener,
+    tls_acceptor: TlsAcceptor,
+    mut shutdown_signal: oneshot::Receiver<()>,
+) {
+    let mut listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(logger, "server error"; "error" => %err);
+            return;
+        }
+    };
+    if let Ok(addr) = listener.local_addr() {
+        info!(logger, "started metrics server"; "addr" => %addr, "tls" => true);
+    }
+
+    loop {
+        let stream = tokio::select! {
+            _ = &mut shutdown_signal => break,
+            conn = listener.accept() => match conn {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!(logger, "failed to accept connection"; "error" => %err);
+                    continue;
+                }
+            },
+        };
+
+        let registry = registry.clone();
+        let auth_token = auth_token.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            let stream = match tls_acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!(logger, "tls handshake failed"; "error" => %err);
+                    return;
+                }
+            };
+            let service =
+                service_fn(move |req| metrics_handler(registry.clone(), auth_token.clone(), req));
+            if let Err(err) = Http::new().serve_connection(stream, service).await {
+                debug!(logger, "failed to serve connection"; "error" => %err);
+            }
+        });
+    }
+}
+
+// Loads the certificate and key of the metrics endpoint.
+fn tls_acceptor(tls: &MetricsTls) -> Result<TlsAcceptor, Error> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| Error::FailedToStartServer(format!("failed to open {}: {}", path, e)))
+    };
+
+    let cert_chain = certs(&mut open(&tls.cert)?)
+        .map_err(|_| Error::FailedToStartServer(format!("invalid certificate in {}", tls.cert)))?;
+    let mut keys = pkcs8_private_keys(&mut open(&tls.key)?)
+        .map_err(|_| Error::FailedToStartServer(format!("invalid private key in {}", tls.key)))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(&tls.key)?).map_err(|_| {
+            Error::FailedToStartServer(format!("invalid private key in {}", tls.key))
+        })?;
+    }
+    let key = keys.into_iter().next().ok_or_else(|| {
+        Error::FailedToStartServer(format!("no private key found in {}", tls.key))
+    })?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .map_err(|e| Error::FailedToStartServer(format!("invalid certificate or key: {}", e)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Returns whether the request has the bearer token, when one is required. The tokens are
+// compared in constant time, so that they cannot be guessed from the response times.
+fn is_authorized(auth_token: Option<&AuthToken>, req: &Request<Body>) -> bool {
+    let expected = match auth_token {
+        Some(AuthToken(expected)) => expected,
+        None => return true,
+    };
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) => {
+            given.len() == expected.len()
+                && given
+                    .bytes()
+                    .zip(expected.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        }
+        None => false,
+    }
+}
+
 async fn metrics_handler(
     registry: Arc<prometheus::Registry>,
+    auth_token: Option<AuthToken>,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::http::Error> {
+    if !is_authorized(auth_token.as_ref(), &req) {
+        return Response::builder()
+            .status(hyper::StatusCode::UNAUTHORIZED)
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(Body::from(""));
+    }
+
     let encoder = TextEncoder::new();
     let mut buffer = Vec::new();
     let mf = registry.gather();
@@ -259,21 +471,216 @@ async fn metrics_handler(
     }
 }
 
+fn to_register_error(err: prometheus::Error) -> Error {
+    match err {
+        prometheus::Error::AlreadyReg => Error::MetricAlreadyRegistered(err.to_string()),
+        err => Error::InvalidMetric(err.to_string()),
+    }
+}
+
 fn to_prometheus_buckets(opts: BucketOpts) -> Vec<f64> {
     assert!(opts.count >= 1);
-    assert!(opts.start > 0.0);
     assert!(opts.inc > 0.0);
 
+    if opts.kind == "linear" {
+        return (0..opts.count)
+            .map(|i| opts.start + opts.inc * i as f64)
+            .collect();
+    }
+
+    assert!(opts.start > 0.0);
     let mut next = opts.start;
     let mut buckets = Vec::with_capacity(opts.count);
     for _ in 0..opts.count {
         buckets.push(next);
-        if opts.kind == "linear" {
-            next += opts.inc;
-        } else {
-            next *= opts.inc;
-        }
+        next *= opts.inc;
     }
 
     buckets
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitaya_core::metrics::{self, MetricKind, ThreadSafeReporter};
+    use std::error::Error as StdError;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn metrics_endpoint_exposes_registered_metrics() -> Result<(), Box<dyn StdError>> {
+        let mut reporter = PrometheusReporter::new(
+            "pitaya".to_owned(),
+            HashMap::new(),
+            test_helpers::get_root_logger(),
+            "127.0.0.1:0".parse()?,
+        )?;
+
+        reporter.register_counter(Opts {
+            kind: MetricKind::Counter,
+            namespace: "pitaya".to_owned(),
+            subsystem: "test".to_owned(),
+            name: "exported_counter".to_owned(),
+            help: "a counter exported for scraping".to_owned(),
+            variable_labels: vec!["route".to_owned()],
+            buckets: None,
+        })?;
+        reporter.inc_counter("exported_counter", &["room.room.join"])?;
+
+        reporter.start().await?;
+        let addr = reporter.local_addr().expect("reporter should be listening");
+
+        let res = hyper::Client::new()
+            .get(format!("http://{}/metrics", addr).parse()?)
+            .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("pitaya_test_exported_counter"));
+
+        reporter.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scrapes_without_the_auth_token_are_rejected() -> Result<(), Box<dyn StdError>> {
+        let mut reporter = PrometheusReporter::new(
+            "pitaya".to_owned(),
+            HashMap::new(),
+            test_helpers::get_root_logger(),
+            "127.0.0.1:0".parse()?,
+        )?
+        .with_auth_token("secret".to_owned());
+
+        reporter.start().await?;
+        let addr = reporter.local_addr().expect("reporter should be listening");
+
+        let client = hyper::Client::new();
+        let scrape = |token: Option<&str>| {
+            let mut req = Request::get(format!("http://{}/metrics", addr));
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            client.request(req.body(Body::empty()).expect("request should be valid"))
+        };
+
+        let res = scrape(None).await?;
+        assert_eq!(res.status(), hyper::StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let res = scrape(Some("wrong")).await?;
+        assert_eq!(res.status(), hyper::StatusCode::UNAUTHORIZED);
+        let res = scrape(Some("secret")).await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        reporter.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_tls_files_fail_to_start() -> Result<(), Box<dyn StdError>> {
+        let mut reporter = PrometheusReporter::new(
+            "pitaya".to_owned(),
+            HashMap::new(),
+            test_helpers::get_root_logger(),
+            "127.0.0.1:0".parse()?,
+        )?
+        .with_tls(MetricsTls {
+            cert: "/nonexistent/cert.pem".to_owned(),
+            key: "/nonexistent/key.pem".to_owned(),
+        });
+        assert!(matches!(
+            reporter.start().await,
+            Err(Error::FailedToStartServer(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recorded_duration_lands_in_bucket() -> Result<(), Box<dyn StdError>> {
+        let mut reporter = PrometheusReporter::new(
+            "pitaya".to_owned(),
+            HashMap::new(),
+            test_helpers::get_root_logger(),
+            "127.0.0.1:0".parse()?,
+        )?;
+        reporter.register_histogram(Opts {
+            kind: MetricKind::Histogram,
+            namespace: "pitaya".to_owned(),
+            subsystem: "test".to_owned(),
+            name: "latency".to_owned(),
+            help: "a histogram of latencies".to_owned(),
+            variable_labels: vec![],
+            buckets: Some(metrics::exponential_buckets(0.0005, 2.0, 20)?),
+        })?;
+
+        let registry = reporter.registry.clone();
+        let reporter: ThreadSafeReporter = Arc::new(RwLock::new(Box::new(reporter)));
+        metrics::record_histogram(
+            test_helpers::get_root_logger(),
+            reporter,
+            "latency",
+            Duration::from_millis(3),
+            &[],
+        )
+        .await;
+
+        let families = registry.gather();
+        let histogram = families[0].get_metric()[0].get_histogram();
+        assert_eq!(histogram.get_sample_count(), 1);
+
+        // 3ms is above the 2ms bucket and below the 4ms one.
+        for bucket in histogram.get_bucket() {
+            let expected = if bucket.get_upper_bound() < 0.003 {
+                0
+            } else {
+                1
+            };
+            assert_eq!(bucket.get_cumulative_count(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn linear_buckets_are_evenly_spaced() -> Result<(), Box<dyn StdError>> {
+        assert_eq!(
+            to_prometheus_buckets(metrics::linear_buckets(0.0, 0.25, 5)?),
+            vec![0.0, 0.25, 0.5, 0.75, 1.0]
+        );
+        assert_eq!(
+            to_prometheus_buckets(metrics::linear_buckets(10.0, 5.0, 3)?),
+            vec![10.0, 15.0, 20.0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_metrics_are_reported() -> Result<(), Box<dyn StdError>> {
+        let mut reporter = PrometheusReporter::new(
+            "pitaya".to_owned(),
+            HashMap::new(),
+            test_helpers::get_root_logger(),
+            "127.0.0.1:0".parse()?,
+        )?;
+        let opts = || Opts {
+            kind: MetricKind::Counter,
+            namespace: "pitaya".to_owned(),
+            subsystem: "test".to_owned(),
+            name: "duplicate".to_owned(),
+            help: "a counter registered twice".to_owned(),
+            variable_labels: vec![],
+            buckets: None,
+        };
+
+        reporter.register_counter(opts())?;
+        assert!(matches!(
+            reporter.register_counter(opts()),
+            Err(Error::MetricAlreadyRegistered(_))
+        ));
+        assert!(
+            metrics::allow_already_registered(reporter.register_counter(opts()).unwrap_err())
+                .is_ok()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use async_trait::async_trait;
+use pitaya_core::metrics::{Error, MetricKind, Opts, Reporter};
+use slog::{error, info};
+use std::{collections::HashMap, net::SocketAddr, net::UdpSocket};
+
+// A metric that was registered in the reporter.
+#[derive(Debug)]
+struct Metric {
+    kind: MetricKind,
+    // The full name of the metric, with prefix, namespace and subsystem.
+    name: String,
+    variable_labels: Vec<String>,
+}
+
+//
+// Reporter implementation.
+//
+#[derive(Debug)]
+pub struct StatsdReporter {
+    logger: slog::Logger,
+    addr: SocketAddr,
+    socket: Option<UdpSocket>,
+    metrics: HashMap<String, Metric>,
+    prefix: String,
+    const_labels: HashMap<String, String>,
+}
+
+impl StatsdReporter {
+    pub fn new(
+        prefix: String,
+        const_labels: HashMap<String, String>,
+        logger: slog::Logger,
+        addr: SocketAddr,
+    ) -> Self {
+        StatsdReporter {
+            logger,
+            addr,
+            socket: None,
+            metrics: HashMap::new(),
+            prefix,
+            const_labels,
+        }
+    }
+
+    fn register(&mut self, kind: MetricKind, opts: Opts) -> Result<(), Error> {
+        if self.metrics.contains_key(&opts.name) {
+            return Err(Error::MetricAlreadyRegistered(opts.name));
+        }
+
+        let name = [&self.prefix, &opts.namespace, &opts.subsystem, &opts.name]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| part.as_str())
+            .collect::<Vec<_>>()
+            .join(".");
+
+        self.metrics.insert(
+            opts.name,
+            Metric {
+                kind,
+                name,
+                variable_labels: opts.variable_labels,
+            },
+        );
+        Ok(())
+    }
+
+    // Sends a single StatsD line with the given value and type. Labels are sent as
+    // DogStatsD tags.
+    fn send(
+        &self,
+        name: &str,
+        kind: MetricKind,
+        value: &str,
+        labels: &[&str],
+    ) -> Result<(), Error> {
+        let metric = match self.metrics.get(name) {
+            Some(metric) if metric.kind == kind => metric,
+            _ => {
+                return Err(Error::InvalidMetric(format!(
+                    "unknown metric named {}",
+                    name
+                )))
+            }
+        };
+
+        if metric.variable_labels.len() != labels.len() {
+            return Err(Error::InvalidMetric(format!(
+                "metric {} expects {} labels, got {}",
+                name,
+                metric.variable_labels.len(),
+                labels.len()
+            )));
+        }
+
+        let metric_type = match kind {
+            MetricKind::Counter => "c",
+            MetricKind::Gauge => "g",
+            MetricKind::Histogram => "h",
+        };
+        let line = format!(
+            "{}:{}|{}{}",
+            metric.name,
+            value,
+            metric_type,
+            self.tags(metric, labels)
+        );
+
+        if let Some(socket) = self.socket.as_ref() {
+            socket
+                .send(line.as_bytes())
+                .map_err(|e| Error::InvalidMetric(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn tags(&self, metric: &Metric, labels: &[&str]) -> String {
+        let mut const_labels: Vec<_> = self.const_labels.iter().collect();
+        const_labels.sort();
+
+        let tags: Vec<String> = const_labels
+            .into_iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .chain(
+                metric
+                    .variable_labels
+                    .iter()
+                    .map(|k| k.as_str())
+                    .zip(labels.iter().copied()),
+            )
+            .map(|(k, v)| format!("{}:{}", k, v))
+            .collect();
+
+        if tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", tags.join(","))
+        }
+    }
+}
+
+#[async_trait]
+impl Reporter for StatsdReporter {
+    async fn start(&mut self) -> Result<(), Error> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(self.addr).map(|_| socket))
+            .map_err(|e| Error::FailedToStartServer(e.to_string()))?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|e| Error::FailedToStartServer(e.to_string()))?;
+        self.socket.replace(socket);
+
+        info!(self.logger, "statsd metrics reporter started"; "addr" => %self.addr);
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        if self.socket.take().is_none() {
+            error!(self.logger, "statsd metrics reporter was not started");
+        }
+        info!(self.logger, "statsd metrics reporter was shut down");
+        Ok(())
+    }
+
+    fn register_counter(&mut self, opts: Opts) -> Result<(), Error> {
+        self.register(MetricKind::Counter, opts)
+    }
+
+    fn register_histogram(&mut self, opts: Opts) -> Result<(), Error> {
+        self.register(MetricKind::Histogram, opts)
+    }
+
+    fn register_gauge(&mut self, opts: Opts) -> Result<(), Error> {
+        self.register(MetricKind::Gauge, opts)
+    }
+
+    fn inc_counter(&self, name: &str, labels: &[&str]) -> Result<(), Error> {
+        self.send(name, MetricKind::Counter, "1", labels)
+    }
+
+    fn observe_hist(&self, name: &str, value: f64, labels: &[&str]) -> Result<(), Error> {
+        self.send(name, MetricKind::Histogram, &value.to_string(), labels)
+    }
+
+    fn set_gauge(&self, name: &str, value: f64, labels: &[&str]) -> Result<(), Error> {
+        // A negative value would be read as a decrement, so the gauge is zeroed first and
+        // then decremented down to the value.
+        if value < 0.0 {
+            self.send(name, MetricKind::Gauge, "0", labels)?;
+        }
+        self.send(name, MetricKind::Gauge, &value.to_string(), labels)
+    }
+
+    fn add_gauge(&self, name: &str, value: f64, labels: &[&str]) -> Result<(), Error> {
+        // StatsD gauges are changed relatively when the value has an explicit sign.
+        self.send(name, MetricKind::Gauge, &format!("{:+}", value), labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pitaya_core::metrics::{self, ThreadSafeReporter};
+    use std::error::Error as StdError;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn recorded_duration_is_sent_as_histogram() -> Result<(), Box<dyn StdError>> {
+        let receiver = UdpSocket::bind("127.0.0.1:0")?;
+        receiver.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+        let mut const_labels = HashMap::new();
+        const_labels.insert("game".to_owned(), "example".to_owned());
+
+        let mut reporter = StatsdReporter::new(
+            "pitaya".to_owned(),
+            const_labels,
+            test_helpers::get_root_logger(),
+            receiver.local_addr()?,
+        );
+        reporter.register_histogram(Opts {
+            kind: MetricKind::Histogram,
+            namespace: "pitaya".to_owned(),
+            subsystem: "rpc".to_owned(),
+            name: "rpc_latency".to_owned(),
+            help: "the latency of rpcs".to_owned(),
+            variable_labels: vec!["route".to_owned()],
+            buckets: Some(metrics::exponential_buckets(0.0005, 2.0, 20)?),
+        })?;
+        reporter.start().await?;
+
+        let reporter: ThreadSafeReporter = Arc::new(RwLock::new(Box::new(reporter)));
+        metrics::record_histogram_duration(
+            test_helpers::get_root_logger(),
+            reporter.clone(),
+            "rpc_latency",
+            Instant::now(),
+            &["room.room.join"],
+        )
+        .await;
+
+        let mut buf = [0; 512];
+        let n = receiver.recv(&mut buf)?;
+        let packet = String::from_utf8_lossy(&buf[..n]);
+
+        let (name, rest) = packet.split_at(packet.find(':').unwrap());
+        assert_eq!(name, "pitaya.pitaya.rpc.rpc_latency");
+        assert!(rest.ends_with("|h|#game:example,route:room.room.join"));
+
+        let value = &rest[1..rest.find('|').unwrap()];
+        assert!(value.parse::<f64>()? >= 0.0);
+
+        reporter.write().await.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn negative_gauge_is_zeroed_before_being_set() -> Result<(), Box<dyn StdError>> {
+        let receiver = UdpSocket::bind("127.0.0.1:0")?;
+        receiver.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+        let mut reporter = StatsdReporter::new(
+            "pitaya".to_owned(),
+            HashMap::new(),
+            test_helpers::get_root_logger(),
+            receiver.local_addr()?,
+        );
+        reporter.register_gauge(Opts {
+            kind: MetricKind::Gauge,
+            namespace: "pitaya".to_owned(),
+            subsystem: "rpc".to_owned(),
+            name: "balance".to_owned(),
+            help: "a gauge that can be negative".to_owned(),
+            variable_labels: vec![],
+            buckets: None,
+        })?;
+        reporter.start().await?;
+
+        reporter.set_gauge("balance", -3.0, &[])?;
+
+        let mut buf = [0; 512];
+        let n = receiver.recv(&mut buf)?;
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..n]),
+            "pitaya.pitaya.rpc.balance:0|g"
+        );
+        let n = receiver.recv(&mut buf)?;
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..n]),
+            "pitaya.pitaya.rpc.balance:-3|g"
+        );
+
+        reporter.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_metrics_are_rejected() -> Result<(), Box<dyn StdError>> {
+        let mut reporter = StatsdReporter::new(
+            "pitaya".to_owned(),
+            HashMap::new(),
+            test_helpers::get_root_logger(),
+            "127.0.0.1:8125".parse()?,
+        );
+        reporter.register_counter(Opts {
+            kind: MetricKind::Counter,
+            namespace: "pitaya".to_owned(),
+            subsystem: "rpc".to_owned(),
+            name: "rpc_count".to_owned(),
+            help: "the number of rpcs".to_owned(),
+            variable_labels: vec!["route".to_owned()],
+            buckets: None,
+        })?;
+
+        assert!(reporter
+            .inc_counter("rpc_count", &["room.room.join"])
+            .is_ok());
+        assert!(reporter.inc_counter("rpc_count", &[]).is_err());
+        assert!(reporter.inc_counter("unknown", &[]).is_err());
+        assert!(reporter
+            .set_gauge("rpc_count", 1.0, &["room.room.join"])
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+// The etcd used when the etcd binary is not available, unless overridden with the
+// PITAYA_TEST_ETCD_URL environment variable.
+const DEFAULT_ETCD_URL: &str = "localhost:2379";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// An etcd server for tests. If the `etcd` binary is in the PATH, every instance starts a
+// throwaway server with its own ports and data directory, which is killed and removed
+// when the instance is dropped. That way, tests do not see each other's keys. Otherwise,
+// the server at PITAYA_TEST_ETCD_URL (or localhost:2379) is shared by all instances.
+pub struct EtcdServer {
+    url: String,
+    process: Option<(Child, PathBuf)>,
+}
+
+impl EtcdServer {
+    pub fn start() -> Self {
+        match Self::spawn() {
+            Some(server) => server,
+            None => Self {
+                url: std::env::var("PITAYA_TEST_ETCD_URL")
+                    .unwrap_or_else(|_| DEFAULT_ETCD_URL.to_owned()),
+                process: None,
+            },
+        }
+    }
+
+    // The url of the server, in the format expected by the etcd client.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Stops the server, so that tests can check how it going away is handled. Returns false
+    // if the server is shared, since it was not started by this instance.
+    pub fn stop(&mut self) -> bool {
+        match self.process.take() {
+            Some((mut process, data_dir)) => {
+                let _ = process.kill();
+                let _ = process.wait();
+                let _ = std::fs::remove_dir_all(data_dir);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn spawn() -> Option<Self> {
+        let client_port = free_port();
+        let peer_port = free_port();
+        let data_dir = std::env::temp_dir().join(format!(
+            "pitaya-etcd-{}-{}",
+            std::process::id(),
+            client_port
+        ));
+        let client_url = format!("http://127.0.0.1:{}", client_port);
+        let peer_url = format!("http://127.0.0.1:{}", peer_port);
+
+        let process = Command::new("etcd")
+            .arg("--name")
+            .arg(format!("pitaya-test-{}", client_port))
+            .arg("--data-dir")
+            .arg(&data_dir)
+            .arg("--listen-client-urls")
+            .arg(&client_url)
+            .arg("--advertise-client-urls")
+            .arg(&client_url)
+            .arg("--listen-peer-urls")
+            .arg(&peer_url)
+            .arg("--initial-advertise-peer-urls")
+            .arg(&peer_url)
+            .arg("--initial-cluster")
+            .arg(format!("pitaya-test-{}={}", client_port, peer_url))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+
+        let server = Self {
+            url: format!("127.0.0.1:{}", client_port),
+            process: Some((process, data_dir)),
+        };
+        server.wait_until_healthy();
+        Some(server)
+    }
+
+    fn wait_until_healthy(&self) {
+        let start = Instant::now();
+        while start.elapsed() < STARTUP_TIMEOUT {
+            if is_healthy(&self.url) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("etcd at {} did not become healthy in time", self.url);
+    }
+}
+
+impl Drop for EtcdServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("should find a free port")
+}
+
+fn is_healthy(addr: &str) -> bool {
+    let mut stream = match TcpStream::connect(addr) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let request = format!("GET /health HTTP/1.0\r\nHost: {}\r\n\r\n", addr);
+    let mut response = String::new();
+    stream.write_all(request.as_bytes()).is_ok()
+        && stream.read_to_string(&mut response).is_ok()
+        && response.contains(r#""health":"true""#)
+}
@@ -1,4 +1,9 @@
 use slog::{o, Drain};
+use std::sync::{Arc, Mutex};
+
+mod etcd;
+
+pub use etcd::EtcdServer;
 
 pub fn get_root_logger() -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().build();
@@ -10,3 +15,60 @@ pub fn get_root_logger() -> slog::Logger {
         .fuse();
     slog::Logger::root(drain, o!())
 }
+
+// The lines logged by a logger created with `get_capturing_logger`. Each line has the
+// message followed by its key-value pairs, like `message key=value`.
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<String>>>);
+
+impl CapturedLogs {
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn contains(&self, text: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line.contains(text))
+    }
+}
+
+// Returns a logger that keeps every record in memory, so that tests can assert on
+// what was logged.
+pub fn get_capturing_logger() -> (slog::Logger, CapturedLogs) {
+    let logs = CapturedLogs::default();
+    let logger = slog::Logger::root(CaptureDrain(logs.clone()), o!());
+    (logger, logs)
+}
+
+struct CaptureDrain(CapturedLogs);
+
+impl Drain for CaptureDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let mut line = record.msg().to_string();
+        let mut serializer = LineSerializer(&mut line);
+        // Formatting into a String cannot fail.
+        let _ = slog::KV::serialize(record.kv(), record, &mut serializer);
+        let _ = slog::KV::serialize(values, record, &mut serializer);
+        (self.0).0.lock().unwrap().push(line);
+        Ok(())
+    }
+}
+
+struct LineSerializer<'a>(&'a mut String);
+
+impl slog::Serializer for LineSerializer<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+        self.0.push_str(&format!(" {}={}", key, val));
+        Ok(())
+    }
+}